@@ -10,7 +10,11 @@ const sorted = <T> (iter: Iterable<T>): T[] => Array.from(iter).sort();
 export class Query {
   private readonly modeTerms: ReadonlyArray<Set<string>> = Array(3).fill(void 0).map(() => new Set());
 
-  private continuation: number = 0;
+  private continuation: number | string = 0;
+
+  private includeIds: boolean = false;
+
+  private fields: ReadonlyArray<string> | undefined;
 
   public add (mode: Mode, ...terms: ReadonlyArray<string>): this {
     for (const w of terms) {
@@ -19,17 +23,33 @@ export class Query {
     return this;
   }
 
-  public setContinuation (c: number): this {
+  // Continuations from a sharded index's coordinator are strings.
+  public setContinuation (c: number | string): this {
     this.continuation = c;
     return this;
   }
 
+  // Include the ID of each result in the response, e.g. to get it later with `Client.getDocument`.
+  public setIncludeIds (includeIds: boolean = true): this {
+    this.includeIds = includeIds;
+    return this;
+  }
+
+  // Only return these top-level fields of each result, e.g. `['title', 'url']`; a `Client` for such queries would use a document type with only these fields.
+  // Returning all fields again is done by passing undefined.
+  public setFields (fields: ReadonlyArray<string> | undefined): this {
+    this.fields = fields;
+    return this;
+  }
+
   public build (): string {
     return [
-      `c=${this.continuation}`,
+      `c=${encodeURIComponent(this.continuation)}`,
       ...this.modeTerms
         .map((terms, mode) => sorted(terms).map(t => `t=${mode}_${encodeURIComponent(t)}`))
-        .reduce((flat, modeTerms) => flat.concat(modeTerms), [])
+        .reduce((flat, modeTerms) => flat.concat(modeTerms), []),
+      ...(this.includeIds ? ['ids=1'] : []),
+      ...(this.fields ? [`fields=${this.fields.map(encodeURIComponent).join(',')}`] : []),
     ].join('&');
   }
 }
@@ -38,20 +58,51 @@ export type Agent<T> = (url: string) => Promise<T>;
 
 export const fetchGet = <T> (url: string): Promise<T> => fetch(url).then(res => res.json());
 
+// IDs of sharded indexes are strings.
+export type DocumentId = number | string;
+
 export type SearchResponse<D> = {
   results: D[];
-  continuation: number | null;
+  continuation: number | string | null;
   total: number;
+  // IDs of `results`, if requested with `Query.setIncludeIds`.
+  ids?: DocumentId[];
 };
 
-export class Client<D> {
+export type DocumentsResponse<D> = {
+  // Documents that don't exist or have been deleted are null.
+  results: (D | null)[];
+};
+
+export type DocumentsByKeysResponse<D> = DocumentsResponse<D> & {
+  // IDs of `results`, or null for documents that don't exist.
+  ids: (DocumentId | null)[];
+};
+
+// `S` is the type of search results, which are summaries for indexes built with document summaries.
+export class Client<D, S = D> {
   constructor (
     private readonly prefix: string,
-    private readonly agent: Agent<SearchResponse<D>> = fetchGet,
+    private readonly agent: Agent<unknown> = fetchGet,
   ) {
   }
 
-  search (query: Query): Promise<SearchResponse<D>> {
-    return this.agent(`${this.prefix}/search?${query.build()}`);
+  search (query: Query): Promise<SearchResponse<S>> {
+    return this.agent(`${this.prefix}/search?${query.build()}`) as Promise<SearchResponse<S>>;
+  }
+
+  getDocuments (ids: ReadonlyArray<DocumentId>): Promise<DocumentsResponse<D>> {
+    const params = ids.map(id => `id=${encodeURIComponent(id)}`).join('&');
+    return this.agent(`${this.prefix}/docs?${params}`) as Promise<DocumentsResponse<D>>;
+  }
+
+  getDocument (id: DocumentId): Promise<D | null> {
+    return this.getDocuments([id]).then(res => res.results[0]);
+  }
+
+  // Only for indexes built with document keys.
+  getDocumentsByKeys (keys: ReadonlyArray<string | number>): Promise<DocumentsByKeysResponse<D>> {
+    const params = keys.map(key => `key=${encodeURIComponent(key)}`).join('&');
+    return this.agent(`${this.prefix}/docs?${params}`) as Promise<DocumentsByKeysResponse<D>>;
   }
 }
@@ -5,7 +5,7 @@ set -euo pipefail
 pushd "$(dirname "$0")" >/dev/null
 
 mkdir -p build
-../../../target/release/edgesearch \
+../../../target/release/edgesearch build \
   --data-store kv \
   --documents ../data/build/docs.txt \
   --document-terms ../data/build/terms.txt \
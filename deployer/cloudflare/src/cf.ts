@@ -16,8 +16,8 @@ export type CFResponse<Result> = {
 class FormData {
   readonly entries = {};
 
-  add (key: string, value: Buffer | string): this {
-    this.entries[key] = value;
+  add (key: string, value: Buffer | string, contentType?: string): this {
+    this.entries[key] = contentType ? {value, options: {filename: key, contentType}} : value;
     return this;
   }
 }
@@ -80,37 +80,73 @@ export const publishWorker = ({
   script,
   wasm,
   kvNamespaceId,
+  r2Bucket,
+  moduleFormat,
 }: {
   auth: CFAuth;
   name: string;
   script: Buffer;
-  wasm: Buffer;
+  // Undefined for workers without a runner, such as the coordinator of a sharded build.
+  wasm: Buffer | undefined;
   kvNamespaceId: string | undefined;
-}) => makeRequest<{
-  script: string;
-  etag: string;
-  size: number;
-  modified_on: string;
-}>({
-  auth,
-  method: 'PUT',
-  path: `/workers/scripts/${name}`,
-  body: new FormData()
-    .add('metadata', JSON.stringify({
-      body_part: 'script',
-      bindings: [
-        {
-          name: 'QUERY_RUNNER_WASM',
-          type: 'wasm_module',
-          part: 'wasm',
-        },
-        kvNamespaceId && {
-          name: 'KV',
-          type: 'kv_namespace',
-          namespace_id: kvNamespaceId,
-        },
-      ].filter(b => b),
-    }))
-    .add('script', script)
-    .add('wasm', wasm),
-});
+  r2Bucket: { binding: string; bucketName: string } | undefined;
+  // Module workers import the runner as a module part instead of binding it.
+  moduleFormat: boolean;
+}) => {
+  let body;
+  if (moduleFormat) {
+    body = new FormData()
+      .add('metadata', JSON.stringify({
+        main_module: 'worker.js',
+        bindings: dataStoreBindings(kvNamespaceId, r2Bucket),
+      }))
+      .add('worker.js', script, 'application/javascript+module');
+    if (wasm) {
+      body.add('runner.wasm', wasm, 'application/wasm');
+    }
+  } else {
+    body = new FormData()
+      .add('metadata', JSON.stringify({
+        body_part: 'script',
+        bindings: [
+          wasm && {
+            name: 'QUERY_RUNNER_WASM',
+            type: 'wasm_module',
+            part: 'wasm',
+          },
+          ...dataStoreBindings(kvNamespaceId, r2Bucket),
+        ].filter(b => b),
+      }))
+      .add('script', script);
+    if (wasm) {
+      body.add('wasm', wasm);
+    }
+  }
+  return makeRequest<{
+    script: string;
+    etag: string;
+    size: number;
+    modified_on: string;
+  }>({
+    auth,
+    method: 'PUT',
+    path: `/workers/scripts/${name}`,
+    body,
+  });
+};
+
+const dataStoreBindings = (
+  kvNamespaceId: string | undefined,
+  r2Bucket: { binding: string; bucketName: string } | undefined,
+) => [
+  kvNamespaceId && {
+    name: 'KV',
+    type: 'kv_namespace',
+    namespace_id: kvNamespaceId,
+  },
+  r2Bucket && {
+    name: r2Bucket.binding,
+    type: 'r2_bucket',
+    bucket_name: r2Bucket.bucketName,
+  },
+].filter(b => b);
@@ -2,9 +2,18 @@ import {promises as fs} from 'fs';
 import {join} from 'path';
 import {CFAuth, publishWorker, uploadKv} from './cf';
 
+// Keep in sync with build::manifest::FORMAT_VERSION.
+const SUPPORTED_FORMAT_VERSION = 1;
+
 type UploadState = {
   nextDocumentsChunk: number;
   nextTermsChunk: number;
+  // Missing from state written by versions before document keys were added.
+  nextKeysChunk?: number;
+  // Missing from state written by versions before document summaries were added.
+  nextSummariesChunk?: number;
+  // Next chunk of each stored field, by position; missing from state written by versions before stored fields were added.
+  nextFieldsChunks?: number[];
 };
 
 class UploadStateManager {
@@ -13,6 +22,9 @@ class UploadStateManager {
     private readonly state: UploadState = {
       nextDocumentsChunk: 0,
       nextTermsChunk: 0,
+      nextKeysChunk: 0,
+      nextSummariesChunk: 0,
+      nextFieldsChunks: [],
     },
   ) {
   }
@@ -50,78 +62,307 @@ class UploadStateManager {
     return this.state.nextTermsChunk;
   }
 
+  async incrementKeysChunk () {
+    this.state.nextKeysChunk = this.getNextKeysChunk() + 1;
+    await this.writeState();
+  }
+
+  getNextKeysChunk () {
+    return this.state.nextKeysChunk ?? 0;
+  }
+
+  async incrementSummariesChunk () {
+    this.state.nextSummariesChunk = this.getNextSummariesChunk() + 1;
+    await this.writeState();
+  }
+
+  getNextSummariesChunk () {
+    return this.state.nextSummariesChunk ?? 0;
+  }
+
+  async incrementFieldsChunk (field: number) {
+    const next = this.state.nextFieldsChunks ?? [];
+    next[field] = this.getNextFieldsChunk(field) + 1;
+    this.state.nextFieldsChunks = next;
+    await this.writeState();
+  }
+
+  getNextFieldsChunk (field: number) {
+    return this.state.nextFieldsChunks?.[field] ?? 0;
+  }
+
   async delete () {
     await fs.unlink(this.path);
   }
 }
 
-const listDirChunks = async (dir: string): Promise<number[]> =>
-  (await fs.readdir(dir))
-    .map(e => Number.parseInt(e, 10))
-    .sort((a, b) => a - b);
+type ManifestChunk = {
+  id: number;
+  name: string;
+  reused: boolean;
+};
 
-export const deploy = async ({
-  accountEmail,
-  accountId,
-  globalApiKey,
+const uploadLayer = async ({
+  auth,
+  changedOnly,
   kvNamespaceId,
-  name,
+  manifest,
   outputDir,
-  uploadData,
 }: {
-  accountEmail: string;
-  accountId: string;
-  globalApiKey: string;
-  kvNamespaceId: string | undefined;
-  name: string;
+  auth: CFAuth;
+  changedOnly: boolean;
+  kvNamespaceId: string;
+  manifest: any;
   outputDir: string;
-  uploadData: boolean;
 }) => {
-  const auth: CFAuth = {accountEmail, accountId, globalApiKey};
+  // Set for delta builds and named indexes, whose chunks are stored under a prefix.
+  const layerPrefix: string = manifest.layer_prefix;
+  // Keys of all chunks are prefixed by the data store prefix, if any, such as `shards/0/` for shards.
+  const keyPrefix = `${manifest.settings.data_store_url_prefix ?? ''}${layerPrefix}`;
+  const uploadState = await UploadStateManager.forOutputDir(join(outputDir, layerPrefix));
 
-  console.log('Uploading worker...');
-  const [script, wasm] = await Promise.all(
-    ['worker.js', 'runner.wasm'].map(f => fs.readFile(join(outputDir, f))),
-  );
-
-  await publishWorker({auth, name, kvNamespaceId, script, wasm});
-  console.log('Worker uploaded');
-
-  if (!kvNamespaceId || !uploadData) {
-    console.log(`Not uploading data`);
-    return;
-  }
-
-  const uploadState = await UploadStateManager.forOutputDir(outputDir);
-
-  for (const chunkId of await listDirChunks(join(outputDir, 'documents'))) {
+  for (const {id: chunkId, name: chunkName, reused} of manifest.documents_chunks as ManifestChunk[]) {
     if (chunkId < uploadState.getNextDocumentsChunk()) {
       continue;
     }
-    console.log(`Uploading documents chunk ${chunkId}...`);
+    if (changedOnly && reused) {
+      console.log(`Skipping unchanged documents chunk ${layerPrefix}${chunkId}`);
+      await uploadState.incrementDocumentsChunk();
+      continue;
+    }
+    console.log(`Uploading documents chunk ${layerPrefix}${chunkId}...`);
     await uploadKv({
       auth,
-      key: `documents/${chunkId}`,
+      key: `${keyPrefix}documents/${chunkName}`,
       namespaceId: kvNamespaceId,
-      value: await fs.readFile(join(outputDir, 'documents', `${chunkId}`)),
+      value: await fs.readFile(join(outputDir, layerPrefix, 'documents', chunkName)),
     });
     await uploadState.incrementDocumentsChunk();
   }
 
-  for (const chunkId of await listDirChunks(join(outputDir, 'terms'))) {
+  for (const {id: chunkId, name: chunkName, reused} of manifest.terms_chunks as ManifestChunk[]) {
     if (chunkId < uploadState.getNextTermsChunk()) {
       continue;
     }
-    console.log(`Uploading terms chunk ${chunkId}...`);
+    if (changedOnly && reused) {
+      console.log(`Skipping unchanged terms chunk ${layerPrefix}${chunkId}`);
+      await uploadState.incrementTermsChunk();
+      continue;
+    }
+    console.log(`Uploading terms chunk ${layerPrefix}${chunkId}...`);
     await uploadKv({
       auth,
-      key: `terms/${chunkId}`,
+      key: `${keyPrefix}terms/${chunkName}`,
       namespaceId: kvNamespaceId,
-      value: await fs.readFile(join(outputDir, 'terms', `${chunkId}`)),
+      value: await fs.readFile(join(outputDir, layerPrefix, 'terms', chunkName)),
     });
     await uploadState.incrementTermsChunk();
   }
 
+  // Builds without document summaries have no summaries chunks.
+  for (const {id: chunkId, name: chunkName, reused} of (manifest.summaries_chunks ?? []) as ManifestChunk[]) {
+    if (chunkId < uploadState.getNextSummariesChunk()) {
+      continue;
+    }
+    if (changedOnly && reused) {
+      console.log(`Skipping unchanged summaries chunk ${layerPrefix}${chunkId}`);
+      await uploadState.incrementSummariesChunk();
+      continue;
+    }
+    console.log(`Uploading summaries chunk ${layerPrefix}${chunkId}...`);
+    await uploadKv({
+      auth,
+      key: `${keyPrefix}summaries/${chunkName}`,
+      namespaceId: kvNamespaceId,
+      value: await fs.readFile(join(outputDir, layerPrefix, 'summaries', chunkName)),
+    });
+    await uploadState.incrementSummariesChunk();
+  }
+
+  // Builds without document keys, including those before keys were added, have no keys chunks.
+  for (const {id: chunkId, name: chunkName, reused} of (manifest.keys_chunks ?? []) as ManifestChunk[]) {
+    if (chunkId < uploadState.getNextKeysChunk()) {
+      continue;
+    }
+    if (changedOnly && reused) {
+      console.log(`Skipping unchanged keys chunk ${layerPrefix}${chunkId}`);
+      await uploadState.incrementKeysChunk();
+      continue;
+    }
+    console.log(`Uploading keys chunk ${layerPrefix}${chunkId}...`);
+    await uploadKv({
+      auth,
+      key: `${keyPrefix}keys/${chunkName}`,
+      namespaceId: kvNamespaceId,
+      value: await fs.readFile(join(outputDir, layerPrefix, 'keys', chunkName)),
+    });
+    await uploadState.incrementKeysChunk();
+  }
+
+  // Chunks of each stored field are under the field's position, e.g. `fields/0/`.
+  const fields: { name: string; chunks: ManifestChunk[] }[] = manifest.fields ?? [];
+  for (const [field, {name: fieldName, chunks}] of fields.entries()) {
+    for (const {id: chunkId, name: chunkName, reused} of chunks) {
+      if (chunkId < uploadState.getNextFieldsChunk(field)) {
+        continue;
+      }
+      if (changedOnly && reused) {
+        console.log(`Skipping unchanged ${fieldName} field chunk ${layerPrefix}${chunkId}`);
+        await uploadState.incrementFieldsChunk(field);
+        continue;
+      }
+      console.log(`Uploading ${fieldName} field chunk ${layerPrefix}${chunkId}...`);
+      await uploadKv({
+        auth,
+        key: `${keyPrefix}fields/${field}/${chunkName}`,
+        namespaceId: kvNamespaceId,
+        value: await fs.readFile(join(outputDir, layerPrefix, 'fields', `${field}`, chunkName)),
+      });
+      await uploadState.incrementFieldsChunk(field);
+    }
+  }
+
+  if (manifest.deleted) {
+    console.log(`Uploading deleted documents ${layerPrefix}...`);
+    await uploadKv({
+      auth,
+      key: `${keyPrefix}deleted/${manifest.deleted.name}`,
+      namespaceId: kvNamespaceId,
+      value: await fs.readFile(join(outputDir, layerPrefix, 'deleted', manifest.deleted.name)),
+    });
+  }
+
+  for (const kind of ['documents', 'summaries']) {
+    const dictionary = manifest[`${kind}_dictionary`];
+    if (dictionary) {
+      console.log(`Uploading ${kind} dictionary ${layerPrefix}...`);
+      await uploadKv({
+        auth,
+        key: `${keyPrefix}${kind}-dictionary/${dictionary.name}`,
+        namespaceId: kvNamespaceId,
+        value: await fs.readFile(join(outputDir, layerPrefix, `${kind}-dictionary`, dictionary.name)),
+      });
+    }
+  }
+
   await uploadState.delete();
-  console.log(`Data successfully uploaded`);
+};
+
+const readManifest = async (outputDir: string) => {
+  const manifest = JSON.parse(await fs.readFile(join(outputDir, 'manifest.json'), 'utf8'));
+  if (manifest.format_version !== SUPPORTED_FORMAT_VERSION) {
+    throw new Error(`Build output uses data format ${manifest.format_version}, but this deployer supports ${SUPPORTED_FORMAT_VERSION}`);
+  }
+  return manifest;
+};
+
+// Upload the data of the build in `outputDir` if requested, and then its worker and runner.
+const deployIndex = async ({
+  auth,
+  changedOnly,
+  kvNamespaceId,
+  manifest,
+  name,
+  outputDir,
+  r2BucketName,
+  uploadData,
+}: {
+  auth: CFAuth;
+  changedOnly: boolean;
+  kvNamespaceId: string | undefined;
+  manifest: any;
+  name: string;
+  outputDir: string;
+  r2BucketName: string | undefined;
+  uploadData: boolean;
+}) => {
+  // Data is uploaded before the worker, so that a worker is never live before the chunks it references exist.
+  // With hash chunk naming, this makes switching between builds atomic.
+  if (kvNamespaceId && uploadData) {
+    // Builds with named indexes have a manifest for each index; the top-level manifest has no chunks.
+    const layerManifests = manifest.indexes.length
+      ? await Promise.all((manifest.indexes as string[]).map(indexName => readManifest(join(outputDir, indexName))))
+      : [manifest];
+    for (const layerManifest of layerManifests) {
+      await uploadLayer({auth, changedOnly, kvNamespaceId, manifest: layerManifest, outputDir});
+    }
+    console.log(`Data successfully uploaded`);
+  } else {
+    console.log(`Not uploading data`);
+  }
+
+  console.log(`Uploading worker ${name}...`);
+  const [script, wasm] = await Promise.all(
+    ['worker.js', 'runner.wasm'].map(f => fs.readFile(join(outputDir, f))),
+  );
+
+  // Objects in R2 are not uploaded by the deployer; copy the output folder to the bucket before deploying.
+  const bucketBinding: string | null = manifest.settings.data_store_bucket_binding;
+  if (bucketBinding && !r2BucketName) {
+    throw new Error(`Build uses the R2 bucket binding ${bucketBinding}, but no R2 bucket was provided`);
+  }
+  const r2Bucket = bucketBinding && r2BucketName ? {binding: bucketBinding, bucketName: r2BucketName} : undefined;
+
+  const moduleFormat = manifest.settings.worker_format === 'module';
+  await publishWorker({auth, name, kvNamespaceId, r2Bucket, moduleFormat, script, wasm});
+  console.log(`Worker ${name} uploaded`);
+};
+
+// Name of the worker of a shard, which the build's shard URL template must point to.
+export const shardWorkerName = (name: string, shard: number) => `${name}-shard-${shard}`;
+
+export const deploy = async ({
+  accountEmail,
+  accountId,
+  changedOnly,
+  globalApiKey,
+  kvNamespaceId,
+  name,
+  outputDir,
+  r2BucketName,
+  uploadData,
+}: {
+  accountEmail: string;
+  changedOnly: boolean;
+  accountId: string;
+  globalApiKey: string;
+  kvNamespaceId: string | undefined;
+  name: string;
+  outputDir: string;
+  r2BucketName: string | undefined;
+  uploadData: boolean;
+}) => {
+  const auth: CFAuth = {accountEmail, accountId, globalApiKey};
+
+  const manifest = await readManifest(outputDir);
+  if (!manifest.shards) {
+    await deployIndex({auth, changedOnly, kvNamespaceId, manifest, name, outputDir, r2BucketName, uploadData});
+    return;
+  }
+
+  // Each shard is deployed as its own worker before the coordinator, which sends queries to all of them.
+  for (let shard = 0; shard < manifest.shards; shard++) {
+    const shardOutputDir = join(outputDir, 'shards', `${shard}`);
+    await deployIndex({
+      auth,
+      changedOnly,
+      kvNamespaceId,
+      manifest: await readManifest(shardOutputDir),
+      name: shardWorkerName(name, shard),
+      outputDir: shardOutputDir,
+      r2BucketName,
+      uploadData,
+    });
+  }
+  console.log(`Uploading coordinator ${name}...`);
+  await publishWorker({
+    auth,
+    name,
+    kvNamespaceId: undefined,
+    r2Bucket: undefined,
+    moduleFormat: manifest.settings.worker_format === 'module',
+    script: await fs.readFile(join(outputDir, 'coordinator.js')),
+    wasm: undefined,
+  });
+  console.log(`Coordinator ${name} uploaded`);
 };
@@ -24,6 +24,12 @@ const cli = sacli.build({
           typeLabel: '<id>',
           description: 'Cloudflare account ID',
         },
+        {
+          name: 'changed-only',
+          alias: 'c',
+          type: Boolean,
+          description: 'Only upload chunks that changed since the previous build (see --previous-output-dir)',
+        },
         {
           name: 'global-api-key',
           alias: 'k',
@@ -52,6 +58,13 @@ const cli = sacli.build({
           typeLabel: '<path>',
           description: 'Path to build output directory',
         },
+        {
+          name: 'r2-bucket',
+          alias: 'r',
+          type: String,
+          typeLabel: '<name>',
+          description: 'Cloudflare R2 bucket to bind to the worker, for builds using the R2 data store',
+        },
         {
           name: 'upload-data',
           alias: 'u',
@@ -63,10 +76,12 @@ const cli = sacli.build({
         deploy({
           accountEmail: args['account-email'],
           accountId: args['account-id'],
+          changedOnly: !!args['changed-only'],
           globalApiKey: args['global-api-key'],
           name: args['name'],
           kvNamespaceId: args['namespace'],
           outputDir: args['output-dir'],
+          r2BucketName: args['r2-bucket'],
           uploadData: !!args['upload-data'],
         }).catch(err => {
           console.error(err);
@@ -0,0 +1,73 @@
+import decodeUtf8 from "extlib/js/decodeUtf8";
+import { Mode, Query, SearchResponse } from "../../client/src/main";
+import {
+  getDocuments,
+  getDocumentsByKeys,
+  getIndex,
+  Index,
+  parseSearchRequest,
+  search,
+} from "./search";
+
+// The same query builder as the client, so that queries can be sent to a worker or searched locally.
+export { Mode, Query };
+
+// Searches an index in the browser without a server, fetching chunks on demand from the URL prefix relative to this module.
+// `S` is the type of search results, which are summaries for indexes built with document summaries.
+export class Client<D, S = D> {
+  // Use `indexName` to search a named index, or null for an unnamed index.
+  constructor(private readonly indexName: string | null = null) {}
+
+  private getIndex(): Index {
+    const index = getIndex(this.indexName);
+    if (!index) {
+      throw new Error(`Index ${this.indexName} does not exist`);
+    }
+    return index;
+  }
+
+  async search(query: Query): Promise<SearchResponse<S>> {
+    const params = new URLSearchParams(query.build());
+    const request = parseSearchRequest(params);
+    if ("error" in request) {
+      throw new Error(request.error);
+    }
+    const { total, continuation, documents, ids } = await search(
+      this.getIndex(),
+      request
+    );
+    return {
+      total,
+      continuation,
+      results: documents.map((doc) => JSON.parse(decodeUtf8(doc))),
+      ...(params.has("ids") && { ids }),
+    };
+  }
+
+  // Documents that don't exist or have been deleted are null.
+  async getDocuments(ids: ReadonlyArray<number>): Promise<(D | null)[]> {
+    const documents = await getDocuments(this.getIndex(), ids.slice());
+    return documents.map((doc) => (doc ? JSON.parse(decodeUtf8(doc)) : null));
+  }
+
+  async getDocument(id: number): Promise<D | null> {
+    const [document] = await this.getDocuments([id]);
+    return document;
+  }
+
+  // Only for indexes built with document keys. IDs are null for documents that don't exist.
+  async getDocumentsByKeys(
+    keys: ReadonlyArray<string | number>
+  ): Promise<{ results: (D | null)[]; ids: (number | null)[] }> {
+    const { ids, documents } = await getDocumentsByKeys(
+      this.getIndex(),
+      keys.map((key) => `${key}`)
+    );
+    return {
+      results: documents.map((doc) =>
+        doc ? JSON.parse(decodeUtf8(doc)) : null
+      ),
+      ids: ids.map((id) => id ?? null),
+    };
+  }
+}
@@ -0,0 +1,246 @@
+import {
+  CORS_HEADERS,
+  responseError,
+  responsePreflight,
+  responseRawJson,
+} from "./http";
+
+// Following variables are set by build/js.rs.
+// Amount of shards the index was split into.
+declare var SHARD_COUNT: number;
+// URL of a shard worker, with `{shard}` replaced by the shard number.
+declare var SHARD_URL_TEMPLATE: string;
+// Maximum amount of results returned at once.
+declare var MAX_RESULTS: number;
+
+type ShardResponse = {
+  results: unknown[];
+  continuation: number | null;
+  total: number;
+  // Only set if requested.
+  ids?: number[];
+};
+
+const shardUrl = (shard: number) =>
+  SHARD_URL_TEMPLATE.replace("{shard}", `${shard}`);
+
+// The continuation is the amount of results already returned from each shard, separated by ".", e.g. "50.0.0".
+const parseContinuation = (raw: string | null): number[] | undefined => {
+  if (!raw) {
+    return Array(SHARD_COUNT).fill(0);
+  }
+  const offsets = raw.split(".").map((o) => Number.parseInt(o, 10));
+  if (
+    offsets.length != SHARD_COUNT ||
+    offsets.some((o) => !Number.isSafeInteger(o) || o < 0)
+  ) {
+    return;
+  }
+  return offsets;
+};
+
+// Document IDs are the shard and the ID of the document within the shard, separated by ".", e.g. "2.1043".
+const parseDocumentId = (
+  raw: string
+): { shard: number; id: string } | undefined => {
+  const matches = /^([0-9]+)\.([0-9]+)$/.exec(raw);
+  if (!matches) {
+    return;
+  }
+  const shard = Number.parseInt(matches[1], 10);
+  return shard < SHARD_COUNT ? { shard, id: matches[2] } : undefined;
+};
+
+const fetchShard = (
+  shard: number,
+  terms: string[],
+  offset: number,
+  includeIds: boolean,
+  fields: string | null
+): Promise<Response> => {
+  const params = new URLSearchParams();
+  for (const term of terms) {
+    params.append("t", term);
+  }
+  params.set("c", `${offset}`);
+  if (includeIds) {
+    params.set("ids", "1");
+  }
+  if (fields != null) {
+    params.set("fields", fields);
+  }
+  return fetch(`${shardUrl(shard)}/search?${params}`);
+};
+
+// Forward an error response from a shard.
+const responseShardError = (res: Response) =>
+  new Response(res.body, {
+    status: res.status,
+    headers: {
+      "Content-Type": "application/json",
+      ...CORS_HEADERS,
+    },
+  });
+
+const handleSearch = async (url: URL) => {
+  const offsets = parseContinuation(url.searchParams.get("c"));
+  if (!offsets) {
+    return responseError("Malformed continuation");
+  }
+  const terms = url.searchParams.getAll("t");
+  const includeIds = url.searchParams.has("ids");
+  const fields = url.searchParams.get("fields");
+
+  // Every shard is queried, even if it has no more results, as its total is still needed.
+  const rawShardResponses = await Promise.all(
+    offsets.map((offset, shard) =>
+      fetchShard(shard, terms, offset, includeIds, fields)
+    )
+  );
+  // All shards validate queries identically, so forward the first error (e.g. a malformed query or too many terms).
+  const failed = rawShardResponses.find((res) => !res.ok);
+  if (failed) {
+    return responseShardError(failed);
+  }
+  const shardResponses: ShardResponse[] = await Promise.all(
+    rawShardResponses.map((res) => res.json())
+  );
+
+  // Results are taken from shards in order, so pages follow the order of shards and then the order within each shard.
+  const results = [];
+  const ids = [];
+  const nextOffsets = offsets.slice();
+  for (let shard = 0; shard < SHARD_COUNT; shard++) {
+    const taken = shardResponses[shard].results.slice(
+      0,
+      MAX_RESULTS - results.length
+    );
+    results.push(...taken);
+    if (includeIds) {
+      ids.push(
+        ...shardResponses[shard]
+          .ids!.slice(0, taken.length)
+          .map((id) => `${shard}.${id}`)
+      );
+    }
+    nextOffsets[shard] += taken.length;
+  }
+  const total = shardResponses.reduce((sum, res) => sum + res.total, 0);
+  const exhausted = shardResponses.every(
+    (res, shard) => nextOffsets[shard] >= res.total
+  );
+
+  return responseRawJson(
+    JSON.stringify({
+      total,
+      continuation: exhausted ? null : nextOffsets.join("."),
+      results,
+      ...(includeIds && { ids }),
+    })
+  );
+};
+
+const handleDocument = async (rawId: string) => {
+  const documentId = parseDocumentId(rawId);
+  if (!documentId) {
+    return responseError("Malformed document ID");
+  }
+  const res = await fetch(
+    `${shardUrl(documentId.shard)}/doc/${documentId.id}`
+  );
+  return res.ok
+    ? responseRawJson(await res.text())
+    : responseShardError(res);
+};
+
+// Keys are unique across shards, so every shard is asked for every key, and the results are taken from whichever shard has each key.
+const handleDocumentsByKeys = async (url: URL) => {
+  const rawShardResponses = await Promise.all(
+    Array.from({ length: SHARD_COUNT }, (_, shard) =>
+      fetch(`${shardUrl(shard)}/docs?${url.searchParams}`)
+    )
+  );
+  // All shards validate keys identically, so forward the first error.
+  const failed = rawShardResponses.find((res) => !res.ok);
+  if (failed) {
+    return responseShardError(failed);
+  }
+  const shardResponses: { results: unknown[]; ids: (number | null)[] }[] =
+    await Promise.all(rawShardResponses.map((res) => res.json()));
+  const keyCount = url.searchParams.getAll("key").length;
+  const results: unknown[] = Array(keyCount).fill(null);
+  const ids: (string | null)[] = Array(keyCount).fill(null);
+  shardResponses.forEach((res, shard) => {
+    res.ids.forEach((id, i) => {
+      if (id != null) {
+        results[i] = res.results[i];
+        ids[i] = `${shard}.${id}`;
+      }
+    });
+  });
+  return responseRawJson(JSON.stringify({ results, ids }));
+};
+
+const handleDocuments = async (url: URL) => {
+  if (url.searchParams.has("key")) {
+    return handleDocumentsByKeys(url);
+  }
+  const documentIds = url.searchParams.getAll("id").map(parseDocumentId);
+  if (documentIds.some((id) => !id)) {
+    return responseError("Malformed document ID");
+  }
+  if (documentIds.length > MAX_RESULTS) {
+    return responseError("Too many documents", 413);
+  }
+
+  // shard => indexes of its documents in the request.
+  const shardDocuments = new Map<number, number[]>();
+  documentIds.forEach((id, i) => {
+    if (!shardDocuments.has(id!.shard)) {
+      shardDocuments.set(id!.shard, []);
+    }
+    shardDocuments.get(id!.shard)!.push(i);
+  });
+  const results: unknown[] = documentIds.map(() => null);
+  const rawShardResponses = await Promise.all(
+    [...shardDocuments].map(async ([shard, indexes]) => {
+      const params = new URLSearchParams();
+      for (const i of indexes) {
+        params.append("id", documentIds[i]!.id);
+      }
+      const res = await fetch(`${shardUrl(shard)}/docs?${params}`);
+      if (res.ok) {
+        const { results: shardResults } = await res.json();
+        indexes.forEach((i, j) => {
+          results[i] = shardResults[j];
+        });
+      }
+      return res;
+    })
+  );
+  const failed = rawShardResponses.find((res) => !res.ok);
+  if (failed) {
+    return responseShardError(failed);
+  }
+  return responseRawJson(JSON.stringify({ results }));
+};
+
+const requestHandler = async (request: Request) => {
+  if (request.method == "OPTIONS") {
+    return responsePreflight();
+  }
+
+  const url = new URL(request.url);
+
+  const docMatches = /^\/doc\/([^/]+)$/.exec(url.pathname);
+  return url.pathname === "/search"
+    ? handleSearch(url)
+    : url.pathname === "/docs"
+    ? handleDocuments(url)
+    : docMatches
+    ? handleDocument(docMatches[1])
+    : new Response(null, { status: 404, headers: CORS_HEADERS });
+};
+
+// Called with the request by the entry point for the worker's format, which build/js.rs appends.
+export const handleRequest = requestHandler;
@@ -27,5 +27,7 @@ export const responseRawJson = (json: string, status = 200) =>
     },
   });
 
-export const responseNoResults = () =>
-  responseRawJson(`{"results":[],"continuation":null,"total":0}`);
+export const responseNoResults = (includeIds: boolean = false) =>
+  responseRawJson(
+    `{"results":[],"continuation":null,"total":0${includeIds ? `,"ids":[]` : ""}}`
+  );
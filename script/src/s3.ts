@@ -0,0 +1,82 @@
+import encodeUtf8 from "extlib/js/encodeUtf8";
+
+const toHex = (bytes: ArrayBuffer) =>
+  Array.from(new Uint8Array(bytes))
+    .map((b) => b.toString(16).padStart(2, "0"))
+    .join("");
+
+const sha256Hex = async (data: string) =>
+  toHex(await crypto.subtle.digest("SHA-256", encodeUtf8(data)));
+
+const hmac = async (key: ArrayBuffer | Uint8Array, data: string) =>
+  crypto.subtle.sign(
+    "HMAC",
+    await crypto.subtle.importKey(
+      "raw",
+      key,
+      { name: "HMAC", hash: "SHA-256" },
+      false,
+      ["sign"]
+    ),
+    encodeUtf8(data)
+  );
+
+// SHA-256 of an empty payload, as only GET requests are signed.
+const EMPTY_PAYLOAD_HASH =
+  "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+// Sign a GET request to an S3-compatible API using AWS Signature Version 4, and return `headers` with the headers required by S3 added.
+// All provided headers are signed. See https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-header-based-auth.html.
+export const signS3Request = async (
+  url: string,
+  headers: { [name: string]: string },
+  region: string,
+  accessKeyId: string,
+  secretAccessKey: string,
+  date: Date = new Date()
+): Promise<{ [name: string]: string }> => {
+  const { host, pathname } = new URL(url);
+  // E.g. 20130524T000000Z.
+  const amzDate = date.toISOString().replace(/[-:]|\.\d+/g, "");
+  const dateStamp = amzDate.slice(0, 8);
+  const signed: { [name: string]: string } = {
+    ...headers,
+    "x-amz-content-sha256": EMPTY_PAYLOAD_HASH,
+    "x-amz-date": amzDate,
+  };
+
+  const canonicalHeaders = Object.entries({ ...signed, host })
+    .map(([name, value]) => [name.toLowerCase(), value.trim()])
+    .sort(([a], [b]) => (a < b ? -1 : a > b ? 1 : 0));
+  const signedHeaders = canonicalHeaders.map(([name]) => name).join(";");
+  const canonicalRequest = [
+    "GET",
+    pathname,
+    "",
+    ...canonicalHeaders.map(([name, value]) => `${name}:${value}`),
+    "",
+    signedHeaders,
+    EMPTY_PAYLOAD_HASH,
+  ].join("\n");
+
+  const scope = `${dateStamp}/${region}/s3/aws4_request`;
+  const stringToSign = [
+    "AWS4-HMAC-SHA256",
+    amzDate,
+    scope,
+    await sha256Hex(canonicalRequest),
+  ].join("\n");
+  let signingKey = await hmac(
+    encodeUtf8(`AWS4${secretAccessKey}`),
+    dateStamp
+  );
+  for (const part of [region, "s3", "aws4_request"]) {
+    signingKey = await hmac(signingKey, part);
+  }
+  const signature = toHex(await hmac(signingKey, stringToSign));
+
+  return {
+    ...signed,
+    Authorization: `AWS4-HMAC-SHA256 Credential=${accessKeyId}/${scope}, SignedHeaders=${signedHeaders}, Signature=${signature}`,
+  };
+};
@@ -0,0 +1,1203 @@
+import decodeUtf8 from "extlib/js/decodeUtf8";
+import encodeUtf8 from "extlib/js/encodeUtf8";
+import exists from "extlib/js/exists";
+import { formatFromVarargs, MemoryWalker } from "wasm-sys";
+import { signS3Request } from "./s3";
+
+// Set by Cloudflare to the WebAssembly module that was uploaded alongside this script, or imported or compiled from `runner.wasm` by build/js.rs.
+declare var QUERY_RUNNER_WASM: WebAssembly.Module;
+
+type KVNamespace = {
+  get<T>(key: string, encoding: "json"): Promise<T>;
+  get(key: string, encoding: "text"): Promise<string>;
+  get(key: string, encoding: "arrayBuffer"): Promise<ArrayBuffer>;
+};
+type R2Bucket = {
+  get(
+    key: string,
+    options?: { range?: { offset: number; length: number } }
+  ): Promise<{ arrayBuffer(): Promise<ArrayBuffer> } | null>;
+};
+// Not in TypeScript's libs yet; runtimes must support the "deflate-raw" format to serve compressed layers.
+declare var DecompressionStream: {
+  new (format: "deflate-raw"): {
+    readable: ReadableStream<Uint8Array>;
+    writable: WritableStream<Uint8Array>;
+  };
+};
+// Cloudflare bindings and secrets of the worker, which are globals for service workers and properties of `env` for module workers.
+export type Bindings = {
+  // Set if DATA_STORE is "kv".
+  KV?: KVNamespace;
+  // Set if DATA_STORE is "s3".
+  S3_ACCESS_KEY_ID?: string;
+  S3_SECRET_ACCESS_KEY?: string;
+  // Also has the R2 bucket named DATA_STORE_BUCKET_BINDING if DATA_STORE is "r2".
+  [name: string]: unknown;
+};
+
+let bindings: Bindings = {};
+
+// Set the bindings used to access the data store. Called for every request, as module workers only receive bindings with requests.
+export const setBindings = (newBindings: Bindings) => {
+  bindings = newBindings;
+};
+
+// Following variables are set by build/js.rs.
+// Version of the data format this worker was built for; keep in sync with build::manifest::FORMAT_VERSION.
+declare var FORMAT_VERSION: number;
+// Maximum amount of terms a query can have across all modes.
+declare var MAX_QUERY_TERMS: number;
+// Maximum amount of results returned at once.
+declare var MAX_RESULTS: number;
+declare var DATA_STORE: "kv" | "url" | "packed" | "r2" | "s3" | "embedded";
+// Keep in sync with build::js::generate_worker_js.
+type Layer = {
+  // Prefix of data store keys of the layer's chunks, e.g. "delta/".
+  prefix: string;
+  // Compression of each of the layer's documents and summaries; keep in sync with build::Compression.
+  compression: "none" | "deflate";
+  // Whether the layer has summaries of its documents, stored under `${prefix}summaries/`, which search results return instead of documents.
+  summaries: boolean;
+  // Names of the layer's stored fields; the chunks of a field are stored under `${prefix}fields/${position}/`.
+  fields: string[];
+  // The layer's deleted documents bitmap, if any; stored as `${prefix}deleted/${name}`, or at `offset` in the layer's packed file.
+  deleted: {
+    name: string;
+    offset: number | null;
+    size: number;
+  } | null;
+  // Trained dictionaries of the layer's documents and summaries if they're compressed; stored as `${prefix}${kind}-dictionary/${name}`,
+  // or at `offset` in the layer's packed file. Keep in sync with build::dictionary::DeflateDictionary.
+  documentsDictionary: Dictionary | null;
+  summariesDictionary: Dictionary | null;
+};
+type Dictionary = {
+  name: string;
+  offset: number | null;
+  size: number;
+  // Size of the dictionary once decompressed, which is skipped at the start of decompressed entries.
+  dictionarySize: number;
+};
+// Layers of all indexes. The index of a layer in this array is its ID in the runner's chunk tables.
+declare var LAYERS: Layer[];
+// Keep in sync with build::js::generate_worker_js.
+export type Index = {
+  // Served at `/${name}/search`, or `/search` if null.
+  name: string | null;
+  // Total number of documents.
+  documentCount: number;
+  // IDs of the index's layers ordered from oldest to newest, such as a base index followed by a delta index on top of it.
+  layers: number[];
+};
+declare var INDEXES: Index[];
+
+// Named indexes are served at `/${name}/search`, and an unnamed index at `/search`.
+export const getIndex = (name: string | null): Index | undefined =>
+  INDEXES.find((index) => index.name == name);
+// URL prefix of chunks. For "kv" and "r2", this is an optional prefix of keys instead, such as `shards/0/` for shards sharing a namespace or bucket. For servers, this is a `file:` URL of the output folder unless a URL prefix was provided.
+declare var DATASTORE_URL_PREFIX: string | undefined;
+// Only set if DATA_STORE is "r2".
+declare var DATA_STORE_BUCKET_BINDING: string;
+// Only set if DATA_STORE is "s3".
+declare var DATA_STORE_REGION: string;
+// Whether to keep fetched chunks in memory for the lifetime of the script.
+declare var CACHE_DATA: boolean;
+
+type FileReader = (url: URL, range?: [number, number]) => Promise<ArrayBuffer>;
+
+let readFile: FileReader | undefined;
+
+// Set the function used to read chunks from `file:` URLs, which `fetch` can't read in all runtimes. Called by script/src/server.ts.
+export const setFileReader = (reader: FileReader) => {
+  readFile = reader;
+};
+
+// Fetch the object stored at `key`, or only `range[1]` bytes of it at `range[0]`.
+// Ranges are only requested from packed files and data stores that support them (see build::DataStore::supports_ranges).
+// Chunks are stored by name, which is either their index or a hash of their contents (see build::ChunkNaming).
+let fetchData: (
+  key: string,
+  range?: [number, number]
+) => Promise<ArrayBuffer>;
+if (DATA_STORE == "kv") {
+  fetchData = async (key: string): Promise<ArrayBuffer> => {
+    const data = await bindings.KV!.get(
+      `${DATASTORE_URL_PREFIX ?? ""}${key}`,
+      "arrayBuffer"
+    );
+    console.log("Fetched chunk from KV");
+    return data;
+  };
+} else if (DATA_STORE == "embedded") {
+  // Packed files are embedded in the runner and read directly from its memory.
+  fetchData = async (
+    key: string,
+    range?: [number, number]
+  ): Promise<ArrayBuffer> => {
+    const layer = LAYERS.findIndex(
+      ({ prefix }) => `${prefix}${PACKED_FILE_NAME}` == key
+    );
+    const ptr = layer == -1 ? 0 : queryRunner.embedded_data(layer);
+    if (!ptr || !range) {
+      throw new Error(`${key} is not embedded in runner`);
+    }
+    return wasmMemory.buffer.slice(ptr + range[0], ptr + range[0] + range[1]);
+  };
+} else if (DATA_STORE == "r2") {
+  fetchData = async (
+    key: string,
+    range?: [number, number]
+  ): Promise<ArrayBuffer> => {
+    const bucket = bindings[DATA_STORE_BUCKET_BINDING] as R2Bucket;
+    const object = await bucket.get(
+      `${DATASTORE_URL_PREFIX ?? ""}${key}`,
+      range && { range: { offset: range[0], length: range[1] } }
+    );
+    if (!object) {
+      throw new Error(`${key} does not exist in R2 bucket`);
+    }
+    console.log("Fetched chunk from R2");
+    return object.arrayBuffer();
+  };
+} else if (DATASTORE_URL_PREFIX?.startsWith("file:")) {
+  fetchData = (key: string, range?: [number, number]) =>
+    readFile!(new URL(`${DATASTORE_URL_PREFIX}${key}`), range);
+} else {
+  fetchData = async (
+    key: string,
+    range?: [number, number]
+  ): Promise<ArrayBuffer> => {
+    const url = `${DATASTORE_URL_PREFIX}${key}`;
+    const headers: { [name: string]: string } = range
+      ? { Range: `bytes=${range[0]}-${range[0] + range[1] - 1}` }
+      : {};
+    const res = await fetch(url, {
+      headers:
+        DATA_STORE == "s3"
+          ? await signS3Request(
+              url,
+              headers,
+              DATA_STORE_REGION,
+              bindings.S3_ACCESS_KEY_ID!,
+              bindings.S3_SECRET_ACCESS_KEY!
+            )
+          : headers,
+    });
+    if (!res.ok) {
+      throw new Error(`Failed to fetch ${key} with status ${res.status}`);
+    }
+    const data = await res.arrayBuffer();
+    console.log("Fetched chunk");
+    // Servers without support for Range requests respond with the entire file.
+    return range && res.status != 206
+      ? data.slice(range[0], range[0] + range[1])
+      : data;
+  };
+}
+
+// Keep fetched data in memory, for runtimes without a shared cache in front of the data store such as browsers.
+if (CACHE_DATA) {
+  const fetchUncached = fetchData;
+  const cache = new Map<string, Promise<ArrayBuffer>>();
+  fetchData = (key: string, range?: [number, number]) => {
+    const cacheKey = range ? `${key}#${range[0]}-${range[1]}` : key;
+    let data = cache.get(cacheKey);
+    if (!data) {
+      data = fetchUncached(key, range);
+      cache.set(cacheKey, data);
+      // Allow retrying failed fetches.
+      data.catch(() => cache.delete(cacheKey));
+    }
+    return data;
+  };
+}
+
+// Keep in sync with build::pack::PACKED_FILE_NAME.
+const PACKED_FILE_NAME = "index.bin";
+
+const wasmMemory = new WebAssembly.Memory({ initial: 1024 });
+
+const wasmInstance = new WebAssembly.Instance(QUERY_RUNNER_WASM, {
+  env: {
+    printf(ptrFmt: number, ptrVarargs: number) {
+      // There's no way to print without line terminator in standard JS (the execution runtime is not Node.js).
+      // Any printf calls without a line terminator will still be printed with a line terminator.
+      console.log(
+        formatFromVarargs(
+          queryRunnerMemory.forkAndJump(ptrFmt),
+          queryRunnerMemory.forkAndJump(ptrVarargs)
+        ).replace(/\n$/, "")
+      );
+      return 0;
+    },
+    fprintf(fd: number, ptrFmt: number, ptrVarargs: number) {
+      const msg = formatFromVarargs(
+        queryRunnerMemory.forkAndJump(ptrFmt),
+        queryRunnerMemory.forkAndJump(ptrVarargs)
+      ).replace(/\n$/, "");
+      if (fd == 1) {
+        console.log(msg);
+      } else {
+        throw new Error(`[fprintf] ${msg}`);
+      }
+      return 0;
+    },
+    memory: wasmMemory,
+  },
+});
+
+const queryRunner = wasmInstance.exports as {
+  // Keep synchronised with function declarations wasm/*.c with WASM_EXPORT.
+  format_version(): number;
+  reset(): void;
+  malloc(size: number): number;
+  index_query_malloc(): number;
+  index_query(input: number): number;
+  index_deleted(deleted: number): number;
+  index_is_deleted(deleted: number, doc: number): number;
+  find_chunk_containing_term(
+    layer: number,
+    termPtr: number,
+    termLen: number
+  ): number;
+  find_chunk_containing_doc(layer: number, doc: number): number;
+  find_chunk_containing_summary(layer: number, doc: number): number;
+  find_chunk_containing_key(
+    layer: number,
+    keyPtr: number,
+    keyLen: number
+  ): number;
+  find_chunk_containing_field(
+    layer: number,
+    field: number,
+    doc: number
+  ): number;
+  embedded_data(layer: number): number;
+};
+
+const queryRunnerMemory = new MemoryWalker(wasmMemory.buffer);
+
+if (queryRunner.format_version() !== FORMAT_VERSION) {
+  throw new Error(
+    `Runner was built for data format ${queryRunner.format_version()}, but worker expects ${FORMAT_VERSION}`
+  );
+}
+
+const allocateKey = (key: string | number) => {
+  if (typeof key == "string") {
+    const encoded = encodeUtf8(key);
+    const len = encoded.length;
+    const ptr = queryRunner.malloc(len);
+    queryRunnerMemory.forkAndJump(ptr).writeAll(encoded);
+    return { ptr, len };
+  } else {
+    return key;
+  }
+};
+
+type ChunkRef = {
+  id: number;
+  midPos: number;
+  name: string;
+  offset: number;
+  size: number;
+  secondaryIndexSize: number;
+};
+
+// Kinds of chunks; terms and keys chunks have string keys, and documents, summaries, and fields chunks have document IDs as keys.
+// Fields chunks are identified by the position of the field in the layer's `fields`.
+type ChunkKind =
+  | "terms"
+  | "documents"
+  | "summaries"
+  | "keys"
+  | { field: number };
+
+// Keep in sync with build::build_layer.
+const chunkKindPrefix = (kind: ChunkKind) =>
+  typeof kind == "string" ? kind : `fields/${kind.field}`;
+
+const chunkKeyType = (kind: ChunkKind): "string" | "number" =>
+  kind == "terms" || kind == "keys" ? "string" : "number";
+
+const findContainingChunk = (
+  layer: number,
+  kind: ChunkKind,
+  key: string | number
+): ChunkRef | undefined => {
+  let chunkRefPtr;
+  let cKey = allocateKey(key);
+  if (typeof kind == "object") {
+    chunkRefPtr = queryRunner.find_chunk_containing_field(
+      layer,
+      kind.field,
+      cKey as number
+    );
+  } else if (kind == "summaries") {
+    chunkRefPtr = queryRunner.find_chunk_containing_summary(
+      layer,
+      cKey as number
+    );
+  } else if (typeof cKey == "number") {
+    chunkRefPtr = queryRunner.find_chunk_containing_doc(layer, cKey);
+  } else if (kind == "keys") {
+    chunkRefPtr = queryRunner.find_chunk_containing_key(
+      layer,
+      cKey.ptr,
+      cKey.len
+    );
+  } else {
+    chunkRefPtr = queryRunner.find_chunk_containing_term(
+      layer,
+      cKey.ptr,
+      cKey.len
+    );
+  }
+
+  console.log("Found containing chunk");
+  if (chunkRefPtr === 0) {
+    return undefined;
+  }
+  // Synchronise with `bst_chunk_ref_t` in wasm/chunks.c.
+  const chunkRef = queryRunnerMemory.forkAndJump(chunkRefPtr);
+  const chunkId = chunkRef.readUInt32LE();
+  const chunkMidPos = chunkRef.readUInt32LE();
+  const chunkNamePtr = chunkRef.readUInt32LE();
+  const chunkNameLen = chunkRef.readUInt32LE();
+  const chunkOffset = chunkRef.readUInt32LE();
+  const chunkSize = chunkRef.readUInt32LE();
+  const chunkSecondaryIndexSize = chunkRef.readUInt32LE();
+  const chunkName = decodeUtf8(
+    queryRunnerMemory.forkAndJump(chunkNamePtr).readSliceView(chunkNameLen)
+  );
+
+  return {
+    id: chunkId,
+    midPos: chunkMidPos,
+    name: chunkName,
+    offset: chunkOffset,
+    size: chunkSize,
+    secondaryIndexSize: chunkSecondaryIndexSize,
+  };
+};
+
+// Keys are sorted by their UTF-8 bytes when building (see `Ord for String` in Rust), which orders them like their code points.
+// `localeCompare` and `<` on strings don't, as the latter compares UTF-16 code units.
+const compareStrings = (a: string, b: string): number => {
+  let i = 0;
+  let j = 0;
+  while (i < a.length && j < b.length) {
+    const aCodePoint = a.codePointAt(i)!;
+    const bCodePoint = b.codePointAt(j)!;
+    if (aCodePoint != bCodePoint) {
+      return aCodePoint - bCodePoint;
+    }
+    i += aCodePoint > 0xffff ? 2 : 1;
+    j += bCodePoint > 0xffff ? 2 : 1;
+  }
+  return (i < a.length ? 1 : 0) - (j < b.length ? 1 : 0);
+};
+
+const compareKey = (a: string | number, b: string | number): number => {
+  return typeof a == "number"
+    ? a - (b as number)
+    : compareStrings(a as string, b as string);
+};
+
+const extractKeyAtPosInBstChunkJs = (
+  chunk: MemoryWalker,
+  type: "string" | "number"
+): string | number => {
+  if (type == "string") {
+    // Keep in sync with build::chunks::ChunkStrKey.
+    const len = chunk.readUInt8();
+    return decodeUtf8(chunk.readSliceView(len));
+  } else {
+    // Keep in sync with build::ChunkU32Key.
+    return chunk.readUInt32LE();
+  }
+};
+
+// `chunk` contains the bytes of the chunk from position `start` up to `end`, which is either the entire chunk
+// or a range from its secondary index. The search stops at nodes outside of the range.
+const searchInBstChunkJs = (
+  chunk: MemoryWalker,
+  targetKey: string | number,
+  start: number = 0,
+  end: number = Infinity
+): ArrayBuffer | undefined => {
+  while (true) {
+    const currentKey = extractKeyAtPosInBstChunkJs(
+      chunk,
+      typeof targetKey as any
+    );
+    // Keep in sync with build::chunks::bst::BST::_serialise_node.
+    const leftPos = chunk.readInt32LE();
+    const rightPos = chunk.readInt32LE();
+    const valueLen = chunk.readUInt32LE();
+    const cmp = compareKey(targetKey, currentKey);
+    if (cmp < 0) {
+      if (leftPos < start || leftPos >= end) {
+        break;
+      }
+      chunk.jumpTo(leftPos - start);
+    } else if (cmp == 0) {
+      console.log("Found entry in chunk");
+      return chunk.readSliceCopy(valueLen);
+    } else {
+      if (rightPos < start || rightPos >= end) {
+        break;
+      }
+      chunk.jumpTo(rightPos - start);
+    }
+  }
+  console.log("Searched failed to find entry in chunk");
+  return undefined;
+};
+
+// A contiguous byte range of a chunk containing a subtree of its BST (or a single node).
+type SecondaryIndexRange = {
+  firstKey: string | number;
+  start: number;
+  end: number;
+  rootPos: number;
+};
+
+// Secondary indexes never change for a chunk, so they're kept for the lifetime of the worker.
+const secondaryIndexCache = new Map<string, SecondaryIndexRange[]>();
+
+// Keep in sync with build::chunks::bst::BST::serialise.
+const parseSecondaryIndex = (
+  data: ArrayBuffer,
+  type: "string" | "number"
+): SecondaryIndexRange[] => {
+  const view = new DataView(data);
+  const ranges = [];
+  let pos = 0;
+  while (pos < data.byteLength) {
+    let firstKey;
+    if (type == "string") {
+      const len = view.getUint8(pos);
+      firstKey = decodeUtf8(new Uint8Array(data, pos + 1, len));
+      pos += 1 + len;
+    } else {
+      firstKey = view.getUint32(pos, true);
+      pos += 4;
+    }
+    ranges.push({
+      firstKey,
+      start: view.getUint32(pos, true),
+      end: view.getUint32(pos + 4, true),
+      rootPos: view.getInt32(pos + 8, true),
+    });
+    pos += 12;
+  }
+  return ranges;
+};
+
+// Find the entries for `keys` in a chunk by fetching only the ranges containing them, using the chunk's secondary index.
+// Only used with data stores that support Range requests.
+const findAllInChunkRanges = async (
+  layer: number,
+  kind: ChunkKind,
+  chunk: ChunkRef,
+  keys: (string | number)[]
+): Promise<(ArrayBuffer | undefined)[]> => {
+  // Keep in sync with build::ChunksOutput.
+  const { key, base } =
+    DATA_STORE == "packed"
+      ? {
+          key: `${LAYERS[layer].prefix}${PACKED_FILE_NAME}`,
+          base: chunk.offset,
+        }
+      : {
+          key: `${LAYERS[layer].prefix}${chunkKindPrefix(kind)}/${chunk.name}`,
+          base: 0,
+        };
+  const cacheKey = `${key}#${base}`;
+  let ranges = secondaryIndexCache.get(cacheKey);
+  if (!ranges) {
+    const data =
+      DATA_STORE == "packed"
+        ? await fetchData(key, [base + chunk.size, chunk.secondaryIndexSize])
+        : await fetchData(`${key}.index`);
+    ranges = parseSecondaryIndex(data, chunkKeyType(kind));
+    secondaryIndexCache.set(cacheKey, ranges);
+  }
+
+  // Group by range to avoid fetching the same range more than once.
+  const rangeKeys = new Map<number, [string | number, number][]>();
+  keys.forEach((key, keyIdx) => {
+    // Find the last range whose first key is not greater than the key.
+    let lo = 0;
+    let hi = ranges!.length;
+    while (lo < hi) {
+      const mid = (lo + hi) >> 1;
+      if (compareKey(ranges![mid].firstKey, key) <= 0) {
+        lo = mid + 1;
+      } else {
+        hi = mid;
+      }
+    }
+    if (lo == 0) {
+      return;
+    }
+    if (!rangeKeys.has(lo - 1)) {
+      rangeKeys.set(lo - 1, []);
+    }
+    rangeKeys.get(lo - 1)!.push([key, keyIdx]);
+  });
+
+  const results: (ArrayBuffer | undefined)[] = keys.map(() => undefined);
+  await Promise.all(
+    [...rangeKeys].map(async ([rangeIdx, keys]) => {
+      const { start, end, rootPos } = ranges![rangeIdx];
+      const rangeData = await fetchData(key, [base + start, end - start]);
+      for (const [key, keyIdx] of keys) {
+        results[keyIdx] = searchInBstChunkJs(
+          new MemoryWalker(rangeData).jumpTo(rootPos - start),
+          key,
+          start,
+          end
+        );
+      }
+    })
+  );
+  return results;
+};
+
+const findAllInChunks = async (
+  layer: number,
+  kind: ChunkKind,
+  keys: (string | number)[]
+): Promise<(ArrayBuffer | undefined)[]> => {
+  // Keep in sync with build::ChunksOutput.
+  const chunkIdPrefix = `${LAYERS[layer].prefix}${chunkKindPrefix(kind)}/`;
+  const results = [];
+  // Group by chunk to avoid repeated fetches and memory management.
+  const chunks = new Map<
+    number,
+    {
+      keys: [string | number, number][];
+      ref: ChunkRef;
+    }
+  >();
+  for (const key of keys) {
+    const chunkRef = findContainingChunk(layer, kind, key);
+    // We reserve a spot in `results` and keep track of it so that results are in the same order as `keys`,
+    // and missing keys have `undefined` and can be detected.
+    const resultIdx = results.push(undefined) - 1;
+    if (!chunkRef) {
+      continue;
+    }
+    if (!chunks.has(chunkRef.id)) {
+      chunks.set(chunkRef.id, {
+        keys: [],
+        ref: chunkRef,
+      });
+    }
+    chunks.get(chunkRef.id)!.keys.push([key, resultIdx]);
+  }
+
+  // We want to process chunks one by one as otherwise we will run into memory limits
+  // from fetching and allocating memory for too many at once.
+  for (const { keys, ref } of chunks.values()) {
+    if (DATA_STORE != "kv" && DATA_STORE != "embedded") {
+      const entries = await findAllInChunkRanges(
+        layer,
+        kind,
+        ref,
+        keys.map(([key]) => key)
+      );
+      keys.forEach(([, resultIdx], i) => {
+        results[resultIdx] = entries[i];
+      });
+      continue;
+    }
+    const { midPos, name, offset, size } = ref;
+    const chunkData =
+      DATA_STORE == "embedded"
+        ? await fetchData(`${LAYERS[layer].prefix}${PACKED_FILE_NAME}`, [
+            offset,
+            size,
+          ])
+        : await fetchData(`${chunkIdPrefix}${name}`);
+    // We need to reset as otherwise we might overflow memory with unused previous chunks.
+    // queryRunner.reset();
+    // const res = searchInBstChunk(chunkData, chunkRef.midPos, key);
+    for (const [key, resultIdx] of keys) {
+      const entry = searchInBstChunkJs(
+        new MemoryWalker(chunkData).jumpTo(midPos),
+        key
+      );
+      if (!entry) {
+        continue;
+      }
+      results[resultIdx] = entry;
+    }
+  }
+  return results;
+};
+
+// Keep order in sync with mode_t.
+export type ParsedQuery = [
+  // Require.
+  string[],
+  // Contain.
+  string[],
+  // Exclude.
+  string[]
+];
+
+// Take a raw query string and parse in into an array with three subarrays, each subarray representing terms for a mode.
+const parseQuery = (termsRaw: string[]): ParsedQuery | undefined => {
+  const modeTerms: ParsedQuery = [
+    Array<string>(),
+    Array<string>(),
+    Array<string>(),
+  ];
+  for (const value of termsRaw) {
+    // Synchronise mode IDs with mode_t enum in wasm/index.c.
+    const matches = /^([012])_([^&]+)(?:&|$)/.exec(value);
+    if (!matches) {
+      return;
+    }
+    const mode = Number.parseInt(matches[1], 10);
+    const term = decodeURIComponent(matches[2]);
+    modeTerms[mode].push(term);
+  }
+
+  return modeTerms;
+};
+
+type QueryResult = {
+  continuation: number | null;
+  total: number;
+  documents: number[];
+};
+
+const readResult = (result: MemoryWalker): QueryResult => {
+  // Synchronise with `results_t` in wasm/index.c.
+  const continuation = result.readInt32LE();
+  const total = result.readUInt32LE();
+  const count = result.readUInt8();
+  // Starts from next WORD_SIZE (uint32_t) due to alignment.
+  result.skip(3);
+  const documents: number[] = [];
+  for (let resultNo = 0; resultNo < count; resultNo++) {
+    // Synchronise with `doc_id_t` in wasm/index.c.
+    const docId = result.readUInt32LE();
+    documents.push(docId);
+  }
+  return {
+    continuation: continuation == -1 ? null : continuation,
+    total,
+    documents,
+  };
+};
+
+// A term's serialised bitmaps from every layer that contains it, or undefined if no layer contains it.
+type TermBitmapParts = ArrayBuffer[] | undefined;
+
+const findSerialisedTermBitmaps = async (
+  index: Index,
+  query: ParsedQuery
+): Promise<TermBitmapParts[][]> => {
+  const layerModeTermBitmaps = await Promise.all(
+    index.layers.map((layer) =>
+      Promise.all(
+        query.map((modeTerms) => findAllInChunks(layer, "terms", modeTerms))
+      )
+    )
+  );
+  return query.map((modeTerms, mode) =>
+    modeTerms.map((_, termIdx) => {
+      const parts = layerModeTermBitmaps
+        .map((modeTermBitmaps) => modeTermBitmaps[mode][termIdx])
+        .filter(exists);
+      return parts.length ? parts : undefined;
+    })
+  );
+};
+
+// Deleted documents bitmaps never change for a layer, as a redeployed layer comes with a new worker, so they're kept for the lifetime of the worker.
+const deletedBitmapCache = new Map<number, Promise<ArrayBuffer>>();
+
+const findSerialisedDeletedBitmaps = (index: Index): Promise<ArrayBuffer[]> =>
+  Promise.all(
+    index.layers
+      .filter((layer) => LAYERS[layer].deleted != null)
+      .map((layer) => {
+        let data = deletedBitmapCache.get(layer);
+        if (!data) {
+          const { prefix, deleted } = LAYERS[layer];
+          data =
+            DATA_STORE == "packed" || DATA_STORE == "embedded"
+              ? fetchData(`${prefix}${PACKED_FILE_NAME}`, [
+                  deleted!.offset!,
+                  deleted!.size,
+                ])
+              : fetchData(`${prefix}deleted/${deleted!.name}`);
+          // Don't keep failed fetches.
+          data.catch(() => deletedBitmapCache.delete(layer));
+          deletedBitmapCache.set(layer, data);
+        }
+        return data;
+      })
+  );
+
+// Copy serialised bitmaps into runner memory and return a pointer to a NULL-terminated array of pointers to them.
+const allocateBitmapParts = (parts: ArrayBuffer[]): number => {
+  const partsPtr = queryRunner.malloc((parts.length + 1) * 4);
+  const partsArray = queryRunnerMemory.forkAndJump(partsPtr);
+  for (const bitmap of parts) {
+    const ptr = queryRunner.malloc(bitmap.byteLength);
+    queryRunnerMemory.forkAndJump(ptr).writeAll(new Uint8Array(bitmap));
+    // WASM is LE.
+    partsArray.writeUInt32LE(ptr);
+  }
+  partsArray.writeUInt32LE(0);
+  return partsPtr;
+};
+
+const buildIndexQuery = async (
+  firstRank: number,
+  documentCount: number,
+  modeTermBitmaps: ArrayBuffer[][][],
+  deletedBitmaps: ArrayBuffer[]
+): Promise<Uint8Array> => {
+  const termCount = modeTermBitmaps.reduce(
+    (count, modeTerms) => count + modeTerms.length,
+    0
+  );
+
+  // Synchronise with index_query_t.
+  const input = new MemoryWalker(
+    new ArrayBuffer(4 + 4 + 4 + (termCount + 3) * 4)
+  );
+  input.writeUInt32LE(firstRank);
+  input.writeUInt32LE(documentCount);
+  input.writeUInt32LE(
+    deletedBitmaps.length ? allocateBitmapParts(deletedBitmaps) : 0
+  );
+  for (const mode of modeTermBitmaps) {
+    for (const parts of mode) {
+      input.writeUInt32LE(allocateBitmapParts(parts));
+    }
+    input.writeUInt32LE(0);
+  }
+
+  return new Uint8Array(input.buffer);
+};
+
+// Dictionaries never change for a layer, so they're kept for the lifetime of the worker.
+const dictionaryCache = new Map<string, Promise<ArrayBuffer>>();
+
+const fetchDictionary = (
+  layer: number,
+  kind: "documents" | "summaries",
+  dictionary: Dictionary
+): Promise<ArrayBuffer> => {
+  const { prefix } = LAYERS[layer];
+  const cacheKey = `${prefix}${kind}`;
+  let data = dictionaryCache.get(cacheKey);
+  if (!data) {
+    data =
+      DATA_STORE == "packed" || DATA_STORE == "embedded"
+        ? fetchData(`${prefix}${PACKED_FILE_NAME}`, [
+            dictionary.offset!,
+            dictionary.size,
+          ])
+        : fetchData(`${prefix}${kind}-dictionary/${dictionary.name}`);
+    // Don't keep failed fetches.
+    data.catch(() => dictionaryCache.delete(cacheKey));
+    dictionaryCache.set(cacheKey, data);
+  }
+  return data;
+};
+
+// Keep in sync with build::ENTRY_UNCOMPRESSED and build::ENTRY_DEFLATE.
+const ENTRY_UNCOMPRESSED = 0;
+const ENTRY_DEFLATE = 1;
+
+// Decompress a document or summary of a layer. Compressed entries start with a flag; entries compressed with DEFLATE continue the stream
+// of the layer's compressed dictionary, so they're decompressed after it and the dictionary is skipped.
+// The stream is read directly instead of using `Response`, which tester/server.js replaces.
+const decompress = async (
+  layer: number,
+  kind: "documents" | "summaries",
+  data: ArrayBuffer
+): Promise<ArrayBuffer> => {
+  if (LAYERS[layer].compression == "none") {
+    return data;
+  }
+  const flag = new Uint8Array(data)[0];
+  if (flag == ENTRY_UNCOMPRESSED) {
+    return data.slice(1);
+  }
+  if (flag != ENTRY_DEFLATE) {
+    throw new Error(`Unknown compressed entry flag ${flag}`);
+  }
+  const dictionary =
+    kind == "summaries"
+      ? LAYERS[layer].summariesDictionary!
+      : LAYERS[layer].documentsDictionary!;
+  const compressedDictionary = await fetchDictionary(layer, kind, dictionary);
+  const stream = new DecompressionStream("deflate-raw");
+  const writer = stream.writable.getWriter();
+  const reader = stream.readable.getReader();
+  const parts: Uint8Array[] = [];
+  await Promise.all([
+    writer
+      .write(new Uint8Array(compressedDictionary))
+      .then(() => writer.write(new Uint8Array(data, 1)))
+      .then(() => writer.close()),
+    (async () => {
+      for (let part; !(part = await reader.read()).done; ) {
+        parts.push(part.value!);
+      }
+    })(),
+  ]);
+  const decompressed = new Uint8Array(
+    parts.reduce((size, part) => size + part.length, 0) -
+      dictionary.dictionarySize
+  );
+  let skip = dictionary.dictionarySize;
+  let offset = 0;
+  for (const part of parts) {
+    const start = Math.min(skip, part.length);
+    skip -= start;
+    decompressed.set(part.subarray(start), offset);
+    offset += part.length - start;
+  }
+  return decompressed.buffer;
+};
+
+// Look up documents in the newest layer first, so that a delta can override documents in its base.
+// Summaries are looked up instead of documents in layers that have them.
+const findDocuments = async (
+  index: Index,
+  documentIds: number[],
+  kind: "documents" | "summaries" = "documents"
+): Promise<(ArrayBuffer | undefined)[]> => {
+  const documents: (ArrayBuffer | undefined)[] = documentIds.map(
+    () => undefined
+  );
+  for (const layer of index.layers.slice().reverse()) {
+    const missing = documentIds
+      .map((_, i) => i)
+      .filter((i) => !documents[i]);
+    if (!missing.length) {
+      break;
+    }
+    const layerKind = LAYERS[layer].summaries ? kind : "documents";
+    const found = await findAllInChunks(
+      layer,
+      layerKind,
+      missing.map((i) => documentIds[i])
+    );
+    const decompressed = await Promise.all(
+      found.map((doc) => doc && decompress(layer, layerKind, doc))
+    );
+    decompressed.forEach((doc, j) => {
+      documents[missing[j]] = doc;
+    });
+  }
+  return documents;
+};
+
+// Only return `fields` of documents. If every layer stores every field, only their chunks are fetched; otherwise, documents are fetched and parsed.
+// Fields are in the order of `fields`, and fields missing from a document are omitted. Documents without any of `fields` are undefined.
+const findDocumentFields = async (
+  index: Index,
+  documentIds: number[],
+  fields: string[]
+): Promise<(Uint8Array | undefined)[]> => {
+  const layers = index.layers.map((layer) => LAYERS[layer]);
+  if (
+    !layers.every((layer) => fields.every((f) => layer.fields.includes(f)))
+  ) {
+    return (await findDocuments(index, documentIds)).map((doc) => {
+      if (!doc) {
+        return undefined;
+      }
+      const value = JSON.parse(decodeUtf8(new Uint8Array(doc)));
+      const projected: { [name: string]: unknown } = {};
+      let found = false;
+      for (const name of fields) {
+        if (
+          value !== null &&
+          typeof value == "object" &&
+          !Array.isArray(value) &&
+          Object.prototype.hasOwnProperty.call(value, name)
+        ) {
+          projected[name] = value[name];
+          found = true;
+        }
+      }
+      return found ? encodeUtf8(JSON.stringify(projected)) : undefined;
+    });
+  }
+
+  // Values are raw JSON, as they appeared in the document.
+  const values: (ArrayBuffer | undefined)[][] = await Promise.all(
+    fields.map(async (name) => {
+      const fieldValues: (ArrayBuffer | undefined)[] = documentIds.map(
+        () => undefined
+      );
+      // Document IDs don't overlap between layers, so a value found in one layer is never overridden by another.
+      for (const layer of index.layers.slice().reverse()) {
+        const field = LAYERS[layer].fields.indexOf(name);
+        if (field == -1) {
+          continue;
+        }
+        const missing = documentIds
+          .map((_, i) => i)
+          .filter((i) => !fieldValues[i]);
+        if (!missing.length) {
+          break;
+        }
+        const found = await findAllInChunks(
+          layer,
+          { field },
+          missing.map((i) => documentIds[i])
+        );
+        found.forEach((value, j) => {
+          fieldValues[missing[j]] = value;
+        });
+      }
+      return fieldValues;
+    })
+  );
+  return documentIds.map((_, i) => {
+    const members = fields
+      .map((name, f) =>
+        values[f][i] &&
+        `${JSON.stringify(name)}:${decodeUtf8(new Uint8Array(values[f][i]!))}`
+      )
+      .filter(exists);
+    return members.length ? encodeUtf8(`{${members.join(",")}}`) : undefined;
+  });
+};
+
+const executePostingsListQuery = (
+  queryData: Uint8Array
+): QueryResult | undefined => {
+  const inputPtr = queryRunner.index_query_malloc();
+  queryRunnerMemory.forkAndJump(inputPtr).writeAll(queryData);
+  const outputPtr = queryRunner.index_query(inputPtr);
+  return outputPtr == 0
+    ? undefined
+    : readResult(queryRunnerMemory.forkAndJump(outputPtr));
+};
+
+export type SearchRequest = {
+  query: ParsedQuery;
+  continuation: number;
+  // Only return these top-level fields of documents, if set.
+  fields?: string[];
+};
+
+// Parse the terms (`t`), continuation (`c`), and fields (`fields`) parameters of a search, or return an error and its HTTP status.
+export const parseSearchRequest = (
+  params: URLSearchParams
+): SearchRequest | { error: string; status: number } => {
+  // NOTE: Just because there are no valid words does not mean that there are no valid results.
+  // For example, excluding an invalid word actually results in all entries matching.
+  const query = parseQuery(params.getAll("t"));
+  if (!query) {
+    return { error: "Malformed query", status: 400 };
+  }
+  const continuation = Math.max(
+    0,
+    Number.parseInt(params.get("c") || "", 10) || 0
+  );
+
+  const termCount = query.reduce(
+    (count, modeTerms) => count + modeTerms.length,
+    0
+  );
+  if (termCount > MAX_QUERY_TERMS) {
+    return { error: "Too many terms", status: 413 };
+  }
+
+  // Fields are separated by commas, e.g. `fields=title,url`.
+  const rawFields = params.get("fields");
+  let fields;
+  if (rawFields != null) {
+    fields = rawFields.split(",");
+    if (fields.some((name) => !name)) {
+      return { error: "Malformed fields", status: 400 };
+    }
+    fields = fields.filter((name, i) => fields!.indexOf(name) == i);
+  }
+  return { query, continuation, fields };
+};
+
+export type SearchResults = {
+  total: number;
+  continuation: number | null;
+  // Each document is a JSON serialised value encoded in UTF-8. Documents are summaries if the index has them.
+  documents: Uint8Array[];
+  // IDs of `documents`, in the same order.
+  ids: number[];
+};
+
+export const search = async (
+  index: Index,
+  { query, continuation, fields }: SearchRequest
+): Promise<SearchResults> => {
+  const modeTermBitmaps = await findSerialisedTermBitmaps(index, query);
+  console.log("Bit sets retrieved");
+  // Handling non-existent terms:
+  // - If REQUIRE, then immediately return zero results, regardless of other terms of any mode.
+  // - If CONTAIN, then simply omit.
+  // - If EXCLUDE, then it depends; if there are other terms of any mode, then simply omit. If there are no other terms of any mode, then return default results.
+  if (modeTermBitmaps[0].some((bm) => !bm)) {
+    return { total: 0, continuation: null, documents: [], ids: [] };
+  }
+  modeTermBitmaps[1] = modeTermBitmaps[1].filter((bm) => bm);
+  modeTermBitmaps[2] = modeTermBitmaps[2].filter((bm) => bm);
+
+  const deletedBitmaps = await findSerialisedDeletedBitmaps(index);
+
+  let result: QueryResult;
+  // Default results can only be enumerated directly if no documents have been deleted; otherwise, the runner removes them.
+  if (
+    modeTermBitmaps.every((modeTerms) => !modeTerms.length) &&
+    !deletedBitmaps.length
+  ) {
+    console.log("Using default results");
+    const after = continuation + MAX_RESULTS;
+    result = {
+      continuation: index.documentCount > after ? after : null,
+      documents: Array.from(
+        { length: MAX_RESULTS },
+        (_, i) => continuation + i
+      ).filter((docId) => docId >= 0 && docId < index.documentCount),
+      total: index.documentCount,
+    };
+  } else {
+    queryRunner.reset();
+    const indexQueryData = await buildIndexQuery(
+      continuation,
+      index.documentCount,
+      modeTermBitmaps as ArrayBuffer[][][],
+      deletedBitmaps
+    );
+    console.log("Query built");
+    const maybeResult = await executePostingsListQuery(indexQueryData);
+    if (!maybeResult) {
+      throw new Error(`Failed to execute query`);
+    }
+    result = maybeResult;
+    console.log("Query executed");
+  }
+
+  const found: (ArrayBuffer | Uint8Array | undefined)[] = fields
+    ? await findDocumentFields(index, result.documents, fields)
+    : await findDocuments(index, result.documents, "summaries");
+  const ids = result.documents.filter((_, i) => found[i]);
+  const documents = found.filter(exists).map((d) => new Uint8Array(d));
+  console.log("Documents fetched");
+  return {
+    total: result.total,
+    continuation: result.continuation,
+    documents,
+    ids,
+  };
+};
+
+// Parse the document IDs (`id`) of a documents request, or return an error and its HTTP status.
+export const parseDocumentIds = (
+  values: string[]
+): number[] | { error: string; status: number } => {
+  if (values.some((id) => !/^[0-9]+$/.test(id))) {
+    return { error: "Malformed document ID", status: 400 };
+  }
+  if (values.length > MAX_RESULTS) {
+    return { error: "Too many documents", status: 413 };
+  }
+  return values.map((id) => Number.parseInt(id, 10));
+};
+
+// Look up documents by ID, in the same order. Documents that don't exist or have been deleted are undefined.
+export const getDocuments = async (
+  index: Index,
+  documentIds: number[]
+): Promise<(Uint8Array | undefined)[]> => {
+  const deletedBitmaps = await findSerialisedDeletedBitmaps(index);
+  let isDeleted = (_: number) => false;
+  if (deletedBitmaps.length) {
+    queryRunner.reset();
+    const deletedPtr = queryRunner.index_deleted(
+      allocateBitmapParts(deletedBitmaps)
+    );
+    isDeleted = (id) => !!queryRunner.index_is_deleted(deletedPtr, id);
+  }
+  const valid = documentIds.map(
+    (id) => id < index.documentCount && !isDeleted(id)
+  );
+  const found = await findDocuments(
+    index,
+    documentIds.filter((_, i) => valid[i])
+  );
+  let foundIdx = 0;
+  return documentIds.map((_, i) => {
+    const doc = valid[i] ? found[foundIdx++] : undefined;
+    return doc && new Uint8Array(doc);
+  });
+};
+
+// Parse the document keys (`key`) of a documents request, or return an error and its HTTP status.
+export const parseDocumentKeys = (
+  values: string[]
+): string[] | { error: string; status: number } => {
+  // Keep in sync with data::document_keys::DocumentKeysReader.
+  if (values.some((key) => !key || encodeUtf8(key).length > 255)) {
+    return { error: "Malformed document key", status: 400 };
+  }
+  if (values.length > MAX_RESULTS) {
+    return { error: "Too many documents", status: 413 };
+  }
+  return values;
+};
+
+// Look up documents by external key, in the same order, along with their IDs.
+// Documents that don't exist or have been deleted are undefined, as are their IDs.
+export const getDocumentsByKeys = async (
+  index: Index,
+  keys: string[]
+): Promise<{
+  ids: (number | undefined)[];
+  documents: (Uint8Array | undefined)[];
+}> => {
+  // Look up keys in the newest layer first, as a delta replaces documents of its base with the same key.
+  const ids: (number | undefined)[] = keys.map(() => undefined);
+  for (const layer of index.layers.slice().reverse()) {
+    const missing = keys.map((_, i) => i).filter((i) => ids[i] == undefined);
+    if (!missing.length) {
+      break;
+    }
+    const found = await findAllInChunks(
+      layer,
+      "keys",
+      missing.map((i) => keys[i])
+    );
+    found.forEach((id, j) => {
+      // Keep in sync with build::build_layer.
+      ids[missing[j]] = id && new DataView(id).getUint32(0, true);
+    });
+  }
+  const foundIds = ids.filter(exists);
+  const foundDocuments = await getDocuments(index, foundIds);
+  let foundIdx = 0;
+  const documents = ids.map((id) =>
+    id == undefined ? undefined : foundDocuments[foundIdx++]
+  );
+  return {
+    ids: ids.map((id, i) => (documents[i] ? id : undefined)),
+    documents,
+  };
+};
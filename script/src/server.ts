@@ -0,0 +1,91 @@
+import { handleRequest as handleWorkerRequest } from "./main";
+import { setFileReader } from "./search";
+
+// Following variables are set by build/js.rs to imports of Node.js modules, which Deno also provides.
+declare var NODE_FS: {
+  open(
+    path: URL,
+    flags: string
+  ): Promise<{
+    read(
+      buffer: Uint8Array,
+      offset: number,
+      length: number,
+      position: number
+    ): Promise<{ bytesRead: number }>;
+    readFile(): Promise<Uint8Array>;
+    close(): Promise<void>;
+  }>;
+};
+declare var NODE_HTTP: {
+  createServer(
+    listener: (
+      req: { method: string; url: string; headers: { host?: string } },
+      res: {
+        writeHead(status: number, headers: { [name: string]: string }): void;
+        end(body?: Uint8Array): void;
+      }
+    ) => void
+  ): { listen(port: number, callback: () => void): void };
+};
+
+// Only one of these is defined, depending on the runtime.
+declare var Deno:
+  | {
+      serve(
+        options: { port: number },
+        handler: (request: Request) => Promise<Response>
+      ): unknown;
+      env: { get(name: string): string | undefined };
+    }
+  | undefined;
+declare var process: { env: { [name: string]: string | undefined } };
+
+setFileReader(async (url, range) => {
+  const file = await NODE_FS.open(url, "r");
+  try {
+    if (!range) {
+      const data = await file.readFile();
+      return data.buffer.slice(
+        data.byteOffset,
+        data.byteOffset + data.byteLength
+      ) as ArrayBuffer;
+    }
+    const data = new Uint8Array(range[1]);
+    const { bytesRead } = await file.read(data, 0, range[1], range[0]);
+    return data.buffer.slice(0, bytesRead) as ArrayBuffer;
+  } finally {
+    await file.close();
+  }
+});
+
+// Handles a request to the same API as the worker. The data stores a server can use don't need bindings.
+export const handleRequest = (request: Request) =>
+  handleWorkerRequest(request, {});
+
+// Listen on the port in the PORT environment variable, or 8080.
+export const serve = () => {
+  if (typeof Deno != "undefined") {
+    Deno.serve({ port: Number(Deno.env.get("PORT") ?? 8080) }, handleRequest);
+    return;
+  }
+
+  const port = Number(process.env.PORT ?? 8080);
+  NODE_HTTP.createServer(async (req, res) => {
+    let response: Response;
+    try {
+      response = await handleRequest(
+        new Request(`http://${req.headers.host ?? "localhost"}${req.url}`, {
+          method: req.method,
+        })
+      );
+    } catch (err) {
+      console.error(err);
+      response = new Response(null, { status: 500 });
+    }
+    const headers: { [name: string]: string } = {};
+    response.headers.forEach((value, name) => (headers[name] = value));
+    res.writeHead(response.status, headers);
+    res.end(new Uint8Array(await response.arrayBuffer()));
+  }).listen(port, () => console.log(`Listening on port ${port}`));
+};
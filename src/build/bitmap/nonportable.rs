@@ -48,4 +48,29 @@ impl Bitmap {
 
         dst
     }
+
+    #[inline]
+    pub fn deserialize(data: &[u8]) -> Self {
+        let bitmap = unsafe { croaring_sys::roaring_bitmap_deserialize(data.as_ptr() as *const ::libc::c_void) };
+
+        Bitmap { bitmap }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        unsafe { croaring_sys::roaring_bitmap_get_cardinality(self.bitmap).try_into().unwrap() }
+    }
+
+    #[inline]
+    pub fn to_vec(&self) -> Vec<u32> {
+        let len = self.len();
+        let mut dst = Vec::<u32>::with_capacity(len);
+
+        unsafe {
+            croaring_sys::roaring_bitmap_to_uint32_array(self.bitmap, dst.as_mut_ptr());
+            dst.set_len(len);
+        }
+
+        dst
+    }
 }
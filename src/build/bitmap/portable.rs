@@ -20,6 +20,7 @@ impl Bitmap {
         // Not implemented by RoaringBitmap.
     }
 
+    #[allow(dead_code)]
     #[inline]
     pub fn get_serialized_size_in_bytes(&self) -> usize {
         self.bitmap.serialized_size()
@@ -31,4 +32,19 @@ impl Bitmap {
         self.bitmap.serialize_into(&mut out).unwrap();
         out
     }
+
+    #[inline]
+    pub fn deserialize(data: &[u8]) -> Self {
+        Bitmap { bitmap: RoaringBitmap::deserialize_from(data).expect("deserialise bitmap") }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bitmap.len() as usize
+    }
+
+    #[inline]
+    pub fn to_vec(&self) -> Vec<u32> {
+        self.bitmap.iter().collect()
+    }
 }
@@ -1,10 +1,16 @@
 use std::convert::TryInto;
 use std::io::Write;
 
-use byteorder::{WriteBytesExt, LittleEndian};
+use byteorder::{ByteOrder, WriteBytesExt, LittleEndian};
 
-use crate::build::chunks::ChunkEntryKey;
+use crate::build::ChunkNaming;
+use crate::build::chunks::{ChunkEntryKey, ChunkKey, ChunkSink, SerialisedChunk};
 
+// Approximate maximum bytes of each range in a chunk's secondary index.
+const SECONDARY_INDEX_RANGE_SIZE: usize = 64 * 1024;
+
+// Named after the data structure, as in the C runner.
+#[allow(clippy::upper_case_acronyms)]
 struct BST<K: ChunkEntryKey> {
     values: Vec<(K, Vec<u8>)>,
     serialised_len: usize,
@@ -30,52 +36,98 @@ impl<K: ChunkEntryKey> BST<K> {
     /**
      * WARNING: Key must be greater than any previously inserted key.
      */
-    fn insert(&mut self, key: K, value: Vec<u8>) -> () {
+    fn insert(&mut self, key: K, value: Vec<u8>) {
         self.serialised_len += BST::<K>::insertion_cost(&key, &value);
         self.values.push((key, value));
     }
 
-    fn _serialise_node(out: &mut Vec<u8>, left_pos: i32, right_pos: i32, key: &K, value: &[u8]) -> i32 {
-        let pos: i32 = out.len().try_into().expect("too much data");
+    // Write a node to the start of `out`, which is at position `pos` of the BST.
+    fn _serialise_node(mut out: &mut [u8], pos: usize, left_pos: i32, right_pos: i32, key: &K, value: &[u8]) -> i32 {
         let value_len: u32 = value.len().try_into().expect("value is too long");
         out.write_all(key.bytes()).expect("write package data");
         out.write_i32::<LittleEndian>(left_pos).expect("write package data");
         out.write_i32::<LittleEndian>(right_pos).expect("write package data");
         out.write_u32::<LittleEndian>(value_len).expect("write package data");
         out.write_all(value).expect("write package data");
-        pos
+        pos.try_into().expect("too much data")
     }
 
-    // Serialise nodes with indices in the range [lo, hi] (inclusive).
+    // Serialise nodes with indices in the range [lo, hi] (inclusive) into `out`, which is exactly as long as those nodes and at position `pos` of the BST.
     // Return the position of the first byte of the serialised middle node.
-    fn _serialise_area(&self, out: &mut Vec<u8>, lo: usize, hi: usize) -> i32 {
+    // `offsets[i]` is the serialised length of all nodes before index i, so the position of every area is known before it's serialised.
+    fn _serialise_area(&self, out: &mut [u8], pos: usize, offsets: &[usize], lo: usize, hi: usize) -> i32 {
         // Add first to prevent underflow.
         match hi + 1 - lo {
             0 => unreachable!(),
             1 => {
                 let (key, value) = &self.values[lo];
-                BST::_serialise_node(out, -1, -1, key, value)
+                BST::_serialise_node(out, pos, -1, -1, key, value)
             }
             2 => {
                 let (left_key, left_value) = &self.values[lo];
                 let (right_key, right_value) = &self.values[hi];
-                let left_pos = BST::_serialise_node(out, -1, -1, left_key, left_value);
-                BST::_serialise_node(out, left_pos, -1, right_key, right_value)
+                let (left_out, right_out) = out.split_at_mut(offsets[hi] - offsets[lo]);
+                let left_pos = BST::_serialise_node(left_out, pos, -1, -1, left_key, left_value);
+                BST::_serialise_node(right_out, pos + left_out.len(), left_pos, -1, right_key, right_value)
             }
             dist => {
                 let mid = lo + (dist / 2);
                 let (key, value) = &self.values[mid];
-                let left_pos = self._serialise_area(out, lo, mid - 1);
-                let right_pos = self._serialise_area(out, mid + 1, hi);
-                BST::_serialise_node(out, left_pos, right_pos, key, value)
+                let (left_out, rest) = out.split_at_mut(offsets[mid] - offsets[lo]);
+                let (right_out, node_out) = rest.split_at_mut(offsets[hi + 1] - offsets[mid + 1]);
+                let left_pos = self._serialise_area(left_out, pos, offsets, lo, mid - 1);
+                let right_pos = self._serialise_area(right_out, pos + left_out.len(), offsets, mid + 1, hi);
+                BST::_serialise_node(node_out, pos + left_out.len() + right_out.len(), left_pos, right_pos, key, value)
             }
         }
     }
 
-    fn serialise(&self) -> (u32, Vec<u8>) {
-        let mut out = Vec::<u8>::new();
-        let centre_pos = self._serialise_area(&mut out, 0, self.values.len() - 1);
-        (centre_pos.try_into().unwrap(), out)
+    // Serialise nodes with indices in the range [lo, hi] exactly like _serialise_area, and return the ranges for the secondary index as
+    // (index of first node, start, end, position of root). An area of at most SECONDARY_INDEX_RANGE_SIZE bytes is one range;
+    // otherwise, its middle node is a range by itself and its sides are split further, serialising both sides in parallel.
+    fn _serialise_indexed_area(&self, out: &mut [u8], pos: usize, offsets: &[usize], lo: usize, hi: usize) -> (i32, Vec<(usize, usize, usize, i32)>) {
+        if hi - lo < 2 || out.len() <= SECONDARY_INDEX_RANGE_SIZE {
+            let root_pos = self._serialise_area(out, pos, offsets, lo, hi);
+            return (root_pos, vec![(lo, pos, pos + out.len(), root_pos)]);
+        };
+        let mid = lo + ((hi + 1 - lo) / 2);
+        let (key, value) = &self.values[mid];
+        let (left_out, rest) = out.split_at_mut(offsets[mid] - offsets[lo]);
+        let (right_out, node_out) = rest.split_at_mut(offsets[hi + 1] - offsets[mid + 1]);
+        let right_start = pos + left_out.len();
+        let node_start = right_start + right_out.len();
+        let ((left_pos, mut ranges), (right_pos, right_ranges)) = rayon::join(
+            || self._serialise_indexed_area(left_out, pos, offsets, lo, mid - 1),
+            || self._serialise_indexed_area(right_out, right_start, offsets, mid + 1, hi),
+        );
+        let root_pos = BST::_serialise_node(node_out, node_start, left_pos, right_pos, key, value);
+        ranges.extend(right_ranges);
+        ranges.push((mid, node_start, node_start + node_out.len(), root_pos));
+        (root_pos, ranges)
+    }
+
+    // Returns the position of the root node, the serialised BST, and its secondary index.
+    // The secondary index lists contiguous byte ranges of the BST in key order, each containing a subtree (or a single node), so that
+    // an entry can be found by fetching only the range containing it. Each range is serialised as its first key, then the range's start,
+    // end, and root node position as u32 LE.
+    fn serialise(&self) -> (u32, Vec<u8>, Vec<u8>) {
+        let mut offsets = Vec::with_capacity(self.values.len() + 1);
+        offsets.push(0);
+        for (key, value) in self.values.iter() {
+            offsets.push(offsets.last().unwrap() + BST::<K>::insertion_cost(key, value));
+        };
+        let mut out = vec![0u8; self.serialised_len];
+        let (centre_pos, mut ranges) = self._serialise_indexed_area(&mut out, 0, &offsets, 0, self.values.len() - 1);
+        ranges.sort_unstable_by_key(|(first, ..)| *first);
+        let mut secondary_index = Vec::<u8>::new();
+        for (first, start, end, pos) in ranges {
+            // Keep in sync with parseSecondaryIndex in script/src/search.ts.
+            secondary_index.write_all(self.values[first].0.bytes()).expect("write secondary index");
+            secondary_index.write_u32::<LittleEndian>(start.try_into().expect("too much data")).expect("write secondary index");
+            secondary_index.write_u32::<LittleEndian>(end.try_into().expect("too much data")).expect("write secondary index");
+            secondary_index.write_i32::<LittleEndian>(pos).expect("write secondary index");
+        };
+        (centre_pos.try_into().unwrap(), out, secondary_index)
     }
 
     fn serialised_len(&self) -> usize {
@@ -83,50 +135,180 @@ impl<K: ChunkEntryKey> BST<K> {
     }
 }
 
-pub struct BstChunks<K: ChunkEntryKey> {
-    chunks: Vec<BST<K>>,
+// Read the entries of a chunk serialised by BstChunks::serialise in key order.
+pub fn read_bst_chunk<K: ChunkEntryKey>(data: &[u8], mid_pos: u32) -> Vec<(K, Vec<u8>)> {
+    fn read_node<K: ChunkEntryKey>(data: &[u8], pos: usize, out: &mut Vec<(K, Vec<u8>)>) {
+        // Keep in sync with BST::_serialise_node.
+        let (key, key_len) = K::read(&data[pos..]);
+        let mut next = pos + key_len;
+        let left_pos = LittleEndian::read_i32(&data[next..]);
+        let right_pos = LittleEndian::read_i32(&data[next + 4..]);
+        let value_len = LittleEndian::read_u32(&data[next + 8..]) as usize;
+        next += 12;
+        if left_pos != -1 {
+            read_node(data, left_pos as usize, out);
+        };
+        out.push((key, data[next..next + value_len].to_vec()));
+        if right_pos != -1 {
+            read_node(data, right_pos as usize, out);
+        };
+    }
+
+    let mut entries = Vec::new();
+    read_node(data, mid_pos as usize, &mut entries);
+    entries
+}
+
+// Splits entries inserted in key order into chunks of at most `max_chunk_size` bytes, and passes each chunk to `sink` as soon as it's full,
+// so that only the chunk being filled and the chunk being serialised are held in memory. Large chunks are serialised in parallel.
+pub struct BstChunks<K: ChunkEntryKey, S: ChunkSink> {
+    // Chunk being filled, not yet passed to the sink.
+    chunk: Option<BST<K>>,
+    // Amount of chunks already passed to the sink.
+    flushed: usize,
+    // Last key of the chunks already passed to the sink.
+    flushed_last_key: Option<ChunkKey>,
     max_chunk_size: usize,
+    // Sorted first keys of chunks from a previous build. A new chunk is always started when a key reaches the next boundary,
+    // so that changes only affect the chunks around them and all other chunks remain byte-identical.
+    boundaries: Vec<ChunkKey>,
+    next_boundary: usize,
+    naming: ChunkNaming,
+    sink: S,
 }
 
-impl<K: ChunkEntryKey> BstChunks<K> {
-    pub fn new(max_chunk_size: usize) -> BstChunks<K> {
+impl<K: ChunkEntryKey, S: ChunkSink> BstChunks<K, S> {
+    pub fn new(max_chunk_size: usize, boundaries: Vec<ChunkKey>, naming: ChunkNaming, sink: S) -> BstChunks<K, S> {
         BstChunks {
-            chunks: Vec::new(),
+            chunk: None,
+            flushed: 0,
+            flushed_last_key: None,
             max_chunk_size,
+            boundaries,
+            next_boundary: 0,
+            naming,
+            sink,
         }
     }
 
-    pub fn insert(&mut self, key: K, value: Vec<u8>) -> () {
-        if self.chunks.last().filter(|p| p.serialised_len() + BST::<K>::insertion_cost(&key, &value) <= self.max_chunk_size).is_none() {
-            self.chunks.push(BST::new());
+    pub fn insert(&mut self, key: K, value: Vec<u8>) {
+        // Lookups binary search each chunk and the first keys of chunks, so an out of order or duplicate key would make entries unreachable.
+        let last_key = self.chunk.as_ref().and_then(|c| c.values.last()).map(|(k, _)| k.key()).or(self.flushed_last_key.as_ref());
+        assert!(last_key.filter(|last| key.key() <= *last).is_none(), "key {:?} was not inserted in strictly increasing order", key.key());
+        let mut reached_boundary = false;
+        while self.boundaries.get(self.next_boundary).filter(|b| key.key() >= *b).is_some() {
+            self.next_boundary += 1;
+            reached_boundary = true;
+        };
+        if reached_boundary || self.chunk.as_ref().filter(|p| p.serialised_len() + BST::<K>::insertion_cost(&key, &value) <= self.max_chunk_size).is_none() {
+            // The chunk can no longer receive entries.
+            self.flush();
+            self.chunk = Some(BST::new());
+        };
+
+        self.chunk.as_mut().unwrap().insert(key, value);
+    }
+
+    // Serialise the chunk being filled, if any, and pass it to the sink.
+    fn flush(&mut self) {
+        let package = match self.chunk.take() {
+            Some(package) => package,
+            None => return,
         };
+        let package_id = self.flushed;
+        self.flushed_last_key = package.values.last().map(|(key, _)| key.key().clone());
+        let (mid_pos, serialised, secondary_index) = package.serialise();
+        let hash = SerialisedChunk::hash(&serialised);
+        self.flushed += 1;
+        self.sink.write(SerialisedChunk {
+            id: package_id,
+            name: match self.naming {
+                ChunkNaming::Index => format!("{}", package_id),
+                ChunkNaming::Hash => hash.clone(),
+            },
+            hash,
+            first_key: package.first_key().unwrap().key().clone(),
+            mid_pos,
+            data: serialised,
+            secondary_index,
+        });
+    }
 
-        self.chunks.last_mut().unwrap().insert(key, value);
+    // Pass the remaining chunk to the sink, and return it.
+    pub fn finish(mut self) -> S {
+        self.flush();
+        self.sink
     }
+}
 
-    pub fn chunk_count(&self) -> usize {
-        self.chunks.len()
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use crate::build::ChunkNaming;
+    use crate::build::chunks::{ChunkEntryKey, ChunkSink, ChunkU32Key, SerialisedChunk};
+    use crate::build::chunks::bst::{BstChunks, SECONDARY_INDEX_RANGE_SIZE, read_bst_chunk};
+
+    impl ChunkSink for Vec<SerialisedChunk> {
+        fn write(&mut self, chunk: SerialisedChunk) {
+            self.push(chunk);
+        }
     }
 
-    pub fn serialise(&self) -> (String, Vec<Vec<u8>>) {
-        let mut lookup = String::new();
-        let mut serialised_chunks = Vec::new();
+    fn chunks(max_chunk_size: usize, keys: &[u32]) -> Vec<SerialisedChunk> {
+        let mut builder = BstChunks::<ChunkU32Key, Vec<SerialisedChunk>>::new(max_chunk_size, Vec::new(), ChunkNaming::Index, Vec::new());
+        for key in keys {
+            builder.insert(ChunkU32Key::new(*key), format!("value {}", key).into_bytes());
+        };
+        builder.finish()
+    }
+
+    #[test]
+    fn entries_are_read_back_in_order() {
+        let keys = (0..100).map(|i| i * 3).collect::<Vec<u32>>();
+        let chunks = chunks(200, &keys);
+        assert!(chunks.len() > 1);
+        let entries = chunks.iter().flat_map(|c| read_bst_chunk::<ChunkU32Key>(&c.data, c.mid_pos)).collect::<Vec<_>>();
+        assert_eq!(entries.len(), keys.len());
+        for ((key, value), expected) in entries.iter().zip(keys.iter()) {
+            assert_eq!(key.key(), ChunkU32Key::new(*expected).key());
+            assert_eq!(value, &format!("value {}", expected).into_bytes());
+        };
+    }
 
-        for (package_id, package) in self.chunks.iter().enumerate() {
-            let (mid_pos, serialised) = package.serialise();
-            let lookup_entry = format!(r#"{{
-                .id = {package_id},
-                .mid_pos = {middle},
-                .first_key = {key},
-            }},"#,
-                key = package.first_key().unwrap().c(),
-                package_id = package_id,
-                middle = mid_pos,
-            );
-            lookup.push_str(lookup_entry.as_str());
-            serialised_chunks.push(serialised);
+    #[test]
+    fn large_chunks_are_serialised_with_contiguous_secondary_index_ranges() {
+        // Large enough that both sides of the root are split into ranges and serialised in parallel.
+        let keys = (0..20000).collect::<Vec<u32>>();
+        let chunks = chunks(SECONDARY_INDEX_RANGE_SIZE * 16, &keys);
+        assert_eq!(chunks.len(), 1);
+        let chunk = &chunks[0];
+        let entries = read_bst_chunk::<ChunkU32Key>(&chunk.data, chunk.mid_pos);
+        assert_eq!(entries.iter().map(|(key, _)| key.key().clone()).collect::<Vec<_>>(), keys.iter().map(|k| ChunkU32Key::new(*k).key().clone()).collect::<Vec<_>>());
+        // Each range is a u32 key followed by its start, end, and root position.
+        let ranges = chunk.secondary_index.chunks(16).map(|r| (LittleEndian::read_u32(&r[4..]) as usize, LittleEndian::read_u32(&r[8..]) as usize)).collect::<Vec<_>>();
+        assert!(ranges.len() > 1);
+        let mut sorted = ranges.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted.first().unwrap().0, 0);
+        assert_eq!(sorted.last().unwrap().1, chunk.data.len());
+        for (range, next) in sorted.iter().zip(sorted.iter().skip(1)) {
+            assert_eq!(range.1, next.0);
         };
+    }
+
+    #[test]
+    #[should_panic(expected = "strictly increasing order")]
+    fn duplicate_keys_are_rejected() {
+        chunks(1000, &[1, 2, 3, 3]);
+    }
 
-        (lookup, serialised_chunks)
+    #[test]
+    #[should_panic(expected = "strictly increasing order")]
+    fn decreasing_keys_across_flushed_chunks_are_rejected() {
+        // Each entry fills a chunk, so the earlier keys have already been passed to the sink.
+        let mut keys = (0..64).collect::<Vec<u32>>();
+        keys.push(3);
+        chunks(1, &keys);
     }
 }
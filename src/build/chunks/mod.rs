@@ -1,18 +1,79 @@
 use std::convert::TryInto;
 use std::io::Write;
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::build::wasm::generate_bytes_literal;
 
 pub mod bst;
 
-pub trait ChunkEntryKey {
+// Plain value of a chunk entry key, used to record and compare chunk boundaries across builds.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ChunkKey {
+    U32(u32),
+    Str(String),
+}
+
+impl ChunkKey {
+    // Keep in sync with `str_or_uint32_t` in wasm/chunks.c.
+    pub fn c(&self) -> String {
+        match self {
+            ChunkKey::U32(key) => format!(r#"{{
+                .intval = {},
+            }}"#, key),
+            ChunkKey::Str(key) => format!(r#"{{
+                .strval = {{
+                    .val = {VAL},
+                    .len = {LEN},
+                }},
+            }}"#,
+                // Keys can contain any character, including quotes, backslashes, and bytes that aren't valid in C source.
+                VAL = generate_bytes_literal(key.as_bytes()),
+                LEN = key.len(),
+            ),
+        }
+    }
+}
+
+pub struct SerialisedChunk {
+    // Position of the chunk amongst all chunks of its kind.
+    pub id: usize,
+    // Key suffix used to store and fetch the chunk from the data store, e.g. `terms/{name}`.
+    pub name: String,
+    // Lowercase hex SHA-256 of `data`.
+    pub hash: String,
+    pub first_key: ChunkKey,
+    // Position of the root node of the BST.
+    pub mid_pos: u32,
+    pub data: Vec<u8>,
+    // Byte ranges of the BST and their first keys, so that only part of the chunk needs to be fetched (see bst::BST::serialise).
+    pub secondary_index: Vec<u8>,
+}
+
+impl SerialisedChunk {
+    pub fn hash(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+}
+
+// Receives chunks from BstChunks as soon as they are full, in order.
+pub trait ChunkSink {
+    fn write(&mut self, chunk: SerialisedChunk);
+}
+
+pub trait ChunkEntryKey: Sized + Send + Sync {
     fn bytes(&self) -> &[u8];
-    fn c(&self) -> &str;
+    fn key(&self) -> &ChunkKey;
+    // Read a key serialised at the start of `data`, returning it and how many bytes it took up.
+    fn read(data: &[u8]) -> (Self, usize);
 }
 
 pub struct ChunkU32Key {
     bytes: Vec<u8>,
-    c: String,
+    key: ChunkKey,
 }
 
 impl ChunkU32Key {
@@ -21,9 +82,7 @@ impl ChunkU32Key {
         bytes.write_u32::<LittleEndian>(key).unwrap();
         ChunkU32Key {
             bytes,
-            c: format!(r#"{{
-                .intval = {},
-            }}"#, key),
+            key: ChunkKey::U32(key),
         }
     }
 }
@@ -33,15 +92,19 @@ impl ChunkEntryKey for ChunkU32Key {
         &self.bytes
     }
 
-    fn c(&self) -> &str {
-        &self.c
+    fn key(&self) -> &ChunkKey {
+        &self.key
+    }
+
+    fn read(data: &[u8]) -> (Self, usize) {
+        (ChunkU32Key::new(LittleEndian::read_u32(data)), 4)
     }
 }
 
 
 pub struct ChunkStrKey {
     bytes: Vec<u8>,
-    c: String,
+    key: ChunkKey,
 }
 
 impl ChunkStrKey {
@@ -51,15 +114,7 @@ impl ChunkStrKey {
         bytes.write_all(key.as_bytes()).unwrap();
         ChunkStrKey {
             bytes,
-            c: format!(r#"{{
-                .strval = {{
-                    .val = "{VAL}",
-                    .len = {LEN},
-                }},
-            }}"#,
-                VAL = key.replace("\n", "\\n").replace("\"", "\\\""),
-                LEN = key.len(),
-            ),
+            key: ChunkKey::Str(key.to_string()),
         }
     }
 }
@@ -69,7 +124,39 @@ impl ChunkEntryKey for ChunkStrKey {
         &self.bytes
     }
 
-    fn c(&self) -> &str {
-        &self.c
+    fn key(&self) -> &ChunkKey {
+        &self.key
+    }
+
+    fn read(data: &[u8]) -> (Self, usize) {
+        let len = data[0] as usize;
+        let key = std::str::from_utf8(&data[1..1 + len]).expect("read key as UTF-8");
+        (ChunkStrKey::new(key), 1 + len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build::chunks::ChunkKey;
+
+    // Read the bytes of the adjacent string literals in C source, which only contain `\xNN` escapes.
+    fn read_c_literals(code: &str) -> Vec<u8> {
+        let start = code.find('"').unwrap();
+        let end = code.rfind('"').unwrap();
+        code[start..=end].split('"').skip(1).step_by(2).flat_map(|literal| {
+            literal.split("\\x").skip(1).map(|hex| {
+                assert_eq!(hex.len(), 2, "escape is followed by {:?}", hex);
+                u8::from_str_radix(hex, 16).unwrap()
+            }).collect::<Vec<_>>()
+        }).collect()
+    }
+
+    #[test]
+    fn string_keys_are_escaped_in_c() {
+        for key in ["plain", "quote\"d", "back\\slash", "new\nline", "é0f", "\u{1}ab", "??=", &"long key 0123456789 ".repeat(10)] {
+            let code = ChunkKey::Str(key.to_string()).c();
+            assert_eq!(read_c_literals(&code), key.as_bytes(), "{:?}", code);
+            assert!(code.contains(&format!(".len = {},", key.len())));
+        };
     }
 }
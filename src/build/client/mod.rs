@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+
+use crate::build::Target;
+use crate::build::output::OutputSink;
+use crate::build::schema::{DocumentSchema, JsonType};
+
+const CLIENT_TS_TEMPLATE: &str = include_str!("template.ts");
+const CLIENT_RS_TEMPLATE: &str = include_str!("template.rs");
+
+// Keep in sync with mode_t in wasm/index.c.
+const MODES: &[(&str, u8)] = &[("REQUIRE", 0), ("CONTAIN", 1), ("EXCLUDE", 2)];
+
+// Keep in sync with parseSearchRequest in script/src/search.ts.
+const TERM_PARAM: &str = "t";
+const CONTINUATION_PARAM: &str = "c";
+const FIELDS_PARAM: &str = "fields";
+// Keep in sync with handleSearch and handleDocuments in script/src/main.ts.
+const IDS_PARAM: &str = "ids";
+const ID_PARAM: &str = "id";
+const KEY_PARAM: &str = "key";
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "box", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false", "fn", "for", "if", "impl", "in",
+    "let", "loop", "match", "mod", "move", "mut", "priv", "pub", "ref", "return", "self", "static", "struct", "super", "trait", "true", "try", "type",
+    "unsafe", "use", "where", "while", "yield",
+];
+
+// An index to generate search and document methods for.
+pub struct ClientIndex<'m> {
+    // Served at `/{name}/search` and `/{name}/docs`, or `/search` and `/docs` if None.
+    pub name: Option<&'m str>,
+    pub schema: &'m DocumentSchema,
+    // Schema of summaries, which search results return instead of documents, if the index has summaries.
+    pub summary_schema: Option<&'m DocumentSchema>,
+    // Whether documents can be looked up by external key.
+    pub has_keys: bool,
+}
+
+// Split a name into its words, e.g. `jobPosts` and `job-posts` into `job` and `posts`.
+fn words(name: &str) -> Vec<String> {
+    let mut words = Vec::<String>::new();
+    let mut prev = None::<char>;
+    for c in name.chars() {
+        if !c.is_ascii_alphanumeric() {
+            prev = None;
+            continue;
+        };
+        let starts_word = match prev {
+            None => true,
+            Some(prev) => c.is_ascii_uppercase() && !prev.is_ascii_uppercase(),
+        };
+        if starts_word {
+            words.push(String::new());
+        };
+        words.last_mut().unwrap().push(c.to_ascii_lowercase());
+        prev = Some(c);
+    };
+    words
+}
+
+fn pascal_case(name: &str) -> String {
+    words(name).iter().map(|word| format!("{}{}", word[..1].to_ascii_uppercase(), &word[1..])).collect()
+}
+
+fn snake_case(name: &str) -> String {
+    words(name).join("_")
+}
+
+// `kind` is `Document` or `Summary`.
+fn index_type_name(index: &ClientIndex, kind: &str) -> String {
+    let name = index.name.map_or_else(String::new, pascal_case);
+    // Index names can start with a digit, which identifiers can't.
+    format!("{}{}{}", if name.starts_with(|c: char| c.is_ascii_digit()) { "Index" } else { "" }, name, kind)
+}
+
+fn document_type_name(index: &ClientIndex) -> String {
+    index_type_name(index, "Document")
+}
+
+// Search results are summaries if the index has them, otherwise documents.
+fn result_type_name(index: &ClientIndex) -> String {
+    if index.summary_schema.is_some() { index_type_name(index, "Summary") } else { document_type_name(index) }
+}
+
+fn ts_property_name(name: &str) -> String {
+    let mut chars = name.chars();
+    let is_identifier = chars.next().filter(|c| c.is_ascii_alphabetic() || *c == '_' || *c == '$').is_some()
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if is_identifier { name.to_string() } else { serde_json::to_string(name).expect("serialise property name") }
+}
+
+fn ts_type(schema: &DocumentSchema, indent: &str) -> String {
+    let types = schema.types.iter().map(|json_type| match json_type {
+        JsonType::Null => "null".to_string(),
+        JsonType::Boolean => "boolean".to_string(),
+        JsonType::Integer | JsonType::Number => "number".to_string(),
+        JsonType::String => "string".to_string(),
+        JsonType::Array => format!("Array<{}>", schema.items.as_ref().map_or("unknown".to_string(), |items| ts_type(items, indent))),
+        JsonType::Object => match &schema.properties {
+            Some(properties) if !properties.is_empty() => {
+                let property_indent = format!("{}  ", indent);
+                let mut code = "{\n".to_string();
+                for (name, property) in properties {
+                    let optional = !schema.is_required(name);
+                    code.push_str(&format!("{}{}{}: {};\n", property_indent, ts_property_name(name), if optional { "?" } else { "" }, ts_type(property, &property_indent)));
+                };
+                code.push_str(&format!("{}}}", indent));
+                code
+            }
+            _ => "Record<string, unknown>".to_string(),
+        },
+    }).collect::<Vec<_>>();
+    if types.is_empty() { "unknown".to_string() } else { types.join(" | ") }
+}
+
+fn generate_client_ts(maximum_query_results: usize, maximum_query_terms: usize, indexes: &[ClientIndex]) -> String {
+    let mut code = format!("// Generated by Edgesearch {} for an index; regenerate it instead of editing it.\n\n", env!("CARGO_PKG_VERSION"));
+    code.push_str(&format!("export const MAX_QUERY_TERMS = {};\n", maximum_query_terms));
+    code.push_str(&format!("export const MAX_RESULTS = {};\n\n", maximum_query_results));
+    code.push_str(&format!("const TERM_PARAM = \"{}\";\n", TERM_PARAM));
+    code.push_str(&format!("const CONTINUATION_PARAM = \"{}\";\n", CONTINUATION_PARAM));
+    code.push_str(&format!("const FIELDS_PARAM = \"{}\";\n", FIELDS_PARAM));
+    code.push_str(&format!("const IDS_PARAM = \"{}\";\n", IDS_PARAM));
+    code.push_str(&format!("const ID_PARAM = \"{}\";\n", ID_PARAM));
+    code.push_str(&format!("const KEY_PARAM = \"{}\";\n\n", KEY_PARAM));
+    code.push_str("export enum Mode {\n");
+    for (name, id) in MODES {
+        code.push_str(&format!("  {} = \"{}\",\n", name, id));
+    };
+    code.push_str("}\n\n");
+    code.push_str(&format!("const MODES = [{}];\n\n", MODES.iter().map(|(name, _)| format!("Mode.{}", name)).collect::<Vec<_>>().join(", ")));
+    for index in indexes {
+        code.push_str(&format!("export type {} = {};\n\n", document_type_name(index), ts_type(index.schema, "")));
+        if let Some(summary_schema) = index.summary_schema {
+            code.push_str(&format!("export type {} = {};\n\n", result_type_name(index), ts_type(summary_schema, "")));
+        };
+    };
+    code.push_str(CLIENT_TS_TEMPLATE);
+    code.push_str("\nexport class Client extends BaseClient {\n");
+    for (i, index) in indexes.iter().enumerate() {
+        if i > 0 {
+            code.push('\n');
+        };
+        let (suffix, path) = match index.name {
+            Some(name) => (pascal_case(name), format!("/{}", name)),
+            None => (String::new(), String::new()),
+        };
+        let document_type = document_type_name(index);
+        code.push_str(&format!("  search{}(query: Query): Promise<SearchResponse<{}>> {{\n", suffix, result_type_name(index)));
+        code.push_str(&format!("    return this.searchIndex(\"{}/search\", query);\n", path));
+        code.push_str("  }\n\n");
+        // Fields are taken from whole documents, even if search results are otherwise summaries.
+        code.push_str("  // Only return `fields` of each document; fields missing from a document are omitted.\n");
+        code.push_str(&format!("  searchFields{}<K extends keyof {} & string>(query: Query, fields: ReadonlyArray<K>): Promise<SearchResponse<Pick<{}, K>>> {{\n", suffix, document_type, document_type));
+        code.push_str(&format!("    return this.searchIndex(\"{}/search\", query, fields);\n", path));
+        code.push_str("  }\n\n");
+        code.push_str(&format!("  getDocuments{}(ids: ReadonlyArray<DocumentId>): Promise<DocumentsResponse<{}>> {{\n", suffix, document_type));
+        code.push_str(&format!("    return this.getIndexDocuments(\"{}/docs\", ids);\n", path));
+        code.push_str("  }\n");
+        if index.has_keys {
+            code.push_str(&format!("\n  getDocumentsByKeys{}(keys: ReadonlyArray<string | number>): Promise<DocumentsByKeysResponse<{}>> {{\n", suffix, document_type));
+            code.push_str(&format!("    return this.getIndexDocumentsByKeys(\"{}/docs\", keys);\n", path));
+            code.push_str("  }\n");
+        };
+    };
+    code.push_str("}\n");
+    code
+}
+
+// Generates named structs for objects, as Rust has no anonymous struct types.
+struct RustTypes {
+    code: String,
+    names: HashSet<String>,
+}
+
+impl RustTypes {
+    fn unique_name(names: &mut HashSet<String>, name: String) -> String {
+        let mut unique = name.clone();
+        let mut suffix = 2;
+        while names.contains(&unique) {
+            unique = format!("{}{}", name, suffix);
+            suffix += 1;
+        };
+        names.insert(unique.clone());
+        unique
+    }
+
+    // Values of more than one type that isn't null are represented as any JSON value.
+    fn rust_type(&mut self, schema: &DocumentSchema, name: &str) -> String {
+        let non_null = schema.types.iter().filter(|json_type| **json_type != JsonType::Null).collect::<Vec<_>>();
+        let rust_type = match non_null.as_slice() {
+            [JsonType::Boolean] => "bool".to_string(),
+            [JsonType::Integer] => "i64".to_string(),
+            [JsonType::Number] => "f64".to_string(),
+            [JsonType::String] => "String".to_string(),
+            [JsonType::Array] => format!("Vec<{}>", schema.items.as_ref().map_or("serde_json::Value".to_string(), |items| self.rust_type(items, &format!("{}Item", name)))),
+            [JsonType::Object] => {
+                let name = RustTypes::unique_name(&mut self.names, name.to_string());
+                self.rust_struct(schema, &name)
+            }
+            _ => return "serde_json::Value".to_string(),
+        };
+        if schema.types.contains(&JsonType::Null) { format!("Option<{}>", rust_type) } else { rust_type }
+    }
+
+    // `name` must already be unique.
+    fn rust_struct(&mut self, schema: &DocumentSchema, name: &str) -> String {
+        let mut fields = String::new();
+        let mut field_names = HashSet::new();
+        for (property_name, property) in schema.properties.iter().flatten() {
+            let mut field_name = snake_case(property_name);
+            if field_name.is_empty() || field_name.starts_with(|c: char| c.is_ascii_digit()) {
+                field_name = format!("field_{}", field_name);
+            } else if RUST_KEYWORDS.contains(&field_name.as_str()) {
+                field_name.push('_');
+            };
+            let field_name = RustTypes::unique_name(&mut field_names, field_name);
+            let mut field_type = self.rust_type(property, &format!("{}{}", name, pascal_case(property_name)));
+            // Missing properties are deserialised as None.
+            let optional = !schema.is_required(property_name);
+            if optional && !field_type.starts_with("Option<") {
+                field_type = format!("Option<{}>", field_type);
+            };
+            if field_name != *property_name {
+                fields.push_str(&format!("    #[serde(rename = {:?})]\n", property_name));
+            };
+            fields.push_str(&format!("    pub {}: {},\n", field_name, field_type));
+        };
+        self.code.push_str(&format!("#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]\npub struct {} {{\n{}}}\n\n", name, fields));
+        name.to_string()
+    }
+}
+
+fn generate_client_rs(maximum_query_results: usize, maximum_query_terms: usize, indexes: &[ClientIndex]) -> String {
+    let mut code = format!("// Generated by Edgesearch {} for an index; regenerate it instead of editing it.\n", env!("CARGO_PKG_VERSION"));
+    code.push_str("// Requires the serde crate with the derive feature, and the serde_json crate.\n\n");
+    code.push_str("use std::collections::{BTreeMap, BTreeSet};\n\n");
+    code.push_str("use serde::{Deserialize, Serialize};\n");
+    code.push_str("use serde::de::DeserializeOwned;\n\n");
+    code.push_str(&format!("pub const MAX_QUERY_TERMS: usize = {};\n", maximum_query_terms));
+    code.push_str(&format!("pub const MAX_RESULTS: usize = {};\n\n", maximum_query_results));
+    code.push_str(&format!("const TERM_PARAM: &str = \"{}\";\n", TERM_PARAM));
+    code.push_str(&format!("const CONTINUATION_PARAM: &str = \"{}\";\n", CONTINUATION_PARAM));
+    code.push_str(&format!("const FIELDS_PARAM: &str = \"{}\";\n", FIELDS_PARAM));
+    code.push_str(&format!("const IDS_PARAM: &str = \"{}\";\n", IDS_PARAM));
+    code.push_str(&format!("const ID_PARAM: &str = \"{}\";\n", ID_PARAM));
+    code.push_str(&format!("const KEY_PARAM: &str = \"{}\";\n\n", KEY_PARAM));
+    code.push_str("#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]\npub enum Mode {\n");
+    for (name, _) in MODES {
+        code.push_str(&format!("    {},\n", pascal_case(name)));
+    };
+    code.push_str("}\n\nimpl Mode {\n    pub fn id(&self) -> u8 {\n        match self {\n");
+    for (name, id) in MODES {
+        code.push_str(&format!("            Mode::{} => {},\n", pascal_case(name), id));
+    };
+    code.push_str("        }\n    }\n}\n\n");
+
+    // Names of document and summary types are reserved first, so that no other struct takes them.
+    let mut types = RustTypes { code: String::new(), names: indexes.iter().map(document_type_name).chain(indexes.iter().map(result_type_name)).collect() };
+    for index in indexes {
+        let mut schemas = vec![(document_type_name(index), index.schema)];
+        if let Some(summary_schema) = index.summary_schema {
+            schemas.push((result_type_name(index), summary_schema));
+        };
+        for (name, schema) in schemas {
+            if schema.types.iter().eq([JsonType::Object].iter()) {
+                types.rust_struct(schema, &name);
+            } else {
+                // Documents and summaries that aren't always objects are given an alias of their type.
+                let document_type = types.rust_type(schema, &format!("{}Object", name));
+                types.code.push_str(&format!("pub type {} = {};\n\n", name, document_type));
+            };
+        };
+    };
+    code.push_str(&types.code);
+    code.push_str(CLIENT_RS_TEMPLATE);
+    code.push_str("\nimpl<F, E> Client<F> where F: Fn(&str) -> Result<Vec<u8>, E> {\n");
+    for (i, index) in indexes.iter().enumerate() {
+        if i > 0 {
+            code.push('\n');
+        };
+        let (suffix, path) = match index.name {
+            Some(name) => (format!("_{}", snake_case(name)), format!("/{}", name)),
+            None => (String::new(), String::new()),
+        };
+        let document_type = document_type_name(index);
+        code.push_str(&format!("    pub fn search{}(&self, query: &Query) -> Result<SearchResponse<{}>, SearchError<E>> {{\n", suffix, result_type_name(index)));
+        code.push_str(&format!("        self.search_index(\"{}/search\", query, None)\n", path));
+        code.push_str("    }\n\n");
+        // Rust has no equivalent of Pick, so callers provide a type with only the requested fields.
+        code.push_str(&format!("    // Only return `fields` of each document, deserialised as `D`, such as a struct with some fields of `{}` or `serde_json::Map`.\n", document_type));
+        code.push_str("    // Fields missing from a document are omitted.\n");
+        code.push_str(&format!("    pub fn search_fields{}<D: DeserializeOwned>(&self, query: &Query, fields: &[&str]) -> Result<SearchResponse<D>, SearchError<E>> {{\n", suffix));
+        code.push_str(&format!("        self.search_index(\"{}/search\", query, Some(fields))\n", path));
+        code.push_str("    }\n\n");
+        code.push_str(&format!("    pub fn get_documents{}(&self, ids: &[DocumentId]) -> Result<DocumentsResponse<{}>, SearchError<E>> {{\n", suffix, document_type));
+        code.push_str(&format!("        self.get_index_documents(\"{}/docs\", ids)\n", path));
+        code.push_str("    }\n");
+        if index.has_keys {
+            code.push_str(&format!("\n    pub fn get_documents_by_keys{}(&self, keys: &[&str]) -> Result<DocumentsByKeysResponse<{}>, SearchError<E>> {{\n", suffix, document_type));
+            code.push_str(&format!("        self.get_index_documents_by_keys(\"{}/docs\", keys)\n", path));
+            code.push_str("    }\n");
+        };
+    };
+    code.push_str("}\n");
+    code
+}
+
+// Write typed TypeScript and Rust clients for `indexes` to `client/` of the output, and the schemas of each index's documents and summaries.
+pub fn generate_clients(
+    output: &dyn OutputSink,
+    target: Target,
+    maximum_query_results: usize,
+    maximum_query_terms: usize,
+    indexes: &[ClientIndex],
+) {
+    assert!(!matches!(target, Target::Browser), "clients cannot be generated for the browser target, as it has no API");
+    output.clear("client");
+    output.write("client/client.ts", generate_client_ts(maximum_query_results, maximum_query_terms, indexes).as_bytes());
+    output.write("client/client.rs", generate_client_rs(maximum_query_results, maximum_query_terms, indexes).as_bytes());
+    for index in indexes {
+        output.write(
+            &format!("client/{}.schema.json", document_type_name(index)),
+            &serde_json::to_vec_pretty(index.schema).expect("serialise document schema"),
+        );
+        if let Some(summary_schema) = index.summary_schema {
+            output.write(
+                &format!("client/{}.schema.json", result_type_name(index)),
+                &serde_json::to_vec_pretty(summary_schema).expect("serialise summary schema"),
+            );
+        };
+    };
+}
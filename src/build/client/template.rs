@@ -0,0 +1,159 @@
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum Continuation {
+    Offset(u64),
+    // Continuations of sharded indexes are strings.
+    Shards(String),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum DocumentId {
+    Id(u64),
+    // IDs of sharded indexes are strings.
+    Shard(String),
+}
+
+impl DocumentId {
+    fn to_param(&self) -> String {
+        match self {
+            DocumentId::Id(id) => id.to_string(),
+            DocumentId::Shard(id) => encode_uri_component(id),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SearchResponse<D> {
+    pub results: Vec<D>,
+    pub continuation: Option<Continuation>,
+    pub total: u64,
+    // IDs of `results`, if requested with `Query::set_include_ids`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ids: Option<Vec<DocumentId>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct DocumentsResponse<D> {
+    // Documents that don't exist or have been deleted are None.
+    pub results: Vec<Option<D>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct DocumentsByKeysResponse<D> {
+    // Documents that don't exist or have been deleted are None.
+    pub results: Vec<Option<D>>,
+    // IDs of `results`, or None for documents that don't exist.
+    pub ids: Vec<Option<DocumentId>>,
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+    // The query would have more than MAX_QUERY_TERMS terms, so the index would reject it.
+    TooManyTerms,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    mode_terms: BTreeMap<Mode, BTreeSet<String>>,
+    continuation: Option<Continuation>,
+    include_ids: bool,
+}
+
+// Same as JavaScript's encodeURIComponent.
+fn encode_uri_component(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'!' | b'~' | b'*' | b'\'' | b'(' | b')' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        };
+    };
+    encoded
+}
+
+impl Query {
+    pub fn new() -> Query {
+        Query::default()
+    }
+
+    pub fn add(&mut self, mode: Mode, term: &str) -> Result<&mut Query, QueryError> {
+        let term_count = self.mode_terms.values().map(|terms| terms.len()).sum::<usize>();
+        let terms = self.mode_terms.entry(mode).or_default();
+        if !terms.contains(term) {
+            if term_count >= MAX_QUERY_TERMS {
+                return Err(QueryError::TooManyTerms);
+            };
+            terms.insert(term.to_string());
+        };
+        Ok(self)
+    }
+
+    pub fn set_continuation(&mut self, continuation: Continuation) -> &mut Query {
+        self.continuation = Some(continuation);
+        self
+    }
+
+    pub fn set_include_ids(&mut self, include_ids: bool) -> &mut Query {
+        self.include_ids = include_ids;
+        self
+    }
+
+    pub fn build(&self) -> String {
+        let continuation = match &self.continuation {
+            Some(Continuation::Offset(offset)) => offset.to_string(),
+            Some(Continuation::Shards(shards)) => shards.clone(),
+            None => "0".to_string(),
+        };
+        let mut params = vec![format!("{}={}", CONTINUATION_PARAM, encode_uri_component(&continuation))];
+        for (mode, terms) in self.mode_terms.iter() {
+            for term in terms {
+                params.push(format!("{}={}_{}", TERM_PARAM, mode.id(), encode_uri_component(term)));
+            };
+        };
+        if self.include_ids {
+            params.push(format!("{}=1", IDS_PARAM));
+        };
+        params.join("&")
+    }
+}
+
+#[derive(Debug)]
+pub enum SearchError<E> {
+    Fetch(E),
+    Parse(serde_json::Error),
+}
+
+// `get` fetches a URL and returns the response body, so that any HTTP client can be used.
+pub struct Client<F> {
+    prefix: String,
+    get: F,
+}
+
+impl<F, E> Client<F> where F: Fn(&str) -> Result<Vec<u8>, E> {
+    pub fn new(prefix: &str, get: F) -> Client<F> {
+        Client { prefix: prefix.to_string(), get }
+    }
+
+    // Results only have `fields` of each document if provided.
+    fn search_index<D: DeserializeOwned>(&self, path: &str, query: &Query, fields: Option<&[&str]>) -> Result<SearchResponse<D>, SearchError<E>> {
+        let mut params = query.build();
+        if let Some(fields) = fields {
+            params.push_str(&format!("&{}={}", FIELDS_PARAM, fields.iter().map(|field| encode_uri_component(field)).collect::<Vec<_>>().join(",")));
+        };
+        let body = (self.get)(&format!("{}{}?{}", self.prefix, path, params)).map_err(SearchError::Fetch)?;
+        serde_json::from_slice(&body).map_err(SearchError::Parse)
+    }
+
+    fn get_index_documents<D: DeserializeOwned>(&self, path: &str, ids: &[DocumentId]) -> Result<DocumentsResponse<D>, SearchError<E>> {
+        let params = ids.iter().map(|id| format!("{}={}", ID_PARAM, id.to_param())).collect::<Vec<_>>().join("&");
+        let body = (self.get)(&format!("{}{}?{}", self.prefix, path, params)).map_err(SearchError::Fetch)?;
+        serde_json::from_slice(&body).map_err(SearchError::Parse)
+    }
+
+    fn get_index_documents_by_keys<D: DeserializeOwned>(&self, path: &str, keys: &[&str]) -> Result<DocumentsByKeysResponse<D>, SearchError<E>> {
+        let params = keys.iter().map(|key| format!("{}={}", KEY_PARAM, encode_uri_component(key))).collect::<Vec<_>>().join("&");
+        let body = (self.get)(&format!("{}{}?{}", self.prefix, path, params)).map_err(SearchError::Fetch)?;
+        serde_json::from_slice(&body).map_err(SearchError::Parse)
+    }
+}
@@ -0,0 +1,128 @@
+// Continuations of sharded indexes are strings.
+export type Continuation = number | string;
+
+// IDs of sharded indexes are strings.
+export type DocumentId = number | string;
+
+export type SearchResponse<D> = {
+  results: D[];
+  continuation: Continuation | null;
+  total: number;
+  // IDs of `results`, if requested with `Query.setIncludeIds`.
+  ids?: DocumentId[];
+};
+
+export type DocumentsResponse<D> = {
+  // Documents that don't exist or have been deleted are null.
+  results: (D | null)[];
+};
+
+export type DocumentsByKeysResponse<D> = DocumentsResponse<D> & {
+  // IDs of `results`, or null for documents that don't exist.
+  ids: (DocumentId | null)[];
+};
+
+// Fetch a URL and parse the response as JSON.
+export type Agent = (url: string) => Promise<unknown>;
+
+export const fetchGet: Agent = (url) => fetch(url).then((res) => res.json());
+
+export class Query {
+  private readonly modeTerms = new Map<Mode, Set<string>>();
+
+  private termCount = 0;
+
+  private continuation: Continuation = 0;
+
+  private includeIds = false;
+
+  // Throws if the query would have more than MAX_QUERY_TERMS terms, as the index would reject it.
+  add(mode: Mode, ...terms: ReadonlyArray<string>): this {
+    let modeTerms = this.modeTerms.get(mode);
+    if (!modeTerms) {
+      modeTerms = new Set();
+      this.modeTerms.set(mode, modeTerms);
+    }
+    for (const term of terms) {
+      if (modeTerms.has(term)) {
+        continue;
+      }
+      if (this.termCount >= MAX_QUERY_TERMS) {
+        throw new RangeError(
+          `Queries can have at most ${MAX_QUERY_TERMS} terms`
+        );
+      }
+      modeTerms.add(term);
+      this.termCount++;
+    }
+    return this;
+  }
+
+  setContinuation(c: Continuation): this {
+    this.continuation = c;
+    return this;
+  }
+
+  setIncludeIds(includeIds = true): this {
+    this.includeIds = includeIds;
+    return this;
+  }
+
+  build(): string {
+    const params = [
+      `${CONTINUATION_PARAM}=${encodeURIComponent(this.continuation)}`,
+    ];
+    for (const mode of MODES) {
+      const terms = Array.from(this.modeTerms.get(mode) ?? []).sort();
+      for (const term of terms) {
+        params.push(`${TERM_PARAM}=${mode}_${encodeURIComponent(term)}`);
+      }
+    }
+    if (this.includeIds) {
+      params.push(`${IDS_PARAM}=1`);
+    }
+    return params.join("&");
+  }
+}
+
+export class BaseClient {
+  constructor(
+    private readonly prefix: string,
+    private readonly agent: Agent = fetchGet
+  ) {}
+
+  // Results only have `fields` of each document if provided.
+  protected searchIndex<D>(
+    path: string,
+    query: Query,
+    fields?: ReadonlyArray<string>
+  ): Promise<SearchResponse<D>> {
+    const params = [query.build()];
+    if (fields) {
+      params.push(`${FIELDS_PARAM}=${fields.map(encodeURIComponent).join(",")}`);
+    }
+    return this.agent(`${this.prefix}${path}?${params.join("&")}`) as Promise<
+      SearchResponse<D>
+    >;
+  }
+
+  protected getIndexDocuments<D>(
+    path: string,
+    ids: ReadonlyArray<DocumentId>
+  ): Promise<DocumentsResponse<D>> {
+    const params = ids.map((id) => `${ID_PARAM}=${encodeURIComponent(id)}`);
+    return this.agent(`${this.prefix}${path}?${params.join("&")}`) as Promise<
+      DocumentsResponse<D>
+    >;
+  }
+
+  protected getIndexDocumentsByKeys<D>(
+    path: string,
+    keys: ReadonlyArray<string | number>
+  ): Promise<DocumentsByKeysResponse<D>> {
+    const params = keys.map((key) => `${KEY_PARAM}=${encodeURIComponent(key)}`);
+    return this.agent(`${this.prefix}${path}?${params.join("&")}`) as Promise<
+      DocumentsByKeysResponse<D>
+    >;
+  }
+}
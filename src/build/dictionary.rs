@@ -0,0 +1,170 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use miniz_oxide::deflate::core::{compress, CompressorOxide, create_comp_flags_from_zip_params, TDEFLFlush, TDEFLStatus};
+use miniz_oxide::inflate::decompress_to_vec;
+
+// DEFLATE can only refer back this far, so a longer dictionary would be partly unusable.
+pub const MAX_DICTIONARY_SIZE: usize = 32 * 1024;
+// Approximate amount of bytes of samples to train a dictionary on.
+pub const SAMPLES_SIZE: usize = 1024 * 1024;
+// Length of the substrings whose frequency across samples is counted when training.
+const DMER_SIZE: usize = 8;
+// Length of the segments of samples that a dictionary is made of.
+const SEGMENT_SIZE: usize = 256;
+// Distance between the starts of candidate segments within a sample.
+const SEGMENT_STEP: usize = SEGMENT_SIZE / 4;
+const LEVEL: i32 = 9;
+// Negative for raw DEFLATE without a zlib header, as decoded by `DecompressionStream("deflate-raw")`.
+const WINDOW_BITS: i32 = -15;
+
+// Score of a segment: the sum of the amount of samples containing each of its distinct substrings of DMER_SIZE bytes,
+// ignoring substrings that only appear in one sample or are already in the dictionary.
+fn segment_score<'s>(segment: &'s [u8], frequencies: &HashMap<&'s [u8], usize>) -> usize {
+    let mut seen = HashSet::new();
+    segment.windows(DMER_SIZE)
+        .filter(|dmer| seen.insert(*dmer))
+        .map(|dmer| frequencies[dmer])
+        .filter(|frequency| *frequency > 1)
+        .sum()
+}
+
+// Build a dictionary of at most MAX_DICTIONARY_SIZE bytes out of the segments of `samples` with the most substrings common to other samples,
+// greedily picking the best segment and then no longer counting its substrings. The best segments are at the end of the dictionary,
+// as later bytes are closer to the compressed data and so are cheaper to refer to.
+pub fn train_dictionary(samples: &[Vec<u8>]) -> Vec<u8> {
+    let mut frequencies = HashMap::<&[u8], usize>::new();
+    for sample in samples {
+        let mut seen = HashSet::new();
+        for dmer in sample.windows(DMER_SIZE) {
+            if seen.insert(dmer) {
+                *frequencies.entry(dmer).or_default() += 1;
+            };
+        };
+    };
+
+    // Scores only decrease as segments are picked, so a segment whose recomputed score is still the highest is the best one.
+    let mut candidates = samples.iter()
+        .flat_map(|sample| (0..sample.len().saturating_sub(DMER_SIZE - 1)).step_by(SEGMENT_STEP).map(move |start| &sample[start..sample.len().min(start + SEGMENT_SIZE)]))
+        .map(|segment| (segment_score(segment, &frequencies), segment))
+        .collect::<BinaryHeap<_>>();
+    let mut segments = Vec::new();
+    let mut size = 0;
+    while let Some((score, segment)) = candidates.pop() {
+        if score == 0 || size + segment.len() > MAX_DICTIONARY_SIZE {
+            break;
+        };
+        let current_score = segment_score(segment, &frequencies);
+        if candidates.peek().filter(|(next_score, _)| *next_score > current_score).is_some() {
+            candidates.push((current_score, segment));
+            continue;
+        };
+        if current_score == 0 {
+            break;
+        };
+        for dmer in segment.windows(DMER_SIZE) {
+            frequencies.insert(dmer, 0);
+        };
+        segments.push(segment);
+        size += segment.len();
+    };
+    segments.iter().rev().flat_map(|segment| segment.iter().copied()).collect()
+}
+
+// Compress `input` and then flush with `flush`, appending to `output`.
+fn deflate(compressor: &mut CompressorOxide, mut input: &[u8], flush: TDEFLFlush, output: &mut Vec<u8>) {
+    let mut out_pos = output.len();
+    output.resize(out_pos + input.len() / 2 + 64, 0);
+    loop {
+        let (status, bytes_in, bytes_out) = compress(compressor, input, &mut output[out_pos..], flush);
+        out_pos += bytes_out;
+        input = &input[bytes_in..];
+        match status {
+            TDEFLStatus::Done => break,
+            // All output has been flushed if there was space left for more.
+            TDEFLStatus::Okay if input.is_empty() && out_pos < output.len() => break,
+            TDEFLStatus::Okay => output.resize(output.len() * 2, 0),
+            _ => panic!("compress value: {:?}", status),
+        };
+    };
+    output.truncate(out_pos);
+}
+
+// A preset dictionary for raw DEFLATE. DEFLATE streams don't declare a dictionary, so instead, the dictionary is compressed as the start
+// of a stream and flushed to a byte boundary, and each value continues that stream from the compressor's state at that point, referring back
+// to the dictionary. A value is decompressed by decompressing the compressed dictionary followed by the value, and skipping the dictionary.
+pub struct DeflateDictionary {
+    dictionary_size: usize,
+    compressed: Vec<u8>,
+    // State after compressing the dictionary, which every value is compressed from.
+    primed: Box<CompressorOxide>,
+}
+
+impl DeflateDictionary {
+    pub fn new(dictionary: &[u8]) -> DeflateDictionary {
+        let mut compressor = Box::new(CompressorOxide::new(create_comp_flags_from_zip_params(LEVEL, WINDOW_BITS, 0)));
+        let mut compressed = Vec::new();
+        deflate(&mut compressor, dictionary, TDEFLFlush::Sync, &mut compressed);
+        DeflateDictionary { dictionary_size: dictionary.len(), compressed, primed: compressor }
+    }
+
+    pub fn dictionary_size(&self) -> usize {
+        self.dictionary_size
+    }
+
+    // The compressed dictionary, which is stored once per layer for decompression.
+    pub fn compressed(&self) -> &[u8] {
+        &self.compressed
+    }
+
+    // A compressor for `compress`, to be reused for every value compressed by one thread.
+    pub fn compressor(&self) -> Box<CompressorOxide> {
+        self.primed.clone()
+    }
+
+    // Returns the value compressed as the rest of the dictionary's stream, excluding the compressed dictionary.
+    pub fn compress(&self, compressor: &mut CompressorOxide, value: &[u8]) -> Vec<u8> {
+        // Copying the primed state is much faster than compressing the dictionary again.
+        compressor.clone_from(&self.primed);
+        let mut compressed = Vec::new();
+        deflate(compressor, value, TDEFLFlush::Finish, &mut compressed);
+        compressed
+    }
+}
+
+// Decompress a value returned by DeflateDictionary::compress, given the dictionary's compressed form and size.
+pub fn inflate_with_dictionary(compressed_dictionary: &[u8], dictionary_size: usize, value: &[u8]) -> Vec<u8> {
+    let mut decompressed = decompress_to_vec(&[compressed_dictionary, value].concat()).expect("decompress value");
+    decompressed.drain(..dictionary_size);
+    decompressed
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build::dictionary::{DeflateDictionary, inflate_with_dictionary, MAX_DICTIONARY_SIZE, train_dictionary};
+
+    fn documents() -> Vec<Vec<u8>> {
+        (0..2000).map(|i| format!(r#"{{"title":"Listing {}","company":"Company {}","location":"Sydney, New South Wales, Australia","remote":{}}}"#, i, i % 7, i % 2 == 0).into_bytes()).collect()
+    }
+
+    #[test]
+    fn values_are_decompressed_with_the_dictionary() {
+        let samples = documents();
+        let dictionary = DeflateDictionary::new(&train_dictionary(&samples));
+        let mut compressor = dictionary.compressor();
+        for value in [&b""[..], b"x", &samples[0], &samples[1999], &vec![b'a'; 100000]] {
+            let compressed = dictionary.compress(&mut compressor, value);
+            assert_eq!(inflate_with_dictionary(dictionary.compressed(), dictionary.dictionary_size(), &compressed), value);
+        };
+    }
+
+    #[test]
+    fn trained_dictionaries_compress_similar_values_better() {
+        let samples = documents();
+        let trained = train_dictionary(&samples);
+        assert!(!trained.is_empty() && trained.len() <= MAX_DICTIONARY_SIZE);
+        let trained = DeflateDictionary::new(&trained);
+        let empty = DeflateDictionary::new(&[]);
+        let value = br#"{"title":"Listing 5000","company":"Company 3","location":"Sydney, New South Wales, Australia","remote":true}"#;
+        assert!(trained.compress(&mut trained.compressor(), value).len() * 2 < empty.compress(&mut empty.compressor(), value).len());
+    }
+}
@@ -0,0 +1,209 @@
+use std::cmp::Reverse;
+use std::convert::TryInto;
+use std::collections::BinaryHeap;
+use std::fs::{create_dir, File, remove_dir_all, remove_file};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::mem::{size_of, take};
+use std::path::PathBuf;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use rayon::prelude::*;
+
+use crate::{DocumentId, Term};
+use crate::build::bitmap::bitmap::Bitmap;
+use crate::util::format::number;
+
+// Maximum amount of runs merged at once, so that a small memory limit doesn't open more files than the system allows.
+// If there are more runs, they are merged in passes into fewer, longer runs first.
+const MAX_MERGED_RUNS: usize = 64;
+
+// Collects (term, document ID) pairs and produces the bitmap of every term in term order, while holding at most
+// `memory_limit` bytes of pairs in memory. Pairs are sorted in runs that are spilled to disk and then merged.
+pub struct ExternalPostingsSorter {
+    dir: PathBuf,
+    memory_limit: usize,
+    pairs: Vec<(Term, DocumentId)>,
+    pairs_memory: usize,
+    runs: Vec<PathBuf>,
+    // Amount of run files created, including those already merged into longer runs.
+    run_files: usize,
+}
+
+// Keep in sync with ExternalPostingsSorter::write_run.
+fn read_pair(reader: &mut BufReader<File>) -> Option<(Term, DocumentId)> {
+    let mut term = Vec::new();
+    if reader.read_until(b'\0', &mut term).expect("read run") == 0 {
+        return None;
+    };
+    term.pop();
+    let document_id = reader.read_u32::<LittleEndian>().expect("read run");
+    Some((String::from_utf8(term).expect("parse term in run as UTF-8"), document_id))
+}
+
+// Read the pairs of sorted runs in order.
+fn merge_runs(runs: &[PathBuf]) -> impl Iterator<Item = (Term, DocumentId)> {
+    let mut readers = runs.iter()
+        .map(|path| BufReader::new(File::open(path).expect("open run file")))
+        .collect::<Vec<_>>();
+    let mut heap = BinaryHeap::new();
+    for (run, reader) in readers.iter_mut().enumerate() {
+        if let Some((term, document_id)) = read_pair(reader) {
+            heap.push(Reverse((term, document_id, run)));
+        };
+    };
+    std::iter::from_fn(move || {
+        let Reverse((term, document_id, run)) = heap.pop()?;
+        if let Some((next_term, next_document_id)) = read_pair(&mut readers[run]) {
+            heap.push(Reverse((next_term, next_document_id, run)));
+        };
+        Some((term, document_id))
+    })
+}
+
+impl ExternalPostingsSorter {
+    pub fn new(dir: PathBuf, memory_limit: usize) -> ExternalPostingsSorter {
+        let _ = remove_dir_all(&dir);
+        create_dir(&dir).expect("create runs folder");
+        ExternalPostingsSorter {
+            dir,
+            memory_limit,
+            pairs: Vec::new(),
+            pairs_memory: 0,
+            runs: Vec::new(),
+            run_files: 0,
+        }
+    }
+
+    pub fn add(&mut self, term: Term, document_id: DocumentId) {
+        self.pairs_memory += size_of::<(Term, DocumentId)>() + term.len();
+        self.pairs.push((term, document_id));
+        if self.pairs_memory >= self.memory_limit {
+            self.spill();
+        };
+    }
+
+    // Write sorted pairs to a new run file, and return its path.
+    fn write_run(&mut self, pairs: impl Iterator<Item = (Term, DocumentId)>) -> PathBuf {
+        let path = self.dir.join(self.run_files.to_string());
+        self.run_files += 1;
+        let mut writer = BufWriter::new(File::create(&path).expect("create run file"));
+        for (term, document_id) in pairs {
+            writer.write_all(term.as_bytes()).expect("write run");
+            writer.write_all(b"\0").expect("write run");
+            writer.write_u32::<LittleEndian>(document_id).expect("write run");
+        };
+        writer.flush().expect("write run");
+        path
+    }
+
+    fn spill(&mut self) {
+        let mut pairs = take(&mut self.pairs);
+        pairs.par_sort_unstable();
+        let run = self.write_run(pairs.drain(..));
+        // Reuse the allocation for the next run.
+        self.pairs = pairs;
+        self.pairs_memory = 0;
+        self.runs.push(run);
+    }
+
+    // Merge all runs and call `f` with each term and the bitmap of documents containing it, in term order.
+    // Returns the amount of terms.
+    pub fn merge<F: FnMut(Term, Bitmap)>(mut self, mut f: F) -> usize {
+        if !self.pairs.is_empty() {
+            self.spill();
+        };
+        println!("Spilled {} runs to disk", number(self.runs.len()));
+        while self.runs.len() > MAX_MERGED_RUNS {
+            let runs = take(&mut self.runs);
+            for group in runs.chunks(MAX_MERGED_RUNS) {
+                let run = self.write_run(merge_runs(group));
+                for path in group {
+                    remove_file(path).expect("remove run file");
+                };
+                self.runs.push(run);
+            };
+            println!("Merged runs into {} runs", number(self.runs.len()));
+        };
+
+        let mut term_count = 0;
+        let mut current: Option<(Term, Bitmap)> = None;
+        for (term, document_id) in merge_runs(&self.runs) {
+            if current.as_ref().filter(|(current_term, _)| *current_term == term).is_none() {
+                if let Some((current_term, bitmap)) = current.take() {
+                    f(current_term, bitmap);
+                    term_count += 1;
+                };
+                current = Some((term, Bitmap::create()));
+            };
+            current.as_mut().unwrap().1.add(document_id);
+        };
+        if let Some((current_term, bitmap)) = current {
+            f(current_term, bitmap);
+            term_count += 1;
+        };
+
+        remove_dir_all(&self.dir).expect("remove runs folder");
+        term_count
+    }
+}
+
+// Values keyed by document ID, appended to a file as they're produced so that they can be inserted into chunks later
+// without holding them in memory, such as stored fields, whose chunks are written after the documents chunks.
+pub struct SpilledValues {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl SpilledValues {
+    pub fn new(path: PathBuf) -> SpilledValues {
+        let writer = BufWriter::new(File::create(&path).expect("create spilled values file"));
+        SpilledValues { path, writer }
+    }
+
+    pub fn add(&mut self, document_id: DocumentId, value: &[u8]) {
+        self.writer.write_u32::<LittleEndian>(document_id).expect("write spilled values");
+        self.writer.write_u32::<LittleEndian>(value.len().try_into().expect("value is too long")).expect("write spilled values");
+        self.writer.write_all(value).expect("write spilled values");
+    }
+
+    // Read back all values in the order they were added.
+    pub fn read(mut self) -> impl Iterator<Item = (DocumentId, Vec<u8>)> {
+        self.writer.flush().expect("write spilled values");
+        let mut reader = BufReader::new(File::open(&self.path).expect("open spilled values file"));
+        std::iter::from_fn(move || {
+            // Keep in sync with SpilledValues::add.
+            let document_id = match reader.read_u32::<LittleEndian>() {
+                Ok(document_id) => document_id,
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+                Err(err) => panic!("read spilled values: {}", err),
+            };
+            let len = reader.read_u32::<LittleEndian>().expect("read spilled values") as usize;
+            let mut value = vec![0u8; len];
+            reader.read_exact(&mut value).expect("read spilled values");
+            Some((document_id, value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build::BuildConfig;
+    use crate::build::testing::{build_test_layer, numbered_documents, TestDir};
+
+    #[test]
+    fn terms_chunks_are_the_same_when_sorted_externally() {
+        let dir = TestDir::new();
+        let (documents, terms) = numbered_documents(200, |i| (0..i % 5).map(|t| ["a", "b", "c", "d", "e"][(i + t) % 5]).chain(Some(["x", "y"][i % 2])).collect());
+        let in_memory = build_test_layer(dir.config("in_memory", &documents, &terms));
+        assert!(in_memory.terms_chunks.len() > 1);
+        // A limit of one byte spills every pair in its own run.
+        for (output, memory_limit) in [("one_byte", 1), ("some_pairs", 256)] {
+            let external = build_test_layer(BuildConfig { memory_limit: Some(memory_limit), ..dir.config(output, &documents, &terms) });
+            assert_eq!(external.document_count, in_memory.document_count);
+            assert_eq!(
+                external.terms_chunks.iter().map(|c| (&c.name, &c.hash, &c.first_key)).collect::<Vec<_>>(),
+                in_memory.terms_chunks.iter().map(|c| (&c.name, &c.hash, &c.first_key)).collect::<Vec<_>>(),
+            );
+        };
+    }
+}
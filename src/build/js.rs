@@ -1,37 +1,159 @@
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
-use crate::build::DataStore;
+use crate::build::{DataStore, Target, WorkerFormat};
+use crate::build::manifest::{FORMAT_VERSION, ManifestDictionary, ManifestSettings, WorkerIndex};
+use crate::build::output::OutputSink;
 
-const WORKER_JS_MAIN_TEMPLATE: &'static str = include_str!("../../script/dist/main.js");
+const WORKER_JS_MAIN_TEMPLATE: &str = include_str!("../../script/dist/main.js");
+const WORKER_JS_COORDINATOR_TEMPLATE: &str = include_str!("../../script/dist/coordinator.js");
+const BROWSER_JS_TEMPLATE: &str = include_str!("../../script/dist/browser.js");
+const SERVER_JS_TEMPLATE: &str = include_str!("../../script/dist/server.js");
 
+// Script bundles expose their exports on the `Edgesearch` global (see script/package.json), and the code that uses them is appended.
+// For workers, this calls `handleRequest` with every request and the worker's bindings.
+fn worker_entry_point(format: WorkerFormat) -> &'static str {
+    match format {
+        WorkerFormat::Service => r#"addEventListener("fetch", (event) => event.respondWith(Edgesearch.handleRequest(event.request, self)));"#,
+        WorkerFormat::Module => r#"export default { fetch: (request, env) => Edgesearch.handleRequest(request, env) };"#,
+    }
+}
+
+fn dictionary_json(dictionary: &ManifestDictionary) -> serde_json::Value {
+    serde_json::json!({
+        "name": dictionary.name,
+        "offset": dictionary.offset,
+        "size": dictionary.size,
+        "dictionarySize": dictionary.dictionary_size,
+    })
+}
+
+// Write the script for `target`, and return its size.
 pub fn generate_worker_js(
-    output_dir: &PathBuf,
+    output: &dyn OutputSink,
+    target: Target,
+    worker_format: WorkerFormat,
     data_store: DataStore,
-    data_store_url_prefix: Option<String>,
-    document_count: usize,
-    max_query_terms: usize,
-    max_results: usize,
-) -> () {
+    settings: &ManifestSettings,
+    indexes: &[WorkerIndex],
+) -> usize {
+    // Layers of all indexes are numbered in order, matching the runner's chunk tables (see build::wasm::generate_chunk_tables).
+    // Keep in sync with `Layer` and `Index` in script/src/search.ts.
+    let mut layers_json = Vec::new();
+    let mut indexes_json = Vec::new();
+    for index in indexes {
+        let first_layer = layers_json.len();
+        for layer in index.layers.iter() {
+            layers_json.push(serde_json::json!({
+                "prefix": layer.prefix,
+                "compression": layer.compression,
+                "summaries": layer.summaries_chunks.is_some(),
+                "fields": layer.fields.iter().map(|f| &f.name).collect::<Vec<_>>(),
+                "deleted": layer.deleted.map(|d| serde_json::json!({
+                    "name": d.name,
+                    "offset": d.offset,
+                    "size": d.size,
+                })),
+                "documentsDictionary": layer.documents_dictionary.map(dictionary_json),
+                "summariesDictionary": layer.summaries_dictionary.map(dictionary_json),
+            }));
+        };
+        indexes_json.push(serde_json::json!({
+            "name": index.name,
+            "documentCount": index.document_count,
+            "layers": (first_layer..layers_json.len()).collect::<Vec<_>>(),
+        }));
+    };
+
+    let (file_name, template, prelude, epilogue, url_prefix) = match target {
+        Target::Worker => (
+            "worker.js",
+            WORKER_JS_MAIN_TEMPLATE,
+            match worker_format {
+                WorkerFormat::Service => String::new(),
+                // Module workers can't have WebAssembly module bindings, so the runner is imported.
+                WorkerFormat::Module => r#"import QUERY_RUNNER_WASM from "./runner.wasm";"#.to_string(),
+            },
+            worker_entry_point(worker_format),
+            settings.data_store_url_prefix.as_ref().map_or("undefined".to_string(), |prefix| format!("`{}`", prefix)),
+        ),
+        // The runner and chunks are fetched relative to the module, so that the output folder can be hosted anywhere.
+        Target::Browser => (
+            "browser.js",
+            BROWSER_JS_TEMPLATE,
+            r#"const QUERY_RUNNER_WASM = await WebAssembly.compile(await (await fetch(new URL("runner.wasm", import.meta.url))).arrayBuffer());"#.to_string(),
+            "export const { Client, Mode, Query } = Edgesearch;",
+            format!("new URL(`{}`, import.meta.url).href", settings.data_store_url_prefix.as_deref().unwrap_or("./")),
+        ),
+        // Node.js modules are imported as constants for script/src/server.ts, and Deno provides them too.
+        // Without a URL prefix, chunks are read from files next to the module.
+        Target::Server => (
+            "server.mjs",
+            SERVER_JS_TEMPLATE,
+            [
+                r#"import * as NODE_FS from "node:fs/promises";"#,
+                r#"import * as NODE_HTTP from "node:http";"#,
+                r#"const QUERY_RUNNER_WASM = await WebAssembly.compile(await NODE_FS.readFile(new URL("runner.wasm", import.meta.url)));"#,
+            ].join("\n"),
+            "Edgesearch.serve();",
+            settings.data_store_url_prefix.as_ref().map_or(r#"new URL("./", import.meta.url).href"#.to_string(), |prefix| format!("`{}`", prefix)),
+        ),
+    };
+
     // Keep in sync with variables declared in script/src/**/*.ts.
     let js = format!(r#"
+        {PRELUDE}
+        const CACHE_DATA = {CACHE_DATA};
         const DATA_STORE = "{DATA_STORE}";
         const DATASTORE_URL_PREFIX = {DATASTORE_URL_PREFIX};
-        const DOCUMENT_COUNT = {DOCUMENT_COUNT};
+        const DATA_STORE_BUCKET_BINDING = {DATA_STORE_BUCKET_BINDING};
+        const DATA_STORE_REGION = {DATA_STORE_REGION};
+        const FORMAT_VERSION = {FORMAT_VERSION};
+        const INDEXES = {INDEXES};
+        const LAYERS = {LAYERS};
         const MAX_QUERY_TERMS = {MAX_QUERY_TERMS};
         const MAX_RESULTS = {MAX_RESULTS};
         {WORKER_JS_TEMPLATE}
+        {EPILOGUE}
+    "#,
+        PRELUDE = prelude,
+        EPILOGUE = epilogue,
+        // Browsers keep chunks in memory, as they would otherwise fetch them again for every search.
+        CACHE_DATA = matches!(target, Target::Browser),
+        DATA_STORE = data_store.id(),
+        DATASTORE_URL_PREFIX = url_prefix,
+        DATA_STORE_BUCKET_BINDING = serde_json::json!(settings.data_store_bucket_binding),
+        DATA_STORE_REGION = serde_json::json!(settings.data_store_region),
+        FORMAT_VERSION = FORMAT_VERSION,
+        INDEXES = serde_json::Value::Array(indexes_json),
+        LAYERS = serde_json::Value::Array(layers_json),
+        MAX_QUERY_TERMS = settings.maximum_query_terms,
+        MAX_RESULTS = settings.maximum_query_results,
+        WORKER_JS_TEMPLATE = template
+    );
+
+    output.write(file_name, js.as_bytes());
+    js.len()
+}
+
+pub fn generate_coordinator_js(
+    output: &dyn OutputSink,
+    worker_format: WorkerFormat,
+    shard_count: usize,
+    shard_url_template: &str,
+    max_results: usize,
+) {
+    // Keep in sync with variables declared in script/src/coordinator.ts.
+    let js = format!(r#"
+        const SHARD_COUNT = {SHARD_COUNT};
+        const SHARD_URL_TEMPLATE = {SHARD_URL_TEMPLATE};
+        const MAX_RESULTS = {MAX_RESULTS};
+        {WORKER_JS_TEMPLATE}
+        {EPILOGUE}
     "#,
-        DATA_STORE = match data_store {
-            DataStore::KV => "kv",
-            DataStore::URL => "url",
-        },
-        DATASTORE_URL_PREFIX = data_store_url_prefix.map_or("undefined".to_string(), |prefix| format!("`{}`", prefix)),
-        DOCUMENT_COUNT = document_count,
-        MAX_QUERY_TERMS = max_query_terms,
+        EPILOGUE = worker_entry_point(worker_format),
+        SHARD_COUNT = shard_count,
+        SHARD_URL_TEMPLATE = serde_json::Value::String(shard_url_template.to_string()),
         MAX_RESULTS = max_results,
-        WORKER_JS_TEMPLATE = WORKER_JS_MAIN_TEMPLATE
+        WORKER_JS_TEMPLATE = WORKER_JS_COORDINATOR_TEMPLATE
     );
 
-    File::create(output_dir.join("worker.js")).expect("create worker.js file").write_all(js.as_bytes()).expect("write worker.js");
+    output.write("coordinator.js", js.as_bytes());
 }
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::build::chunks::{ChunkKey, SerialisedChunk};
+use crate::build::output::OutputSink;
+use crate::build::schema::DocumentSchema;
+
+// Bump whenever the layout of chunks, lookup tables, or runner/worker interfaces changes.
+// Keep in sync with FORMAT_VERSION in script/src/search.ts and wasm/index.c.
+pub const FORMAT_VERSION: u32 = 1;
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ManifestSettings {
+    pub chunk_naming: String,
+    pub chunk_size: usize,
+    pub compression: String,
+    pub data_store: String,
+    pub data_store_url_prefix: Option<String>,
+    pub data_store_bucket_binding: Option<String>,
+    pub data_store_region: Option<String>,
+    pub target: String,
+    pub worker_format: String,
+    pub maximum_query_results: usize,
+    pub maximum_query_terms: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestChunk {
+    pub id: usize,
+    // Key suffix under which the chunk is stored, e.g. `terms/{name}`.
+    pub name: String,
+    pub size: usize,
+    // Lowercase hex SHA-256 of the chunk's bytes.
+    pub hash: String,
+    pub first_key: ChunkKey,
+    // Position of the root node of the chunk's BST.
+    pub mid_pos: u32,
+    // Whether an identical chunk with the same name existed in the previous build this build was based on.
+    pub reused: bool,
+    // Byte offset of the chunk in `{layer_prefix}index.bin` if the data store is packed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    // Size of the chunk's secondary index if the data store supports Range requests. It's stored at `{name}.index`, or directly after the chunk if packed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secondary_index_size: Option<usize>,
+}
+
+impl ManifestChunk {
+    pub fn for_chunk(chunk: &SerialisedChunk, reused: bool) -> ManifestChunk {
+        ManifestChunk {
+            id: chunk.id,
+            name: chunk.name.clone(),
+            size: chunk.data.len(),
+            hash: chunk.hash.clone(),
+            first_key: chunk.first_key.clone(),
+            mid_pos: chunk.mid_pos,
+            reused,
+            offset: None,
+            secondary_index_size: None,
+        }
+    }
+
+    pub fn boundaries(chunks: &[ManifestChunk]) -> Vec<ChunkKey> {
+        chunks.iter().map(|c| c.first_key.clone()).collect()
+    }
+}
+
+// Serialised Roaring Bitmap of IDs of documents to exclude from all results, stored at `{layer_prefix}deleted/{name}`.
+#[derive(Serialize, Deserialize)]
+pub struct ManifestDeleted {
+    pub name: String,
+    pub size: usize,
+    pub hash: String,
+    pub count: usize,
+    // Byte offset of the bitmap in `{layer_prefix}index.bin` if the data store is packed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+}
+
+// Dictionary that a layer's documents or summaries are compressed with (see build::dictionary::DeflateDictionary), stored in its compressed form
+// at `{layer_prefix}{documents or summaries}-dictionary/{name}`.
+#[derive(Serialize, Deserialize)]
+pub struct ManifestDictionary {
+    pub name: String,
+    pub size: usize,
+    pub hash: String,
+    // Size of the dictionary before compression, which is skipped when decompressing.
+    pub dictionary_size: usize,
+    // Byte offset of the compressed dictionary in `{layer_prefix}index.bin` if the data store is packed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+}
+
+// Values of a top-level field of documents, stored in their own chunks keyed by document ID so that searches can return only some fields.
+#[derive(Serialize, Deserialize)]
+pub struct ManifestField {
+    pub name: String,
+    // Stored at `{layer_prefix}fields/{position of the field}/{name}`. Documents without the field have no entry.
+    pub chunks: Vec<ManifestChunk>,
+}
+
+// Set when the build is a delta index layered on top of a base build.
+#[derive(Serialize, Deserialize)]
+pub struct ManifestDeltaBase {
+    // Document IDs in the delta start from this value.
+    pub document_count: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestTiming {
+    // Seconds since the Unix epoch.
+    pub started: u64,
+    // Milliseconds taken by each phase.
+    pub phases: BTreeMap<String, u128>,
+    // Milliseconds taken in total.
+    pub total: u128,
+    #[serde(skip)]
+    start: Option<Instant>,
+}
+
+impl ManifestTiming {
+    pub fn start() -> ManifestTiming {
+        ManifestTiming {
+            started: SystemTime::now().duration_since(UNIX_EPOCH).expect("get current time").as_secs(),
+            phases: BTreeMap::new(),
+            total: 0,
+            start: Some(Instant::now()),
+        }
+    }
+
+    pub fn record(&mut self, phase: &str, phase_start: Instant) {
+        self.phases.insert(phase.to_string(), phase_start.elapsed().as_millis());
+    }
+
+    pub fn finish(&mut self) {
+        self.total = self.start.expect("timing was not started").elapsed().as_millis();
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub edgesearch_version: String,
+    pub format_version: u32,
+    pub settings: ManifestSettings,
+    // Prefix of all data store keys of this build's chunks, e.g. `delta/` for `delta/terms/0`.
+    pub layer_prefix: String,
+    pub delta_base: Option<ManifestDeltaBase>,
+    // Total documents, including those in the base if this is a delta.
+    pub document_count: usize,
+    pub term_count: usize,
+    pub terms_chunks: Vec<ManifestChunk>,
+    pub documents_chunks: Vec<ManifestChunk>,
+    // Chunks of summaries of documents, which search results return instead of documents, if document summaries were provided.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summaries_chunks: Option<Vec<ManifestChunk>>,
+    // Chunks mapping external document keys to document IDs, if document keys were provided.
+    pub keys_chunks: Vec<ManifestChunk>,
+    // Fields stored separately, if any.
+    pub fields: Vec<ManifestField>,
+    pub deleted: Option<ManifestDeleted>,
+    // Set if documents are compressed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub documents_dictionary: Option<ManifestDictionary>,
+    // Set if summaries are compressed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summaries_dictionary: Option<ManifestDictionary>,
+    // Names of the named indexes of the build, each with its own manifest at `{name}/manifest.json`.
+    // If there are any, this manifest has no chunks, and its counts are summed across all named indexes.
+    pub indexes: Vec<String>,
+    // Amount of shards of a sharded build, each with its own manifest at `shards/{shard}/manifest.json` and served by its own worker.
+    // If there are any, this manifest has no chunks, its counts are summed across all shards, and its worker is `coordinator.js`.
+    pub shards: usize,
+    // Inferred schema of every document of this build, including those of its delta base, if clients were generated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document_schema: Option<DocumentSchema>,
+    // Inferred schema of every summary of this build, including those of its delta base, if summaries were provided and clients were generated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary_schema: Option<DocumentSchema>,
+    pub timing: ManifestTiming,
+    // Packed chunks of this layer for the embedded data store, which are compiled into the runner instead of being written to a data store.
+    #[serde(skip)]
+    pub embedded_data: Option<Vec<u8>>,
+}
+
+// A set of chunks searched together with other layers, such as a base index and a delta index on top of it.
+pub struct IndexLayer<'m> {
+    pub prefix: &'m str,
+    // Compression of the layer's documents and summaries, which can differ from other layers of the same index.
+    pub compression: &'m str,
+    pub terms_chunks: &'m [ManifestChunk],
+    pub documents_chunks: &'m [ManifestChunk],
+    // None if the layer has no summaries, in which case search results return documents.
+    pub summaries_chunks: Option<&'m [ManifestChunk]>,
+    pub keys_chunks: &'m [ManifestChunk],
+    pub fields: &'m [ManifestField],
+    pub deleted: Option<&'m ManifestDeleted>,
+    pub documents_dictionary: Option<&'m ManifestDictionary>,
+    pub summaries_dictionary: Option<&'m ManifestDictionary>,
+    pub embedded_data: Option<&'m [u8]>,
+}
+
+// An index served by a worker, such as a base index with a delta index on top of it.
+pub struct WorkerIndex<'m> {
+    // Served at `/{name}/search`, or `/search` if None.
+    pub name: Option<&'m str>,
+    pub document_count: usize,
+    // Ordered from oldest to newest.
+    pub layers: Vec<IndexLayer<'m>>,
+}
+
+impl Manifest {
+    pub fn read(output_dir: &Path) -> Manifest {
+        let file = File::open(output_dir.join(MANIFEST_FILE_NAME)).expect("open manifest");
+        serde_json::from_reader(file).expect("parse manifest")
+    }
+
+    pub fn layer(&self) -> IndexLayer<'_> {
+        IndexLayer {
+            prefix: &self.layer_prefix,
+            compression: &self.settings.compression,
+            terms_chunks: &self.terms_chunks,
+            documents_chunks: &self.documents_chunks,
+            summaries_chunks: self.summaries_chunks.as_deref(),
+            keys_chunks: &self.keys_chunks,
+            fields: &self.fields,
+            deleted: self.deleted.as_ref(),
+            documents_dictionary: self.documents_dictionary.as_ref(),
+            summaries_dictionary: self.summaries_dictionary.as_ref(),
+            embedded_data: self.embedded_data.as_deref(),
+        }
+    }
+
+    // Write to `{prefix}manifest.json` of the output.
+    pub fn write(&self, output: &dyn OutputSink, prefix: &str) {
+        output.write(&format!("{}{}", prefix, MANIFEST_FILE_NAME), &serde_json::to_vec_pretty(self).expect("serialise manifest"));
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{File, read};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::DocumentId;
+use crate::build::{chunks_output, ChunkNaming, Compression, DataStore, finish_pack, OutputSink, Target, ValueCompressor, ValueDecompressor, with_threads, WorkerFormat, write_deleted, write_worker_runner_and_manifest};
+use crate::build::bitmap::bitmap::Bitmap;
+use crate::build::chunks::{ChunkEntryKey, ChunkKey, ChunkStrKey, ChunkU32Key};
+use crate::build::chunks::bst::read_bst_chunk;
+use crate::build::manifest::{FORMAT_VERSION, Manifest, ManifestChunk, ManifestDictionary, ManifestField, ManifestSettings, ManifestTiming};
+use crate::build::pack::{PACKED_FILE_NAME, PackWriter};
+use crate::build::schema::DocumentSchema;
+use crate::util::format::number;
+
+pub struct MergeConfig {
+    pub chunk_naming: ChunkNaming,
+    pub chunk_size: usize,
+    // Compression of documents and summaries of the merged index; inputs are decompressed first, regardless of their compression.
+    pub compression: Compression,
+    pub data_store: DataStore,
+    pub data_store_url_prefix: Option<String>,
+    pub data_store_bucket_binding: Option<String>,
+    pub data_store_region: Option<String>,
+    pub target: Target,
+    pub worker_format: WorkerFormat,
+    // Output directories of the builds to merge. Document IDs of each input are offset by the total documents of all inputs before it.
+    pub input_dirs: Vec<PathBuf>,
+    pub maximum_query_results: usize,
+    pub maximum_query_terms: usize,
+    // Infer a schema from the documents of all inputs, and write typed clients for it.
+    pub generate_clients: bool,
+    // Must not be the output directory of an input, as inputs are read while the output is written.
+    pub output: Arc<dyn OutputSink>,
+    // Amount of threads to use; 0 uses one per CPU.
+    pub threads: usize,
+}
+
+// Read a chunk or deleted bitmap from the folder `{input_dir}/{kind}`, or from the input's packed file if it has an offset.
+fn read_input(input_dir: &Path, kind: &str, name: &str, offset: Option<usize>, size: usize) -> Vec<u8> {
+    match offset {
+        Some(offset) => {
+            let mut file = File::open(input_dir.join(PACKED_FILE_NAME)).expect("open packed file");
+            file.seek(SeekFrom::Start(offset as u64)).expect("seek in packed file");
+            let mut data = vec![0u8; size];
+            file.read_exact(&mut data).expect("read packed file");
+            data
+        }
+        None => read(input_dir.join(kind).join(name)).expect("read chunk"),
+    }
+}
+
+// Create a decompressor for the documents or summaries of an input, which are `kind`, compressed with `dictionary` if compressed.
+fn input_decompressor(input_dir: &Path, manifest: &Manifest, kind: &str, dictionary: Option<&ManifestDictionary>) -> ValueDecompressor {
+    ValueDecompressor::new(
+        manifest.settings.compression.parse::<Compression>().expect("parse compression"),
+        dictionary.map(|d| (read_input(input_dir, &format!("{}-dictionary", kind), &d.name, d.offset, d.size), d.dictionary_size)),
+    )
+}
+
+// Read the decompressed documents or summaries of all inputs, which are `kind`, in order, to train the dictionary of the merged index.
+fn read_samples<'i>(inputs: &'i [(&'i PathBuf, Manifest)], kind: &'i str) -> impl Iterator<Item = Vec<u8>> + 'i {
+    inputs.iter().flat_map(move |(dir, manifest)| {
+        let (chunks, dictionary) = match kind {
+            "documents" => (manifest.documents_chunks.as_slice(), manifest.documents_dictionary.as_ref()),
+            _ => (manifest.summaries_chunks.as_deref().unwrap_or(&[]), manifest.summaries_dictionary.as_ref()),
+        };
+        let decompressor = input_decompressor(dir, manifest, kind, dictionary);
+        read_chunks::<ChunkU32Key>(dir, kind, chunks).map(move |(_, value)| decompressor.decompress(value))
+    })
+}
+
+// Read the entries of chunks in key order, one chunk at a time.
+pub fn read_chunks<'c, K: ChunkEntryKey + 'c>(input_dir: &'c Path, kind: &'c str, chunks: &'c [ManifestChunk]) -> impl Iterator<Item = (K, Vec<u8>)> + 'c {
+    chunks.iter().flat_map(move |chunk| {
+        let data = read_input(input_dir, kind, &chunk.name, chunk.offset, chunk.size);
+        read_bst_chunk::<K>(&data, chunk.mid_pos)
+    })
+}
+
+// Read the entries of keys chunks as (key, document ID) in key order.
+pub fn read_keys<'c>(input_dir: &'c Path, chunks: &'c [ManifestChunk]) -> impl Iterator<Item = (String, DocumentId)> + 'c {
+    read_chunks::<ChunkStrKey>(input_dir, "keys", chunks).map(|(key, document_id)| match key.key() {
+        ChunkKey::Str(key) => (key.clone(), LittleEndian::read_u32(&document_id)),
+        ChunkKey::U32(_) => unreachable!(),
+    })
+}
+
+fn offset_bitmap(target: &mut Bitmap, serialised: &[u8], offset: usize) {
+    for document_id in Bitmap::deserialize(serialised).to_vec() {
+        target.add((offset + document_id as usize).try_into().expect("too many documents"));
+    };
+}
+
+pub fn merge(config: MergeConfig) {
+    with_threads(config.threads, || {
+        let output = config.output.clone();
+        let mut manifest = merge_layer(config);
+        write_worker_runner_and_manifest(output.as_ref(), &mut manifest, &[]);
+        println!("Merge complete")
+    });
+}
+
+// Write the chunks of the merged index, and return its manifest.
+fn merge_layer(MergeConfig {
+    chunk_naming,
+    chunk_size,
+    compression,
+    data_store,
+    data_store_url_prefix,
+    data_store_bucket_binding,
+    data_store_region,
+    target,
+    worker_format,
+    input_dirs,
+    maximum_query_results,
+    maximum_query_terms,
+    generate_clients,
+    output,
+    ..
+}: MergeConfig) -> Manifest {
+    let mut timing = ManifestTiming::start();
+
+    let inputs = input_dirs.iter().map(|dir| (dir, Manifest::read(dir))).collect::<Vec<_>>();
+    for (dir, manifest) in inputs.iter() {
+        assert_eq!(manifest.format_version, FORMAT_VERSION, "{:?} was built with a different data format", dir);
+        assert!(manifest.delta_base.is_none(), "{:?} is a delta and cannot be merged", dir);
+        // The manifests of sharded builds and builds of named indexes have no chunks, only the total amount of documents of their indexes.
+        assert_eq!(manifest.shards, 0, "{:?} is a sharded build and cannot be merged", dir);
+        assert!(manifest.indexes.is_empty(), "{:?} is a build of named indexes and cannot be merged", dir);
+    };
+    let has_summaries = inputs[0].1.summaries_chunks.is_some();
+    assert!(inputs.iter().all(|(_, manifest)| manifest.summaries_chunks.is_some() == has_summaries), "either every input or no input must have document summaries");
+
+    let read_inputs_start = Instant::now();
+    // term => bitmap.
+    let mut postings = BTreeMap::<String, Bitmap>::new();
+    // key => document ID.
+    let mut keys = BTreeMap::<String, DocumentId>::new();
+    let mut deleted = Bitmap::create();
+    let mut has_deleted = false;
+    // Documents are written as they are read, as they are already in order.
+    let mut pack = if data_store.is_packed() { Some(PackWriter::new(None)) } else { None };
+    let mut documents_builder = chunks_output::<ChunkU32Key>(output.as_ref(), pack.as_mut(), data_store, "documents", chunk_size, chunk_naming, None);
+    let mut documents_compressor = ValueCompressor::new(compression, read_samples(&inputs, "documents"));
+    let mut document_schema = if generate_clients { Some(DocumentSchema::default()) } else { None };
+    // Document ID offset of each input.
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    for (dir, manifest) in inputs.iter() {
+        offsets.push(offset);
+        let documents_decompressor = input_decompressor(dir, manifest, "documents", manifest.documents_dictionary.as_ref());
+        println!("Reading {:?} ({} documents)...", dir, number(manifest.document_count));
+        for (key, serialised) in read_chunks::<ChunkStrKey>(dir, "terms", &manifest.terms_chunks) {
+            let term = match key.key() {
+                ChunkKey::Str(term) => term.clone(),
+                ChunkKey::U32(_) => unreachable!(),
+            };
+            offset_bitmap(postings.entry(term).or_insert_with(Bitmap::create), &serialised, offset);
+        };
+        // Document IDs are increasing across inputs, so they can be inserted in order.
+        let documents = read_chunks::<ChunkU32Key>(dir, "documents", &manifest.documents_chunks).map(|(key, document)| {
+            let document_id = match key.key() {
+                ChunkKey::U32(document_id) => *document_id as usize,
+                ChunkKey::Str(_) => unreachable!(),
+            };
+            let document = documents_decompressor.decompress(document);
+            if let Some(schema) = &mut document_schema {
+                schema.add(&serde_json::from_slice(&document).expect("parse document as JSON"));
+            };
+            (offset + document_id, document)
+        });
+        for (document_id, entry) in documents_compressor.compress_all(documents) {
+            documents_builder.insert(ChunkU32Key::new(document_id.try_into().expect("too many documents")), entry);
+        };
+        for (key, document_id) in read_keys(dir, &manifest.keys_chunks) {
+            let document_id = (offset + document_id as usize).try_into().expect("too many documents");
+            assert!(keys.insert(key.clone(), document_id).is_none(), "document key {:?} is in more than one input", key);
+        };
+        if let Some(input_deleted) = &manifest.deleted {
+            offset_bitmap(&mut deleted, &read_input(dir, "deleted", &input_deleted.name, input_deleted.offset, input_deleted.size), offset);
+            has_deleted = true;
+        };
+        offset += manifest.document_count;
+    };
+    // Finished before terms chunks are built, as both write to the same packed file.
+    let documents_chunks = documents_builder.finish().written;
+    timing.record("read_inputs", read_inputs_start);
+    println!("There are {} documents with {} terms", number(offset), number(postings.len()));
+
+    let terms_chunks_start = Instant::now();
+    let term_count = postings.len();
+    let mut terms_index_builder = chunks_output::<ChunkStrKey>(output.as_ref(), pack.as_mut(), data_store, "terms", chunk_size, chunk_naming, None);
+    for (term, mut bitmap) in postings {
+        bitmap.run_optimize();
+        terms_index_builder.insert(ChunkStrKey::new(&term), bitmap.serialize());
+    };
+    let terms_chunks = terms_index_builder.finish().written;
+    println!("{} chunks contain terms", number(terms_chunks.len()));
+    timing.record("build_terms_chunks", terms_chunks_start);
+
+    let keys_chunks_start = Instant::now();
+    let keys_chunks = if keys.is_empty() {
+        Vec::new()
+    } else {
+        let mut keys_builder = chunks_output::<ChunkStrKey>(output.as_ref(), pack.as_mut(), data_store, "keys", chunk_size, chunk_naming, None);
+        for (key, document_id) in keys {
+            keys_builder.insert(ChunkStrKey::new(&key), document_id.to_le_bytes().to_vec());
+        };
+        keys_builder.finish().written
+    };
+    println!("{} chunks contain document keys", number(keys_chunks.len()));
+    timing.record("build_keys_chunks", keys_chunks_start);
+
+    let documents_chunks_start = Instant::now();
+    println!("{} chunks contain documents", number(documents_chunks.len()));
+    documents_compressor.report("Documents");
+    let documents_dictionary = documents_compressor.write_dictionary(output.as_ref(), pack.as_mut(), "", "documents", chunk_naming);
+    // Only fields stored by every input are stored, as other fields would be missing for the documents of some inputs.
+    let mut fields = Vec::new();
+    for (field, field_name) in inputs[0].1.fields.iter().map(|f| &f.name).filter(|name| inputs.iter().all(|(_, m)| m.fields.iter().any(|f| &f.name == *name))).enumerate() {
+        let mut fields_builder = chunks_output::<ChunkU32Key>(output.as_ref(), pack.as_mut(), data_store, &format!("fields/{}", field), chunk_size, chunk_naming, None);
+        for ((dir, manifest), offset) in inputs.iter().zip(offsets.iter().copied()) {
+            let input_field = manifest.fields.iter().position(|f| &f.name == field_name).expect("input does not store field");
+            for (key, value) in read_chunks::<ChunkU32Key>(dir, &format!("fields/{}", input_field), &manifest.fields[input_field].chunks) {
+                let document_id = match key.key() {
+                    ChunkKey::U32(document_id) => *document_id as usize,
+                    ChunkKey::Str(_) => unreachable!(),
+                };
+                fields_builder.insert(ChunkU32Key::new((offset + document_id).try_into().expect("too many documents")), value);
+            };
+        };
+        let chunks = fields_builder.finish().written;
+        println!("{} chunks contain field {:?}", number(chunks.len()), field_name);
+        fields.push(ManifestField { name: field_name.clone(), chunks });
+    };
+    let mut summary_schema = if generate_clients && has_summaries { Some(DocumentSchema::default()) } else { None };
+    let mut summaries_dictionary = None;
+    let summaries_chunks = if has_summaries {
+        let mut summaries_builder = chunks_output::<ChunkU32Key>(output.as_ref(), pack.as_mut(), data_store, "summaries", chunk_size, chunk_naming, None);
+        let mut summaries_compressor = ValueCompressor::new(compression, read_samples(&inputs, "summaries"));
+        for ((dir, manifest), offset) in inputs.iter().zip(offsets.iter().copied()) {
+            let summaries_decompressor = input_decompressor(dir, manifest, "summaries", manifest.summaries_dictionary.as_ref());
+            let summaries = read_chunks::<ChunkU32Key>(dir, "summaries", manifest.summaries_chunks.as_deref().unwrap_or(&[])).map(|(key, summary)| {
+                let document_id = match key.key() {
+                    ChunkKey::U32(document_id) => *document_id as usize,
+                    ChunkKey::Str(_) => unreachable!(),
+                };
+                let summary = summaries_decompressor.decompress(summary);
+                if let Some(schema) = &mut summary_schema {
+                    schema.add(&serde_json::from_slice(&summary).expect("parse summary as JSON"));
+                };
+                (offset + document_id, summary)
+            });
+            for (document_id, entry) in summaries_compressor.compress_all(summaries) {
+                summaries_builder.insert(ChunkU32Key::new(document_id.try_into().expect("too many documents")), entry);
+            };
+        };
+        let chunks = summaries_builder.finish().written;
+        println!("{} chunks contain summaries", number(chunks.len()));
+        summaries_compressor.report("Summaries");
+        summaries_dictionary = summaries_compressor.write_dictionary(output.as_ref(), pack.as_mut(), "", "summaries", chunk_naming);
+        Some(chunks)
+    } else {
+        None
+    };
+    let deleted = if has_deleted { Some(write_deleted(output.as_ref(), pack.as_mut(), "", deleted, chunk_naming)) } else { None };
+    let embedded_data = finish_pack(output.as_ref(), pack, data_store, "");
+    timing.record("build_documents_chunks", documents_chunks_start);
+
+    Manifest {
+        edgesearch_version: env!("CARGO_PKG_VERSION").to_string(),
+        format_version: FORMAT_VERSION,
+        settings: ManifestSettings {
+            chunk_naming: chunk_naming.id().to_string(),
+            chunk_size,
+            compression: compression.id().to_string(),
+            data_store: data_store.id().to_string(),
+            data_store_url_prefix,
+            data_store_bucket_binding,
+            data_store_region,
+            target: target.id().to_string(),
+            worker_format: worker_format.id().to_string(),
+            maximum_query_results,
+            maximum_query_terms,
+        },
+        layer_prefix: "".to_string(),
+        delta_base: None,
+        document_count: offset,
+        term_count,
+        terms_chunks,
+        documents_chunks,
+        summaries_chunks,
+        keys_chunks,
+        fields,
+        deleted,
+        documents_dictionary,
+        summaries_dictionary,
+        indexes: Vec::new(),
+        shards: 0,
+        document_schema,
+        summary_schema,
+        timing,
+        embedded_data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use crate::build::{BuildConfig, ChunkNaming, Compression, DataStore, DirectoryOutput, Target, WorkerFormat};
+    use crate::build::bitmap::bitmap::Bitmap;
+    use crate::build::chunks::{ChunkEntryKey, ChunkKey, ChunkStrKey};
+    use crate::build::merge::{merge_layer, MergeConfig, read_chunks, read_keys};
+    use crate::build::testing::{build_test_layer, DOCUMENTS, edit_manifest, read_u32_entries, TERMS, TestDir};
+
+    fn merge_config(dir: &TestDir, input_dirs: Vec<PathBuf>, output: &str) -> MergeConfig {
+        MergeConfig {
+            chunk_naming: ChunkNaming::Index,
+            chunk_size: 64,
+            compression: Compression::None,
+            data_store: DataStore::KV,
+            data_store_url_prefix: None,
+            data_store_bucket_binding: None,
+            data_store_region: None,
+            target: Target::Worker,
+            worker_format: WorkerFormat::Service,
+            input_dirs,
+            maximum_query_results: 50,
+            maximum_query_terms: 50,
+            generate_clients: false,
+            output: Arc::new(DirectoryOutput::new(&dir.path(output))),
+            threads: 1,
+        }
+    }
+
+    #[test]
+    fn inputs_are_offset_by_their_document_count() {
+        let dir = TestDir::new();
+        build_test_layer(dir.config("input", DOCUMENTS, TERMS));
+        let merged = merge_layer(merge_config(&dir, vec![dir.path("input"), dir.path("input")], "merged"));
+        assert_eq!(merged.document_count, 8);
+        let documents = read_u32_entries(&dir.path("merged"), "documents", &merged.documents_chunks);
+        assert_eq!(documents.iter().map(|(id, _)| *id).collect::<Vec<_>>(), (0..8).collect::<Vec<_>>());
+        assert_eq!(documents[7].1, br#"{"a":4}"#.to_vec());
+        let terms = read_chunks::<ChunkStrKey>(&dir.path("merged"), "terms", &merged.terms_chunks).map(|(key, bitmap)| match key.key() {
+            ChunkKey::Str(term) => (term.clone(), Bitmap::deserialize(&bitmap).to_vec()),
+            ChunkKey::U32(_) => unreachable!(),
+        }).collect::<Vec<_>>();
+        assert_eq!(terms, vec![("x".to_string(), vec![0, 1, 4, 5]), ("y".to_string(), vec![0, 2, 4, 6])]);
+    }
+
+    #[test]
+    fn deleted_documents_are_offset() {
+        let dir = TestDir::new();
+        build_test_layer(BuildConfig {
+            deleted_documents_source: Some(dir.input("first.deleted", &["1"])),
+            ..dir.config("first", DOCUMENTS, TERMS)
+        });
+        build_test_layer(BuildConfig {
+            deleted_documents_source: Some(dir.input("second.deleted", &["0", "3"])),
+            ..dir.config("second", DOCUMENTS, TERMS)
+        });
+        let merged = merge_layer(merge_config(&dir, vec![dir.path("first"), dir.path("second")], "merged"));
+        let deleted = merged.deleted.expect("merged inputs have deleted documents");
+        let bitmap = read(dir.path("merged").join("deleted").join(&deleted.name)).unwrap();
+        assert_eq!(Bitmap::deserialize(&bitmap).to_vec(), vec![1, 4, 7]);
+    }
+
+    #[test]
+    fn keys_are_offset() {
+        let dir = TestDir::new();
+        build_test_layer(BuildConfig {
+            document_keys_source: Some(dir.input("first.keys", &["a0", "a1", "a2", "a3"])),
+            ..dir.config("first", DOCUMENTS, TERMS)
+        });
+        build_test_layer(BuildConfig {
+            document_keys_source: Some(dir.input("second.keys", &["b0", "b1", "b2", "b3"])),
+            ..dir.config("second", DOCUMENTS, TERMS)
+        });
+        let merged = merge_layer(merge_config(&dir, vec![dir.path("first"), dir.path("second")], "merged"));
+        let keys = read_keys(&dir.path("merged"), &merged.keys_chunks).collect::<Vec<_>>();
+        assert_eq!(keys.iter().map(|(key, document_id)| (key.as_str(), *document_id)).collect::<Vec<_>>(), vec![
+            ("a0", 0), ("a1", 1), ("a2", 2), ("a3", 3),
+            ("b0", 4), ("b1", 5), ("b2", 6), ("b3", 7),
+        ]);
+    }
+
+    #[test]
+    fn compressed_inputs_are_decompressed() {
+        let dir = TestDir::new();
+        build_test_layer(dir.config("first", DOCUMENTS, TERMS));
+        build_test_layer(BuildConfig { compression: Compression::Deflate, ..dir.config("second", DOCUMENTS, TERMS) });
+        let merged = merge_layer(merge_config(&dir, vec![dir.path("first"), dir.path("second")], "merged"));
+        let documents = read_u32_entries(&dir.path("merged"), "documents", &merged.documents_chunks);
+        assert_eq!(documents.iter().map(|(_, document)| document.as_slice()).collect::<Vec<_>>(), DOCUMENTS.iter().chain(DOCUMENTS).map(|d| d.as_bytes()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[should_panic(expected = "is a sharded build and cannot be merged")]
+    fn sharded_inputs_are_rejected() {
+        let dir = TestDir::new();
+        build_test_layer(dir.config("input", DOCUMENTS, TERMS));
+        edit_manifest(&dir.path("input"), |manifest| manifest.shards = 2);
+        merge_layer(merge_config(&dir, vec![dir.path("input")], "merged"));
+    }
+
+    #[test]
+    #[should_panic(expected = "is a build of named indexes and cannot be merged")]
+    fn named_index_inputs_are_rejected() {
+        let dir = TestDir::new();
+        build_test_layer(dir.config("input", DOCUMENTS, TERMS));
+        edit_manifest(&dir.path("input"), |manifest| manifest.indexes = vec!["a".to_string()]);
+        merge_layer(merge_config(&dir, vec![dir.path("input")], "merged"));
+    }
+}
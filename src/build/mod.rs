@@ -1,17 +1,32 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::fs::{create_dir, File, remove_dir_all};
-use std::io::Write;
-use std::path::PathBuf;
+use std::fs::{File, read};
+use std::io::{Seek, SeekFrom};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
 use clap::arg_enum;
+use rayon::prelude::*;
+use serde_json::value::RawValue;
 use bitmap::bitmap::Bitmap;
 
-use crate::{Term, TermId};
-use crate::build::chunks::{ChunkStrKey, ChunkU32Key};
+use crate::{DocumentId, Term, TermId};
+use crate::build::chunks::{ChunkEntryKey, ChunkSink, ChunkStrKey, ChunkU32Key, SerialisedChunk};
 use crate::build::chunks::bst::BstChunks;
+use crate::build::client::{ClientIndex, generate_clients};
+use crate::build::dictionary::{DeflateDictionary, inflate_with_dictionary, SAMPLES_SIZE, train_dictionary};
+use crate::build::external::{ExternalPostingsSorter, SpilledValues};
 use crate::build::js::generate_worker_js;
+use crate::build::merge::read_keys;
+use crate::build::manifest::{FORMAT_VERSION, Manifest, ManifestChunk, ManifestDeleted, ManifestDeltaBase, ManifestDictionary, ManifestField, ManifestSettings, ManifestTiming, WorkerIndex};
+use crate::build::output::ScratchDir;
+use crate::build::pack::{PACKED_FILE_NAME, PackWriter};
+use crate::build::schema::DocumentSchema;
 use crate::build::wasm::generate_and_compile_runner_wasm;
+use crate::data::document_ids::DocumentIdsReader;
+use crate::data::document_keys::DocumentKeysReader;
 use crate::data::document_terms::DocumentTermsReader;
 use crate::data::documents::DocumentsReader;
 use crate::util::format::{number, percent};
@@ -19,37 +34,560 @@ use crate::util::log::status_log_interval;
 
 mod js;
 mod chunks;
+mod client;
+mod dictionary;
 mod wasm;
 mod bitmap;
+mod external;
+mod manifest;
+mod merge;
+mod named;
+mod shard;
+mod output;
+mod pack;
+mod schema;
+#[cfg(test)]
+mod testing;
+
+pub use merge::{merge, MergeConfig};
+pub use named::build_named;
+pub use output::{DirectoryOutput, MemoryOutput, OutputSink, TarOutput};
 
 arg_enum! {
+    #[derive(Clone, Copy)]
     pub enum DataStore {
         URL,
         KV,
+        // Chunks of each layer are concatenated into one `index.bin` served from the URL prefix, and entries are fetched using Range requests.
+        Packed,
+        // Chunks are read from a Cloudflare R2 bucket binding, with the URL prefix (if any) as the prefix of object keys.
+        R2,
+        // Chunks are read from an S3-compatible bucket at the URL prefix (e.g. `https://s3.us-east-1.amazonaws.com/my-bucket/`) using signed requests.
+        S3,
+        // Chunks of each layer are packed as for `Packed` and embedded in the runner, so that lookups never leave the worker.
+        Embedded,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy)]
+    pub enum Target {
+        // A Cloudflare worker, written to `worker.js`.
+        Worker,
+        // An ES module that searches in the browser without a server, written to `browser.js`. Chunks are fetched from the URL prefix, resolved relative to the module.
+        Browser,
+        // An ES module HTTP server for Deno and Node.js, written to `server.mjs`. Chunks are read from the output folder next to the module, or fetched from the URL prefix if provided.
+        Server,
+    }
+}
+
+impl Target {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Target::Worker => "worker",
+            Target::Browser => "browser",
+            Target::Server => "server",
+        }
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy)]
+    pub enum WorkerFormat {
+        // A service worker that handles `fetch` events, with bindings as globals.
+        Service,
+        // An ES module worker that exports a `fetch` handler, with bindings on `env` and the runner imported from `./runner.wasm`.
+        Module,
+    }
+}
+
+impl WorkerFormat {
+    pub fn id(&self) -> &'static str {
+        match self {
+            WorkerFormat::Service => "service",
+            WorkerFormat::Module => "module",
+        }
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy)]
+    pub enum ChunkNaming {
+        // Chunks are named by their position, e.g. `terms/0`, `terms/1`.
+        Index,
+        // Chunks are named by the hash of their contents, so multiple builds can share a data store without overwriting each other.
+        Hash,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy)]
+    pub enum ShardBy {
+        // Consecutive ranges of document IDs are assigned to each shard.
+        Range,
+        // Documents are assigned to shards by a hash of their ID.
+        Hash,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy)]
+    pub enum Compression {
+        None,
+        // Each document and summary is compressed on its own using raw DEFLATE with a dictionary trained on samples of the layer's documents
+        // or summaries, so that entries can still be found and fetched individually and are decompressed by the worker using `DecompressionStream`.
+        Deflate,
+    }
+}
+
+impl Compression {
+    // Keep in sync with Layer.compression in script/src/search.ts.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate",
+        }
+    }
+}
+
+// Compressed values start with one of these, as values that DEFLATE can't make smaller are stored as they are.
+// Keep in sync with decompress in script/src/search.ts.
+const ENTRY_UNCOMPRESSED: u8 = 0;
+const ENTRY_DEFLATE: u8 = 1;
+
+// Amount of values compressed in parallel at a time.
+const COMPRESS_BATCH_SIZE: usize = 1024;
+
+// Compresses values before they're inserted into chunks, and counts their total size before and after compression for the build summary.
+struct ValueCompressor {
+    // None if values aren't compressed.
+    dictionary: Option<DeflateDictionary>,
+    uncompressed_size: usize,
+    compressed_size: usize,
+}
+
+impl ValueCompressor {
+    // Values are compressed with a dictionary trained on the first `samples`, which are only read if compressing.
+    fn new<I: Iterator<Item = Vec<u8>>>(compression: Compression, samples: I) -> ValueCompressor {
+        let dictionary = match compression {
+            Compression::None => None,
+            Compression::Deflate => {
+                let mut samples_size = 0;
+                let samples = samples.take_while(|sample| {
+                    samples_size += sample.len();
+                    samples_size <= SAMPLES_SIZE
+                }).collect::<Vec<_>>();
+                Some(DeflateDictionary::new(&train_dictionary(&samples)))
+            }
+        };
+        ValueCompressor { dictionary, uncompressed_size: 0, compressed_size: 0 }
+    }
+
+    // Compress `values`, which are keyed by `K`, in order. Each batch of values is compressed in parallel, with one compressor per thread.
+    fn compress_all<'c, K: Send + 'c>(&'c mut self, mut values: impl Iterator<Item = (K, Vec<u8>)> + 'c) -> impl Iterator<Item = (K, Vec<u8>)> + 'c {
+        iter::from_fn(move || {
+            let batch = values.by_ref().take(COMPRESS_BATCH_SIZE).collect::<Vec<_>>();
+            if batch.is_empty() { None } else { Some(self.compress_batch(batch)) }
+        }).flatten()
+    }
+
+    fn compress_batch<K: Send>(&mut self, batch: Vec<(K, Vec<u8>)>) -> Vec<(K, Vec<u8>)> {
+        self.uncompressed_size += batch.iter().map(|(_, value)| value.len()).sum::<usize>();
+        let entries = match &self.dictionary {
+            None => batch,
+            Some(dictionary) => batch.into_par_iter().map_init(|| dictionary.compressor(), |compressor, (key, value)| {
+                let compressed = dictionary.compress(compressor, &value);
+                let (flag, data) = if compressed.len() < value.len() { (ENTRY_DEFLATE, compressed) } else { (ENTRY_UNCOMPRESSED, value) };
+                let mut entry = Vec::with_capacity(1 + data.len());
+                entry.push(flag);
+                entry.extend(data);
+                (key, entry)
+            }).collect(),
+        };
+        self.compressed_size += entries.iter().map(|(_, entry)| entry.len()).sum::<usize>();
+        entries
+    }
+
+    // Print the compression ratio of the values, which are `kind`, unless they aren't compressed.
+    fn report(&self, kind: &str) {
+        if let Some(dictionary) = &self.dictionary {
+            println!(
+                "{} are compressed from {} to {} bytes ({}) with a dictionary of {} bytes",
+                kind,
+                number(self.uncompressed_size),
+                number(self.compressed_size),
+                percent(self.compressed_size as f64 / self.uncompressed_size.max(1) as f64),
+                number(dictionary.dictionary_size()),
+            );
+        };
+    }
+
+    // Write the dictionary, if any, to `{layer_prefix}{kind}-dictionary/`, or append it to `pack` if packing.
+    fn write_dictionary(&self, output: &dyn OutputSink, pack: Option<&mut PackWriter>, layer_prefix: &str, kind: &str, chunk_naming: ChunkNaming) -> Option<ManifestDictionary> {
+        self.dictionary.as_ref().map(|dictionary| {
+            let (name, hash, offset) = write_layer_file(output, pack, &format!("{}{}-dictionary", layer_prefix, kind), dictionary.compressed(), chunk_naming);
+            ManifestDictionary { name, size: dictionary.compressed().len(), hash, dictionary_size: dictionary.dictionary_size(), offset }
+        })
+    }
+}
+
+// Decompresses values of a layer compressed by ValueCompressor.
+struct ValueDecompressor {
+    // The compressed dictionary and its size, if values are compressed.
+    dictionary: Option<(Vec<u8>, usize)>,
+}
+
+impl ValueDecompressor {
+    fn new(compression: Compression, dictionary: Option<(Vec<u8>, usize)>) -> ValueDecompressor {
+        if let Compression::Deflate = compression {
+            assert!(dictionary.is_some(), "compressed layer has no dictionary");
+        };
+        ValueDecompressor { dictionary }
+    }
+
+    fn decompress(&self, value: Vec<u8>) -> Vec<u8> {
+        match &self.dictionary {
+            None => value,
+            Some((compressed_dictionary, dictionary_size)) => match value[0] {
+                ENTRY_UNCOMPRESSED => value[1..].to_vec(),
+                ENTRY_DEFLATE => inflate_with_dictionary(compressed_dictionary, *dictionary_size, &value[1..]),
+                flag => panic!("unknown compressed entry flag {}", flag),
+            },
+        }
+    }
+}
+
+impl ChunkNaming {
+    pub fn id(&self) -> &'static str {
+        match self {
+            ChunkNaming::Index => "index",
+            ChunkNaming::Hash => "hash",
+        }
+    }
+}
+
+impl DataStore {
+    // Keep in sync with DATA_STORE in script/src/search.ts.
+    pub fn id(&self) -> &'static str {
+        match self {
+            DataStore::KV => "kv",
+            DataStore::URL => "url",
+            DataStore::Packed => "packed",
+            DataStore::R2 => "r2",
+            DataStore::S3 => "s3",
+            DataStore::Embedded => "embedded",
+        }
+    }
+
+    // Whether chunks of each layer are concatenated into one packed file (see build::pack).
+    pub fn is_packed(&self) -> bool {
+        match self {
+            DataStore::Packed | DataStore::Embedded => true,
+            DataStore::URL | DataStore::KV | DataStore::R2 | DataStore::S3 => false,
+        }
+    }
+
+    // Whether the data store supports Range requests, so that chunks need secondary indexes to fetch only the range containing an entry.
+    pub fn supports_ranges(&self) -> bool {
+        match self {
+            DataStore::KV | DataStore::Embedded => false,
+            DataStore::URL | DataStore::Packed | DataStore::R2 | DataStore::S3 => true,
+        }
     }
 }
 
 pub struct BuildConfig {
+    pub chunk_naming: ChunkNaming,
     pub chunk_size: usize,
+    // Compression of documents and summaries in chunks. Terms, keys, and stored fields are never compressed.
+    pub compression: Compression,
     pub data_store: DataStore,
     pub data_store_url_prefix: Option<String>,
+    // Name of the worker's R2 bucket binding, required for the R2 data store.
+    pub data_store_bucket_binding: Option<String>,
+    // Region of the bucket, required for the S3 data store.
+    pub data_store_region: Option<String>,
+    pub target: Target,
+    pub worker_format: WorkerFormat,
     pub document_terms_source: File,
     pub documents_source: File,
+    // External keys of documents, such as primary keys, in the same order as the documents. Every document must have a unique key.
+    // For delta builds, documents of the base with the same key as a document in the delta are replaced by it.
+    pub document_keys_source: Option<File>,
+    // Summaries of documents in the same order and format as the documents, such as only the properties shown on a results page.
+    // If provided, search results return summaries, and documents are only returned when requested by ID or key.
+    pub document_summaries_source: Option<File>,
+    // Top-level fields of documents to also store in their own chunks, so that searches returning only some fields fetch less data.
+    pub stored_fields: Vec<String>,
     pub maximum_query_results: usize,
     pub maximum_query_terms: usize,
-    pub output_dir: PathBuf,
+    // Infer a schema from the documents, and write typed clients for it to `client/` of the output.
+    pub generate_clients: bool,
+    // Where the chunks, worker, runner, and manifest are written to.
+    pub output: Arc<dyn OutputSink>,
+    // Folder to create temporary folders in for intermediate files, such as spilled runs and shard inputs. Defaults to the system temporary folder.
+    pub scratch_dir: Option<PathBuf>,
+    // Output directory of a previous build to keep chunk boundaries stable with, so that unchanged chunks are byte-identical and can be reused.
+    pub previous_output_dir: Option<PathBuf>,
+    // Output directory of a base build to build a delta index on top of. Document IDs continue from the base,
+    // and the generated worker and runner search both the base and the delta.
+    pub delta_base_dir: Option<PathBuf>,
+    // IDs of documents to exclude from all results. For delta builds, these can also be IDs of documents in the base.
+    pub deleted_documents_source: Option<File>,
+    // Keys of documents to exclude from all results, resolved using the keys of this build and its delta base.
+    pub deleted_document_keys_source: Option<File>,
+    // Approximate bytes of document terms to hold in memory at once. If set, terms are sorted in runs spilled to the scratch folder instead of
+    // held in memory, and chunks are written as soon as they are full, so that corpora larger than memory can be built.
+    pub memory_limit: Option<usize>,
+    // Amount of threads to use for building; 0 uses one per CPU. The output is the same regardless of the amount of threads.
+    pub threads: usize,
+    // Amount of indexes to split documents into. Each shard is deployed as its own worker, and a coordinator worker searches all of them.
+    pub shards: usize,
+    pub shard_by: ShardBy,
+    // URL of a shard worker, with `{shard}` replaced by the shard number. Required if there is more than one shard.
+    pub shard_url_template: Option<String>,
 }
 
-pub fn build(BuildConfig {
-    chunk_size,
-    data_store,
-    data_store_url_prefix,
-    document_terms_source,
-    documents_source,
-    maximum_query_results,
-    maximum_query_terms,
-    output_dir,
-}: BuildConfig) -> () {
+// Chunks of delta builds are stored under this prefix so that they can coexist with chunks of the base.
+const DELTA_LAYER_PREFIX: &str = "delta/";
+
+// Writes chunks from BstChunks to a folder of the output, or appends them to the layer's packed file, and records them for the manifest.
+// If enabled, the secondary index of each chunk is written to `{name}.index` next to it, or appended after it in the packed file.
+struct ChunksOutput<'o, 'p> {
+    output: &'o dyn OutputSink,
+    pack: Option<&'o mut PackWriter>,
+    secondary_indexes: bool,
+    dir: String,
+    // (name, hash) of chunks of the same kind from the previous build, to detect reused chunks with.
+    previous: HashSet<(&'p str, &'p str)>,
+    // Set for incremental builds with index naming.
+    stable_names: Option<StableChunkNames>,
+    written: Vec<ManifestChunk>,
+}
+
+// Names chunks of an incremental build that uses index naming, so that a change doesn't rename every chunk after it.
+// Chunks identical to a chunk of the previous build keep its name, and other chunks are given names that the previous build didn't use.
+struct StableChunkNames {
+    // hash => name.
+    previous: HashMap<String, String>,
+    next_name: usize,
+}
+
+impl StableChunkNames {
+    fn new(previous: &[ManifestChunk]) -> StableChunkNames {
+        StableChunkNames {
+            previous: previous.iter().map(|c| (c.hash.clone(), c.name.clone())).collect(),
+            next_name: previous.iter().filter_map(|c| c.name.parse::<usize>().ok()).map(|n| n + 1).max().unwrap_or(0).max(previous.len()),
+        }
+    }
+
+    fn name(&mut self, chunk: &SerialisedChunk) -> String {
+        match self.previous.get(&chunk.hash) {
+            Some(name) => name.clone(),
+            None => {
+                self.next_name += 1;
+                format!("{}", self.next_name - 1)
+            }
+        }
+    }
+}
+
+impl<'o, 'p> ChunkSink for ChunksOutput<'o, 'p> {
+    fn write(&mut self, mut chunk: SerialisedChunk) {
+        if let Some(stable_names) = &mut self.stable_names {
+            chunk.name = stable_names.name(&chunk);
+        };
+        let reused = self.previous.contains(&(chunk.name.as_str(), chunk.hash.as_str()));
+        let mut written = ManifestChunk::for_chunk(&chunk, reused);
+        if self.secondary_indexes {
+            written.secondary_index_size = Some(chunk.secondary_index.len());
+        };
+        match &mut self.pack {
+            Some(pack) => {
+                written.offset = Some(pack.append(&chunk.data));
+                if self.secondary_indexes {
+                    pack.append(&chunk.secondary_index);
+                };
+            }
+            None => {
+                let mut files = vec![(format!("{}/{}", self.dir, chunk.name), chunk.data.as_slice())];
+                if self.secondary_indexes {
+                    files.push((format!("{}/{}.index", self.dir, chunk.name), chunk.secondary_index.as_slice()));
+                };
+                self.output.write_all(&files);
+            }
+        };
+        self.written.push(written);
+    }
+}
+
+// Clear the folder `dir` of the output, or use `pack` if packing, and create a BstChunks that writes to it and keeps boundaries stable with `previous`.
+fn chunks_output<'o, 'p, K: ChunkEntryKey>(
+    output: &'o dyn OutputSink,
+    pack: Option<&'o mut PackWriter>,
+    data_store: DataStore,
+    dir: &str,
+    chunk_size: usize,
+    naming: ChunkNaming,
+    previous: Option<&'p [ManifestChunk]>,
+) -> BstChunks<K, ChunksOutput<'o, 'p>> {
+    if pack.is_none() {
+        output.clear(dir);
+    };
+    BstChunks::new(
+        chunk_size,
+        previous.map_or_else(Vec::new, ManifestChunk::boundaries),
+        naming,
+        ChunksOutput {
+            output,
+            pack,
+            secondary_indexes: data_store.supports_ranges(),
+            dir: dir.to_string(),
+            previous: previous.unwrap_or(&[]).iter().map(|c| (c.name.as_str(), c.hash.as_str())).collect(),
+            stable_names: match (naming, previous) {
+                (ChunkNaming::Index, Some(previous)) => Some(StableChunkNames::new(previous)),
+                _ => None,
+            },
+            written: Vec::new(),
+        },
+    )
+}
+
+// Create a compressor for the documents or summaries of `source`, training its dictionary on the first of them if compressing,
+// and rewind `source` so that all of them can then be read.
+fn input_compressor(compression: Compression, source: &mut File) -> ValueCompressor {
+    let start = source.stream_position().expect("read input position");
+    let compressor = ValueCompressor::new(compression, DocumentsReader::new(source.try_clone().expect("open input")).map(|(_, value)| value.into_bytes()));
+    source.seek(SeekFrom::Start(start)).expect("rewind input");
+    compressor
+}
+
+// Write the only file of the folder `dir` of the output, or append it to `pack` if packing, and return its name, hash, and offset in the packed file.
+fn write_layer_file(output: &dyn OutputSink, pack: Option<&mut PackWriter>, dir: &str, data: &[u8], chunk_naming: ChunkNaming) -> (String, String, Option<usize>) {
+    let hash = SerialisedChunk::hash(data);
+    let name = match chunk_naming {
+        ChunkNaming::Index => "0".to_string(),
+        ChunkNaming::Hash => hash.clone(),
+    };
+    let offset = match pack {
+        Some(pack) => Some(pack.append(data)),
+        None => {
+            output.clear(dir);
+            output.write(&format!("{}/{}", dir, name), data);
+            None
+        }
+    };
+    (name, hash, offset)
+}
+
+// Write a bitmap of IDs of documents to exclude from all results to `{layer_prefix}deleted/`, or append it to `pack` if packing.
+fn write_deleted(output: &dyn OutputSink, pack: Option<&mut PackWriter>, layer_prefix: &str, mut bitmap: Bitmap, chunk_naming: ChunkNaming) -> ManifestDeleted {
+    bitmap.run_optimize();
+    let count = bitmap.len();
+    let data = bitmap.serialize();
+    println!("{} documents are deleted", number(count));
+    let (name, hash, offset) = write_layer_file(output, pack, &format!("{}deleted", layer_prefix), &data, chunk_naming);
+    ManifestDeleted { name, size: data.len(), hash, count, offset }
+}
+
+// Find the ID of the document with `key` in keys sorted by key.
+fn find_key(keys: &[(String, DocumentId)], key: &str) -> Option<DocumentId> {
+    keys.binary_search_by(|(k, _)| k.as_str().cmp(key)).ok().map(|i| keys[i].1)
+}
+
+// Maximum size of a Cloudflare worker's script and WebAssembly modules.
+// Checked for the embedded data store, as every chunk is part of the runner.
+const WORKER_SIZE_LIMIT: usize = 10 * 1024 * 1024;
+
+// Write the packed file of a layer, if the data store packs chunks, and return its contents if they're embedded in the runner.
+fn finish_pack(output: &dyn OutputSink, pack: Option<PackWriter>, data_store: DataStore, layer_prefix: &str) -> Option<Vec<u8>> {
+    match (pack, data_store) {
+        (Some(pack), DataStore::Embedded) => Some(pack.finish_and_read(output, layer_prefix)),
+        (Some(pack), _) => {
+            pack.finish(output, layer_prefix);
+            None
+        }
+        (None, _) => None,
+    }
+}
+
+// Generate a worker and runner that serve `indexes`.
+fn write_worker_and_runner(output: &dyn OutputSink, settings: &ManifestSettings, indexes: &[WorkerIndex]) {
+    let data_store = settings.data_store.parse::<DataStore>().expect("parse data store");
+    let target = settings.target.parse::<Target>().expect("parse target");
+    let worker_format = settings.worker_format.parse::<WorkerFormat>().expect("parse worker format");
+    if let (Target::Browser | Target::Server, WorkerFormat::Module) = (target, worker_format) {
+        panic!("the worker format can only be set for the worker target");
+    };
+    match data_store {
+        DataStore::R2 => assert!(settings.data_store_bucket_binding.is_some(), "R2 data store requires a bucket binding"),
+        DataStore::S3 => assert!(settings.data_store_url_prefix.is_some() && settings.data_store_region.is_some(), "S3 data store requires a URL prefix and region"),
+        _ => {}
+    };
+    // Browsers and servers can only fetch chunks over plain HTTP, read them from files, or read them from the runner.
+    if let (Target::Browser | Target::Server, DataStore::KV | DataStore::R2 | DataStore::S3) = (target, data_store) {
+        panic!("the {} target requires the url, packed, or embedded data store", target.id());
+    };
+    // Only workers have a size limit, so the checks are skipped for other targets.
+    let limited = matches!((target, data_store), (Target::Worker, DataStore::Embedded));
+    if limited {
+        let embedded_size = indexes.iter().flat_map(|index| index.layers.iter()).filter_map(|layer| layer.embedded_data).map(|data| data.len()).sum::<usize>();
+        // Fail before compiling the runner, which is slow for large embedded data.
+        assert!(embedded_size <= WORKER_SIZE_LIMIT, "embedded data is {} bytes, which exceeds the worker size limit of {} bytes; use a different data store", embedded_size, WORKER_SIZE_LIMIT);
+    };
+    let worker_size = generate_worker_js(output, target, worker_format, data_store, settings, indexes) + generate_and_compile_runner_wasm(
+        output,
+        settings.maximum_query_results,
+        settings.maximum_query_terms,
+        indexes,
+    );
+    if limited {
+        assert!(worker_size <= WORKER_SIZE_LIMIT, "worker script and runner with embedded data are {} bytes, which exceeds the worker size limit of {} bytes; use a different data store", worker_size, WORKER_SIZE_LIMIT);
+    };
+}
+
+// Generate the worker and runner for `manifest` layered on top of `base_layers`, and write the manifest.
+fn write_worker_runner_and_manifest(output: &dyn OutputSink, manifest: &mut Manifest, base_layers: &[&Manifest]) {
+    let worker_and_runner_start = Instant::now();
+    {
+        let mut layers = base_layers.iter().map(|m| m.layer()).collect::<Vec<_>>();
+        layers.push(manifest.layer());
+        write_worker_and_runner(output, &manifest.settings, &[WorkerIndex {
+            name: None,
+            document_count: manifest.document_count,
+            layers,
+        }]);
+    }
+    manifest.timing.record("generate_worker_and_runner", worker_and_runner_start);
+    if let Some(schema) = &manifest.document_schema {
+        let generate_clients_start = Instant::now();
+        let settings = &manifest.settings;
+        generate_clients(
+            output,
+            settings.target.parse::<Target>().expect("parse target"),
+            settings.maximum_query_results,
+            settings.maximum_query_terms,
+            &[ClientIndex {
+                name: None,
+                schema,
+                summary_schema: manifest.summary_schema.as_ref(),
+                has_keys: !manifest.keys_chunks.is_empty() || base_layers.iter().any(|base| !base.keys_chunks.is_empty()),
+            }],
+        );
+        manifest.timing.record("generate_clients", generate_clients_start);
+    };
+    manifest.timing.finish();
+    manifest.write(output, "");
+}
+
+// Build terms chunks by holding every term and bitmap in memory. Returns the amount of documents and terms.
+fn build_terms_chunks_in_memory(
+    document_terms_source: File,
+    first_document_id: usize,
+    timing: &mut ManifestTiming,
+    terms_chunks_builder: &mut BstChunks<ChunkStrKey, ChunksOutput>,
+) -> (usize, usize) {
+    let read_document_terms_start = Instant::now();
     // term_id => term.
     let mut terms = Vec::<Term>::new();
     // term => term_id.
@@ -89,69 +627,609 @@ pub fn build(BuildConfig {
     };
 
     let document_count = terms_by_document.len();
+    timing.record("read_document_terms", read_document_terms_start);
 
     let hash_log_interval = status_log_interval(document_count, 10);
     for (document_id, doc_terms) in terms_by_document.iter().enumerate() {
         interval_log!(hash_log_interval, document_id, document_count, "Processing documents ({})...");
         for term_id in doc_terms {
             // Add to the relevant postings list entry bitmap.
-            inverted_index[*term_id].add(document_id.try_into().expect("too many documents"));
+            inverted_index[*term_id].add((first_document_id + document_id).try_into().expect("too many documents"));
         };
     };
 
     println!("There are {} documents with {} terms", number(terms_by_document.len()), number(terms.len()));
 
-    let mut terms_index_builder = BstChunks::<ChunkStrKey>::new(chunk_size);
+    let terms_chunks_start = Instant::now();
     let mut terms_sorted = (0..terms.len()).collect::<Vec<TermId>>();
-    terms_sorted.sort_by(|a, b| terms[*a].cmp(&terms[*b]));
-    for term_id in terms_sorted.iter() {
-        let postings_list_entry = &mut inverted_index[*term_id];
+    terms_sorted.par_sort_by(|a, b| terms[*a].cmp(&terms[*b]));
+    // term_id => serialised bitmap.
+    let mut serialised_postings = inverted_index.into_par_iter().map(|mut postings_list_entry| {
         postings_list_entry.run_optimize();
-        let serialised = postings_list_entry.serialize();
-        terms_index_builder.insert(ChunkStrKey::new(&terms[*term_id]), serialised);
-    };
-    let (terms_index_raw_lookup, terms_index_serialised_entries) = terms_index_builder.serialise();
-    println!("{} chunks contain terms", number(terms_index_builder.chunk_count()));
-    let _ = remove_dir_all(output_dir.join("terms"));
-    create_dir(output_dir.join("terms")).expect("create terms chunks folder");
-    for (i, chunk) in terms_index_serialised_entries.iter().enumerate() {
-        let mut f = File::create(
-            output_dir.join("terms").join(format!("{}", i))
-        ).expect("open terms chunk file for writing");
-        f.write_all(chunk).expect("write terms chunk");
-    };
-
-    let mut documents_builder = BstChunks::<ChunkU32Key>::new(chunk_size);
-    for (document_id, document) in DocumentsReader::new(documents_source) {
-        documents_builder.insert(ChunkU32Key::new(document_id.try_into().expect("too many documents")), document.as_bytes().to_vec());
-    };
-    let (documents_raw_lookup, documents_serialised_entries) = documents_builder.serialise();
-    println!("{} chunks contain documents", number(documents_builder.chunk_count()));
-    let _ = remove_dir_all(output_dir.join("documents"));
-    create_dir(output_dir.join("documents")).expect("create documents chunks folder");
-    for (i, chunk) in documents_serialised_entries.iter().enumerate() {
-        let mut f = File::create(
-            output_dir.join("documents").join(format!("{}", i))
-        ).expect("open documents chunk file for writing");
-        f.write_all(chunk).expect("write documents chunk");
-    };
-
-    generate_worker_js(
-        &output_dir,
+        postings_list_entry.serialize()
+    }).collect::<Vec<_>>();
+    for term_id in terms_sorted.iter() {
+        let serialised = std::mem::take(&mut serialised_postings[*term_id]);
+        terms_chunks_builder.insert(ChunkStrKey::new(&terms[*term_id]), serialised);
+    };
+    timing.record("build_terms_chunks", terms_chunks_start);
+    (document_count, terms.len())
+}
+
+// Build terms chunks by sorting (term, document ID) pairs in runs of at most `memory_limit` bytes spilled to a folder in `scratch_dir`,
+// and merging them into bitmaps one term at a time. Produces the same chunks as build_terms_chunks_in_memory.
+fn build_terms_chunks_external(
+    document_terms_source: File,
+    first_document_id: usize,
+    scratch_dir: Option<&Path>,
+    memory_limit: usize,
+    timing: &mut ManifestTiming,
+    terms_chunks_builder: &mut BstChunks<ChunkStrKey, ChunksOutput>,
+) -> (usize, usize) {
+    let read_document_terms_start = Instant::now();
+    let scratch = ScratchDir::new(scratch_dir, "runs");
+    let mut sorter = ExternalPostingsSorter::new(scratch.path().join("runs"), memory_limit);
+    let mut document_count = 0;
+    for (document_id, term) in DocumentTermsReader::new(document_terms_source) {
+        document_count = document_id + 1;
+        sorter.add(term, (first_document_id + document_id).try_into().expect("too many documents"));
+    };
+    timing.record("read_document_terms", read_document_terms_start);
+
+    let terms_chunks_start = Instant::now();
+    // Bitmaps are serialised in batches in parallel, while still being inserted in term order.
+    let batch_size = rayon::current_num_threads() * 64;
+    let mut batch = Vec::<(Term, Bitmap)>::with_capacity(batch_size);
+    let mut write_batch = |batch: &mut Vec<(Term, Bitmap)>| {
+        let serialised = batch.par_drain(..).map(|(term, mut bitmap)| {
+            bitmap.run_optimize();
+            (term, bitmap.serialize())
+        }).collect::<Vec<_>>();
+        for (term, serialised) in serialised {
+            terms_chunks_builder.insert(ChunkStrKey::new(&term), serialised);
+        };
+    };
+    let term_count = sorter.merge(|term, bitmap| {
+        batch.push((term, bitmap));
+        if batch.len() >= batch_size {
+            write_batch(&mut batch);
+        };
+    });
+    write_batch(&mut batch);
+    println!("There are {} documents with {} terms", number(document_count), number(term_count));
+    timing.record("build_terms_chunks", terms_chunks_start);
+    (document_count, term_count)
+}
+
+// Run `f` with a thread pool of `threads` threads (or one per CPU if 0) for parallel build phases.
+fn with_threads<F: FnOnce() + Send>(threads: usize, f: F) {
+    rayon::ThreadPoolBuilder::new().num_threads(threads).build().expect("create thread pool").install(f)
+}
+
+pub fn build(config: BuildConfig) {
+    with_threads(config.threads, || if config.shards > 1 {
+        shard::build_shards(config);
+    } else {
+        build_index(config);
+    });
+}
+
+// Build an index and its worker and runner, and return its manifest.
+fn build_index(config: BuildConfig) -> Manifest {
+    let output = config.output.clone();
+    let (mut manifest, base_manifest) = build_layer(config, "");
+    // Layers are ordered from oldest to newest.
+    let mut layers = Vec::new();
+    layers.extend(base_manifest.as_ref());
+    write_worker_runner_and_manifest(output.as_ref(), &mut manifest, &layers);
+    println!("Build complete");
+    manifest
+}
+
+// Build the chunks of an index into `{index_prefix}` of the output, and return its manifest and the manifest of its delta base, if any.
+fn build_layer(BuildConfig {
+    chunk_naming,
+    chunk_size,
+    compression,
+    data_store,
+    data_store_url_prefix,
+    data_store_bucket_binding,
+    data_store_region,
+    target,
+    worker_format,
+    document_terms_source,
+    documents_source,
+    document_keys_source,
+    document_summaries_source,
+    stored_fields,
+    maximum_query_results,
+    maximum_query_terms,
+    generate_clients,
+    output,
+    scratch_dir,
+    previous_output_dir,
+    delta_base_dir,
+    deleted_documents_source,
+    deleted_document_keys_source,
+    memory_limit,
+    ..
+}: BuildConfig, index_prefix: &str) -> (Manifest, Option<Manifest>) {
+    let mut timing = ManifestTiming::start();
+
+    // Read before any output is written, as the previous output directory could be the same as the output directory.
+    let previous_manifest = previous_output_dir.map(|dir| Manifest::read(&dir));
+    if let Some(previous) = &previous_manifest {
+        // Otherwise, its chunk boundaries and names could have a different meaning.
+        assert_eq!(previous.format_version, FORMAT_VERSION, "previous output was built with a different data format");
+        assert_eq!(previous.shards, 0, "previous output must not be a sharded build; use it as the previous output of a sharded build instead");
+        assert!(previous.indexes.is_empty(), "previous output must not be a build of named indexes; use it as the previous output of a build of named indexes instead");
+    };
+    let base_manifest = delta_base_dir.as_ref().map(|dir| {
+        let mut base = Manifest::read(dir);
+        if let DataStore::Embedded = data_store {
+            base.embedded_data = Some(read(dir.join(PACKED_FILE_NAME)).expect("read packed file of base; the base must also use the embedded or packed data store"));
+        };
+        base
+    });
+    if let Some(base) = &base_manifest {
+        assert_eq!(base.format_version, FORMAT_VERSION, "base was built with a different data format");
+        assert!(base.delta_base.is_none(), "base must not be a delta");
+        // The manifests of sharded builds and builds of named indexes have no chunks, only the total amount of documents of their indexes.
+        assert_eq!(base.shards, 0, "base must not be a sharded build");
+        assert!(base.indexes.is_empty(), "base must not be a build of named indexes");
+        // Otherwise, search results would be summaries for some documents and whole documents for others.
+        assert_eq!(base.summaries_chunks.is_some(), document_summaries_source.is_some(), "a delta must have document summaries if and only if its base does");
+    };
+    let layer_prefix = format!("{}{}", index_prefix, if base_manifest.is_some() { DELTA_LAYER_PREFIX } else { "" });
+    // Document IDs of a delta continue from the base.
+    let first_document_id = base_manifest.as_ref().map_or(0, |base| base.document_count);
+    let mut pack = if data_store.is_packed() { Some(PackWriter::new(scratch_dir.as_deref())) } else { None };
+
+    let mut terms_chunks_builder = chunks_output::<ChunkStrKey>(
+        output.as_ref(),
+        pack.as_mut(),
         data_store,
-        data_store_url_prefix,
-        terms_by_document.len(),
-        maximum_query_terms,
-        maximum_query_results,
+        &format!("{}terms", layer_prefix),
+        chunk_size,
+        chunk_naming,
+        previous_manifest.as_ref().map(|m| m.terms_chunks.as_slice()),
     );
-    generate_and_compile_runner_wasm(
-        &output_dir,
-        maximum_query_results,
-        maximum_query_terms,
-        terms_index_raw_lookup.as_str(),
-        terms_index_serialised_entries.len(),
-        documents_raw_lookup.as_str(),
-        documents_serialised_entries.len(),
+    let (document_count, term_count) = match memory_limit {
+        Some(memory_limit) => build_terms_chunks_external(document_terms_source, first_document_id, scratch_dir.as_deref(), memory_limit, &mut timing, &mut terms_chunks_builder),
+        None => build_terms_chunks_in_memory(document_terms_source, first_document_id, &mut timing, &mut terms_chunks_builder),
+    };
+    let terms_chunks = terms_chunks_builder.finish().written;
+    println!("{} chunks contain terms", number(terms_chunks.len()));
+
+    let documents_chunks_start = Instant::now();
+    let mut documents_chunks_builder = chunks_output::<ChunkU32Key>(
+        output.as_ref(),
+        pack.as_mut(),
+        data_store,
+        &format!("{}documents", layer_prefix),
+        chunk_size,
+        chunk_naming,
+        previous_manifest.as_ref().map(|m| m.documents_chunks.as_slice()),
     );
-    println!("Build complete")
+    // Starts from the schema of the base, so that it describes every document searched.
+    let mut document_schema = if generate_clients {
+        Some(base_manifest.as_ref().map_or_else(DocumentSchema::default, |base| base.document_schema.clone().expect("base must also be built with clients generated")))
+    } else {
+        None
+    };
+    for (i, name) in stored_fields.iter().enumerate() {
+        assert!(!stored_fields[..i].contains(name), "field {:?} is stored more than once", name);
+    };
+    // Values of each stored field are spilled to the scratch folder as documents are read, and only inserted into chunks once the
+    // documents chunks are finished, as both can be written to the same packed file.
+    let fields_scratch = ScratchDir::new(scratch_dir.as_deref(), "fields");
+    let mut field_values = (0..stored_fields.len()).map(|field| SpilledValues::new(fields_scratch.path().join(field.to_string()))).collect::<Vec<_>>();
+    let mut documents_source = documents_source;
+    let mut documents_compressor = input_compressor(compression, &mut documents_source);
+    let mut documents_read = 0;
+    let documents = DocumentsReader::new(documents_source).map(|(document_id, document)| {
+        documents_read += 1;
+        let document_id: DocumentId = (first_document_id + document_id).try_into().expect("too many documents");
+        if let Some(schema) = &mut document_schema {
+            schema.add(&serde_json::from_str(&document).expect("parse document as JSON"));
+        };
+        // Values are stored as they appear in the document. Documents that aren't objects have no fields.
+        if !stored_fields.is_empty() {
+            if let Ok(properties) = serde_json::from_str::<HashMap<String, &RawValue>>(&document) {
+                for (name, values) in stored_fields.iter().zip(field_values.iter_mut()) {
+                    if let Some(value) = properties.get(name) {
+                        values.add(document_id, value.get().as_bytes());
+                    };
+                };
+            };
+        };
+        (document_id, document.into_bytes())
+    });
+    for (document_id, entry) in documents_compressor.compress_all(documents) {
+        documents_chunks_builder.insert(ChunkU32Key::new(document_id), entry);
+    };
+    let documents_chunks = documents_chunks_builder.finish().written;
+    println!("{} chunks contain documents", number(documents_chunks.len()));
+    documents_compressor.report("Documents");
+    let documents_dictionary = documents_compressor.write_dictionary(output.as_ref(), pack.as_mut(), &layer_prefix, "documents", chunk_naming);
+    let mut fields = Vec::new();
+    for (field, (name, values)) in stored_fields.into_iter().zip(field_values).enumerate() {
+        let previous_chunks = previous_manifest.as_ref().and_then(|m| m.fields.iter().find(|f| f.name == name)).map(|f| f.chunks.as_slice());
+        let mut fields_chunks_builder = chunks_output::<ChunkU32Key>(
+            output.as_ref(),
+            pack.as_mut(),
+            data_store,
+            &format!("{}fields/{}", layer_prefix, field),
+            chunk_size,
+            chunk_naming,
+            previous_chunks,
+        );
+        for (document_id, value) in values.read() {
+            fields_chunks_builder.insert(ChunkU32Key::new(document_id), value);
+        };
+        let chunks = fields_chunks_builder.finish().written;
+        println!("{} chunks contain field {:?}", number(chunks.len()), name);
+        fields.push(ManifestField { name, chunks });
+    };
+    drop(fields_scratch);
+    let mut summary_schema = None;
+    let mut summaries_dictionary = None;
+    let summaries_chunks = match document_summaries_source {
+        Some(mut source) => {
+            if generate_clients {
+                summary_schema = Some(base_manifest.as_ref().map_or_else(DocumentSchema::default, |base| base.summary_schema.clone().expect("base must also be built with clients generated")));
+            };
+            let mut summaries_chunks_builder = chunks_output::<ChunkU32Key>(
+                output.as_ref(),
+                pack.as_mut(),
+                data_store,
+                &format!("{}summaries", layer_prefix),
+                chunk_size,
+                chunk_naming,
+                previous_manifest.as_ref().and_then(|m| m.summaries_chunks.as_deref()),
+            );
+            let mut summaries_compressor = input_compressor(compression, &mut source);
+            let mut summaries_read = 0;
+            let summaries = DocumentsReader::new(source).map(|(document_id, summary)| {
+                summaries_read += 1;
+                if let Some(schema) = &mut summary_schema {
+                    schema.add(&serde_json::from_str(&summary).expect("parse summary as JSON"));
+                };
+                (document_id, summary.into_bytes())
+            });
+            for (document_id, entry) in summaries_compressor.compress_all(summaries) {
+                summaries_chunks_builder.insert(ChunkU32Key::new((first_document_id + document_id).try_into().expect("too many documents")), entry);
+            };
+            assert_eq!(summaries_read, documents_read, "every document must have a summary");
+            let summaries_chunks = summaries_chunks_builder.finish().written;
+            println!("{} chunks contain summaries", number(summaries_chunks.len()));
+            summaries_compressor.report("Summaries");
+            summaries_dictionary = summaries_compressor.write_dictionary(output.as_ref(), pack.as_mut(), &layer_prefix, "summaries", chunk_naming);
+            Some(summaries_chunks)
+        }
+        None => None,
+    };
+    timing.record("build_documents_chunks", documents_chunks_start);
+
+    let keys_chunks_start = Instant::now();
+    // (key, document ID) of this layer, sorted by key.
+    let mut keys = Vec::<(String, DocumentId)>::new();
+    let keys_chunks = match document_keys_source {
+        Some(source) => {
+            keys = DocumentKeysReader::new(source)
+                .map(|(document_id, key)| (key, (first_document_id + document_id).try_into().expect("too many documents")))
+                .collect();
+            assert_eq!(keys.len(), documents_read, "every document must have a key");
+            keys.par_sort_by(|a, b| a.0.cmp(&b.0));
+            if let Some(pair) = keys.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+                panic!("document key {:?} is used more than once", pair[0].0);
+            };
+            let mut keys_chunks_builder = chunks_output::<ChunkStrKey>(
+                output.as_ref(),
+                pack.as_mut(),
+                data_store,
+                &format!("{}keys", layer_prefix),
+                chunk_size,
+                chunk_naming,
+                previous_manifest.as_ref().map(|m| m.keys_chunks.as_slice()),
+            );
+            for (key, document_id) in keys.iter() {
+                keys_chunks_builder.insert(ChunkStrKey::new(key), document_id.to_le_bytes().to_vec());
+            };
+            let keys_chunks = keys_chunks_builder.finish().written;
+            println!("{} chunks contain document keys", number(keys_chunks.len()));
+            keys_chunks
+        }
+        None => Vec::new(),
+    };
+    // Keys of the base are only needed to replace or delete documents in it by key.
+    let base_keys = match (&delta_base_dir, &base_manifest) {
+        (Some(dir), Some(base)) if !keys.is_empty() || deleted_document_keys_source.is_some() => read_keys(dir, &base.keys_chunks).collect::<Vec<_>>(),
+        _ => Vec::new(),
+    };
+    // Documents of the base with the same key as a document of the delta are replaced by it.
+    let replaced = keys.iter().filter_map(|(key, _)| find_key(&base_keys, key)).collect::<Vec<_>>();
+    if !replaced.is_empty() {
+        println!("{} documents replace documents in the base", number(replaced.len()));
+    };
+    timing.record("build_keys_chunks", keys_chunks_start);
+
+    let deleted = if deleted_documents_source.is_some() || deleted_document_keys_source.is_some() || !replaced.is_empty() {
+        let mut bitmap = Bitmap::create();
+        for document_id in replaced {
+            bitmap.add(document_id);
+        };
+        if let Some(source) = deleted_documents_source {
+            for document_id in DocumentIdsReader::new(source) {
+                bitmap.add(document_id);
+            };
+        };
+        if let Some(source) = deleted_document_keys_source {
+            for (_, key) in DocumentKeysReader::new(source) {
+                // Keys of this layer take precedence, as they replace keys of the base.
+                let document_id = find_key(&keys, &key).or_else(|| find_key(&base_keys, &key));
+                bitmap.add(document_id.unwrap_or_else(|| panic!("deleted document key {:?} does not exist", key)));
+            };
+        };
+        Some(write_deleted(output.as_ref(), pack.as_mut(), &layer_prefix, bitmap, chunk_naming))
+    } else {
+        None
+    };
+    let embedded_data = finish_pack(output.as_ref(), pack, data_store, &layer_prefix);
+
+    if previous_manifest.is_some() {
+        println!(
+            "{} of {} terms chunks and {} of {} documents chunks are unchanged from the previous build",
+            number(terms_chunks.iter().filter(|c| c.reused).count()),
+            number(terms_chunks.len()),
+            number(documents_chunks.iter().filter(|c| c.reused).count()),
+            number(documents_chunks.len()),
+        );
+    };
+
+    let manifest = Manifest {
+        edgesearch_version: env!("CARGO_PKG_VERSION").to_string(),
+        format_version: FORMAT_VERSION,
+        settings: ManifestSettings {
+            chunk_naming: chunk_naming.id().to_string(),
+            chunk_size,
+            compression: compression.id().to_string(),
+            data_store: data_store.id().to_string(),
+            data_store_url_prefix,
+            data_store_bucket_binding,
+            data_store_region,
+            target: target.id().to_string(),
+            worker_format: worker_format.id().to_string(),
+            maximum_query_results,
+            maximum_query_terms,
+        },
+        layer_prefix,
+        delta_base: base_manifest.as_ref().map(|base| ManifestDeltaBase { document_count: base.document_count }),
+        // Trailing documents without terms aren't seen when reading terms, but are still documents.
+        document_count: first_document_id + document_count.max(documents_read),
+        term_count,
+        terms_chunks,
+        documents_chunks,
+        summaries_chunks,
+        keys_chunks,
+        fields,
+        deleted,
+        documents_dictionary,
+        summaries_dictionary,
+        indexes: Vec::new(),
+        shards: 0,
+        document_schema,
+        summary_schema,
+        timing,
+        embedded_data,
+    };
+    (manifest, base_manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read;
+
+    use crate::build::{BuildConfig, Compression, ENTRY_DEFLATE, ENTRY_UNCOMPRESSED, ValueDecompressor, with_threads};
+    use crate::build::bitmap::bitmap::Bitmap;
+    use crate::build::manifest::{Manifest, ManifestChunk};
+    use crate::build::merge::read_keys;
+    use crate::build::testing::{build_test_layer, DOCUMENTS, edit_manifest, numbered_documents, read_u32_entries, TERMS, TestDir};
+
+    #[test]
+    fn trailing_documents_without_terms_are_counted() {
+        for memory_limit in [None, Some(1)] {
+            let dir = TestDir::new();
+            let manifest = build_test_layer(BuildConfig { memory_limit, ..dir.config("base", DOCUMENTS, TERMS) });
+            assert_eq!(manifest.document_count, 4);
+            let documents = read_u32_entries(&dir.path("base"), "documents", &manifest.documents_chunks);
+            assert_eq!(documents.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        };
+    }
+
+    #[test]
+    fn delta_ids_continue_after_trailing_documents_without_terms() {
+        let dir = TestDir::new();
+        build_test_layer(dir.config("base", DOCUMENTS, TERMS));
+        let delta = build_test_layer(BuildConfig {
+            delta_base_dir: Some(dir.path("base")),
+            ..dir.config("delta", &[r#"{"a":5}"#], &[&["x"]])
+        });
+        assert_eq!(delta.document_count, 5);
+        let documents = read_u32_entries(&dir.path("delta").join("delta"), "documents", &delta.documents_chunks);
+        assert_eq!(documents, vec![(4, br#"{"a":5}"#.to_vec())]);
+    }
+
+    #[test]
+    fn stored_fields_have_values_of_documents_with_them() {
+        let dir = TestDir::new();
+        let documents = [r#"{"a":1,"b":"one"}"#, r#"{"b":["two"]}"#, "3", r#"{"a":{"c":4}}"#];
+        let manifest = build_test_layer(BuildConfig {
+            stored_fields: vec!["a".to_string(), "b".to_string()],
+            ..dir.config("base", &documents, TERMS)
+        });
+        // Chunks of each field are in `fields/{position}/`.
+        let values = manifest.fields.iter().enumerate()
+            .map(|(position, field)| (field.name.as_str(), read_u32_entries(&dir.path("base"), &format!("fields/{}", position), &field.chunks)))
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![
+            ("a", vec![(0, b"1".to_vec()), (3, br#"{"c":4}"#.to_vec())]),
+            ("b", vec![(0, br#""one""#.to_vec()), (1, br#"["two"]"#.to_vec())]),
+        ]);
+    }
+
+    #[test]
+    fn unchanged_chunks_keep_their_names_in_incremental_builds() {
+        let dir = TestDir::new();
+        let (mut documents, terms) = numbered_documents(20, |_| vec!["x"]);
+        let previous = build_test_layer(dir.config("previous", &documents, &terms));
+        assert!(previous.documents_chunks.len() > 2);
+        documents[19] = r#"{"i":"changed"}"#.to_string();
+        let current = build_test_layer(BuildConfig {
+            previous_output_dir: Some(dir.path("previous")),
+            ..dir.config("current", &documents, &terms)
+        });
+        let (last, unchanged) = current.documents_chunks.split_last().unwrap();
+        assert!(!last.reused);
+        for (chunk, previous_chunk) in unchanged.iter().zip(previous.documents_chunks.iter()) {
+            assert!(chunk.reused);
+            assert_eq!((&chunk.name, &chunk.hash), (&previous_chunk.name, &previous_chunk.hash));
+        };
+    }
+
+    #[test]
+    fn deleted_documents_are_written_as_a_bitmap() {
+        let dir = TestDir::new();
+        let manifest = build_test_layer(BuildConfig {
+            deleted_documents_source: Some(dir.input("base.deleted", &["3", "1"])),
+            ..dir.config("base", DOCUMENTS, TERMS)
+        });
+        let deleted = manifest.deleted.expect("build has deleted documents");
+        assert_eq!(deleted.count, 2);
+        let bitmap = read(dir.path("base").join("deleted").join(&deleted.name)).unwrap();
+        assert_eq!(Bitmap::deserialize(&bitmap).to_vec(), vec![1, 3]);
+    }
+
+    #[test]
+    fn compressed_documents_are_decompressed_with_the_layer_dictionary() {
+        let dir = TestDir::new();
+        let documents = (0..50).map(|i| format!(r#"{{"title":"Listing {}","location":"Sydney, New South Wales, Australia"}}"#, i)).chain(Some("1".to_string())).collect::<Vec<_>>();
+        let terms = documents.iter().map(|_| ["x"]).collect::<Vec<_>>();
+        let manifest = build_test_layer(BuildConfig {
+            compression: Compression::Deflate,
+            chunk_size: 1024,
+            ..dir.config("base", &documents, &terms)
+        });
+        let dictionary = manifest.documents_dictionary.expect("compressed layer has a dictionary");
+        let compressed_dictionary = read(dir.path("base").join("documents-dictionary").join(&dictionary.name)).unwrap();
+        assert_eq!(compressed_dictionary.len(), dictionary.size);
+        let decompressor = ValueDecompressor::new(Compression::Deflate, Some((compressed_dictionary, dictionary.dictionary_size)));
+        let entries = read_u32_entries(&dir.path("base"), "documents", &manifest.documents_chunks);
+        assert_eq!(entries.len(), documents.len());
+        for ((document_id, entry), document) in entries.into_iter().zip(documents.iter()) {
+            // Documents too short to shrink are stored as they are.
+            let flag = if document_id == 50 { ENTRY_UNCOMPRESSED } else { ENTRY_DEFLATE };
+            assert_eq!(entry[0], flag);
+            assert!(flag == ENTRY_UNCOMPRESSED || entry.len() < document.len());
+            assert_eq!(decompressor.decompress(entry), document.as_bytes());
+        };
+    }
+
+    #[test]
+    fn keys_map_to_their_documents_in_byte_order() {
+        let dir = TestDir::new();
+        let manifest = build_test_layer(BuildConfig {
+            document_keys_source: Some(dir.input("base.keys", &["z", "é", "Z", "a\u{1F600}"])),
+            ..dir.config("base", DOCUMENTS, TERMS)
+        });
+        let keys = read_keys(&dir.path("base"), &manifest.keys_chunks).collect::<Vec<_>>();
+        assert_eq!(keys, vec![
+            ("Z".to_string(), 2),
+            ("a\u{1F600}".to_string(), 3),
+            ("z".to_string(), 0),
+            ("é".to_string(), 1),
+        ]);
+    }
+
+    #[test]
+    fn deltas_delete_documents_of_their_base_by_id_key_and_replacement() {
+        let dir = TestDir::new();
+        build_test_layer(BuildConfig {
+            document_keys_source: Some(dir.input("base.keys", &["k0", "k1", "k2", "k3"])),
+            ..dir.config("base", DOCUMENTS, TERMS)
+        });
+        let delta = build_test_layer(BuildConfig {
+            delta_base_dir: Some(dir.path("base")),
+            document_keys_source: Some(dir.input("delta.keys", &["k1", "k4"])),
+            deleted_documents_source: Some(dir.input("delta.deleted", &["0"])),
+            // Deleting a key of the delta deletes the delta's document, not the base's.
+            deleted_document_keys_source: Some(dir.input("delta.deleted_keys", &["k2", "k4"])),
+            ..dir.config("delta", &[r#"{"a":5}"#, r#"{"a":6}"#], &[&["x"], &["y"]])
+        });
+        assert_eq!(delta.document_count, 6);
+        let layer_dir = dir.path("delta").join("delta");
+        let deleted = delta.deleted.expect("delta has deleted documents");
+        assert_eq!(deleted.count, 4);
+        let bitmap = read(layer_dir.join("deleted").join(&deleted.name)).unwrap();
+        // k1 is replaced by document 4 of the delta.
+        assert_eq!(Bitmap::deserialize(&bitmap).to_vec(), vec![0, 1, 2, 5]);
+        // Keys of the delta map to its own documents.
+        assert_eq!(read_keys(&layer_dir, &delta.keys_chunks).collect::<Vec<_>>(), vec![("k1".to_string(), 4), ("k4".to_string(), 5)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "base must not be a sharded build")]
+    fn sharded_bases_are_rejected() {
+        let dir = TestDir::new();
+        build_test_layer(dir.config("base", DOCUMENTS, TERMS));
+        edit_manifest(&dir.path("base"), |manifest| manifest.shards = 2);
+        build_test_layer(BuildConfig { delta_base_dir: Some(dir.path("base")), ..dir.config("delta", DOCUMENTS, TERMS) });
+    }
+
+    #[test]
+    #[should_panic(expected = "previous output must not be a sharded build")]
+    fn sharded_previous_outputs_are_rejected() {
+        let dir = TestDir::new();
+        build_test_layer(dir.config("previous", DOCUMENTS, TERMS));
+        edit_manifest(&dir.path("previous"), |manifest| manifest.shards = 2);
+        build_test_layer(BuildConfig { previous_output_dir: Some(dir.path("previous")), ..dir.config("current", DOCUMENTS, TERMS) });
+    }
+
+    #[test]
+    #[should_panic(expected = "base must not be a build of named indexes")]
+    fn named_index_bases_are_rejected() {
+        let dir = TestDir::new();
+        build_test_layer(dir.config("base", DOCUMENTS, TERMS));
+        edit_manifest(&dir.path("base"), |manifest| manifest.indexes = vec!["a".to_string()]);
+        build_test_layer(BuildConfig { delta_base_dir: Some(dir.path("base")), ..dir.config("delta", DOCUMENTS, TERMS) });
+    }
+
+    #[test]
+    fn builds_with_multiple_threads_are_identical() {
+        let dir = TestDir::new();
+        let (documents, terms) = numbered_documents(300, |i| vec!["a", ["x", "y", "z"][i % 3], ["p", "q"][i % 2]]);
+        let keys = (0..300).map(|i| format!("k{}", i)).collect::<Vec<_>>();
+        let chunks = |manifest: &Manifest| [&manifest.terms_chunks, &manifest.documents_chunks, &manifest.keys_chunks, &manifest.fields[0].chunks]
+            .iter()
+            .map(|chunks| chunks.iter().map(|ManifestChunk { name, hash, .. }| (name.clone(), hash.clone())).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        for memory_limit in [None, Some(256)] {
+            let mut outputs = Vec::new();
+            for threads in [1, 4] {
+                let output = format!("{}_{}", threads, memory_limit.is_some());
+                let config = BuildConfig {
+                    document_keys_source: Some(dir.input(&format!("{}.keys", output), &keys)),
+                    stored_fields: vec!["i".to_string()],
+                    memory_limit,
+                    threads,
+                    ..dir.config(&output, &documents, &terms)
+                };
+                let mut manifest = None;
+                with_threads(threads, || manifest = Some(build_test_layer(config)));
+                outputs.push(chunks(&manifest.unwrap()));
+            };
+            assert!(outputs[0][0].len() > 1 && outputs[0][1].len() > 1);
+            assert_eq!(outputs[0], outputs[1]);
+        };
+    }
 }
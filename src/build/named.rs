@@ -0,0 +1,81 @@
+use std::time::Instant;
+
+use crate::build::{build_layer, BuildConfig, Target, with_threads, write_worker_and_runner};
+use crate::build::client::{ClientIndex, generate_clients};
+use crate::build::manifest::{FORMAT_VERSION, Manifest, ManifestTiming, WorkerIndex};
+
+// Build multiple indexes into the output of the first index, each with its own chunks under `{name}/`, and generate one worker and runner that serve each at `/{name}/search`.
+pub fn build_named(indexes: Vec<(String, BuildConfig)>) {
+    assert!(!indexes.is_empty(), "there are no indexes to build");
+    with_threads(indexes[0].1.threads, || build_named_with_threads(indexes));
+}
+
+fn build_named_with_threads(indexes: Vec<(String, BuildConfig)>) {
+    let mut timing = ManifestTiming::start();
+    let output = indexes[0].1.output.clone();
+
+    let mut names = Vec::<String>::new();
+    let mut manifests = Vec::<Manifest>::new();
+    for (name, config) in indexes {
+        assert!(!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'), "index name {:?} must only contain letters, digits, hyphens, and underscores", name);
+        assert!(!names.contains(&name), "index name {:?} is used more than once", name);
+        assert!(config.delta_base_dir.is_none(), "named indexes cannot be deltas");
+        assert!(config.deleted_documents_source.is_none() && config.deleted_document_keys_source.is_none(), "named indexes cannot delete documents");
+        assert!(config.shards <= 1, "named indexes cannot be sharded");
+
+        println!("Building index {}...", name);
+        let index_prefix = format!("{}/", name);
+        let previous_output_dir = config.previous_output_dir.as_ref().map(|dir| dir.join(&name));
+        let (mut manifest, _) = build_layer(BuildConfig { output: output.clone(), previous_output_dir, ..config }, &index_prefix);
+        manifest.timing.finish();
+        manifest.write(output.as_ref(), &index_prefix);
+        names.push(name);
+        manifests.push(manifest);
+    };
+
+    let worker_and_runner_start = Instant::now();
+    let settings = manifests[0].settings.clone();
+    write_worker_and_runner(output.as_ref(), &settings, &names.iter().zip(manifests.iter()).map(|(name, manifest)| WorkerIndex {
+        name: Some(name),
+        document_count: manifest.document_count,
+        layers: vec![manifest.layer()],
+    }).collect::<Vec<_>>());
+    timing.record("generate_worker_and_runner", worker_and_runner_start);
+    // Every index has a schema if clients are generated.
+    if manifests[0].document_schema.is_some() {
+        let generate_clients_start = Instant::now();
+        generate_clients(output.as_ref(), settings.target.parse::<Target>().expect("parse target"), settings.maximum_query_results, settings.maximum_query_terms, &names.iter().zip(manifests.iter()).map(|(name, manifest)| ClientIndex {
+            name: Some(name),
+            schema: manifest.document_schema.as_ref().expect("index has no document schema"),
+            summary_schema: manifest.summary_schema.as_ref(),
+            has_keys: !manifest.keys_chunks.is_empty(),
+        }).collect::<Vec<_>>());
+        timing.record("generate_clients", generate_clients_start);
+    };
+    timing.finish();
+
+    Manifest {
+        edgesearch_version: env!("CARGO_PKG_VERSION").to_string(),
+        format_version: FORMAT_VERSION,
+        settings,
+        layer_prefix: "".to_string(),
+        delta_base: None,
+        document_count: manifests.iter().map(|m| m.document_count).sum(),
+        term_count: manifests.iter().map(|m| m.term_count).sum(),
+        terms_chunks: Vec::new(),
+        documents_chunks: Vec::new(),
+        summaries_chunks: None,
+        keys_chunks: Vec::new(),
+        fields: Vec::new(),
+        deleted: None,
+        documents_dictionary: None,
+        summaries_dictionary: None,
+        indexes: names,
+        shards: 0,
+        document_schema: None,
+        summary_schema: None,
+        timing,
+        embedded_data: None,
+    }.write(output.as_ref(), "");
+    println!("Build complete")
+}
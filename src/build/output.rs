@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::fs::{copy, create_dir_all, File, metadata, read, remove_dir_all};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rayon::prelude::*;
+
+// Destination of build artifacts. Paths are relative to the root of the output and use `/` as the separator, e.g. `terms/0` or `worker.js`.
+pub trait OutputSink: Send + Sync {
+    fn write(&self, path: &str, data: &[u8]);
+
+    // Remove everything previously written under the folder `dir`, e.g. chunks of an earlier build.
+    fn clear(&self, dir: &str);
+
+    // Write multiple files. Sinks where order does not matter can write them in parallel.
+    fn write_all(&self, files: &[(String, &[u8])]) {
+        for (path, data) in files {
+            self.write(path, data);
+        };
+    }
+
+    // Write the contents of the local file `source`, which may be too large to hold in memory.
+    fn write_file(&self, path: &str, source: &Path) {
+        self.write(path, &read(source).expect("read file for output"));
+    }
+}
+
+// Writes artifacts as files in a folder, which is created if it does not exist.
+pub struct DirectoryOutput {
+    dir: PathBuf,
+}
+
+impl DirectoryOutput {
+    pub fn new(dir: &Path) -> DirectoryOutput {
+        create_dir_all(dir).expect("create output folder");
+        DirectoryOutput { dir: dir.to_path_buf() }
+    }
+}
+
+impl OutputSink for DirectoryOutput {
+    fn write(&self, path: &str, data: &[u8]) {
+        let path = self.dir.join(path);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).expect("create output folder");
+        };
+        File::create(path).expect("open output file for writing").write_all(data).expect("write output file");
+    }
+
+    fn clear(&self, dir: &str) {
+        let dir = self.dir.join(dir);
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).expect("create output folder");
+    }
+
+    fn write_all(&self, files: &[(String, &[u8])]) {
+        files.par_iter().for_each(|(path, data)| self.write(path, data));
+    }
+
+    fn write_file(&self, path: &str, source: &Path) {
+        let path = self.dir.join(path);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).expect("create output folder");
+        };
+        copy(source, path).expect("copy file to output");
+    }
+}
+
+// Writes artifacts as entries of a single uncompressed tar archive, in the order they are written.
+// The archive is complete once the sink is dropped.
+pub struct TarOutput {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl TarOutput {
+    pub fn create(path: &Path) -> TarOutput {
+        TarOutput { writer: Mutex::new(BufWriter::new(File::create(path).expect("create output archive"))) }
+    }
+}
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+// Write `value` as a NULL-terminated octal number filling `field`.
+fn tar_octal(field: &mut [u8], value: usize) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    assert_eq!(digits.len(), field.len() - 1, "value is too large for tar header");
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+// Build a POSIX ustar header for a regular file. Paths longer than 100 bytes are split into the prefix and name fields.
+fn tar_header(path: &str, size: usize) -> [u8; TAR_BLOCK_SIZE] {
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        let split = path[..path.len().min(156)].rfind('/').filter(|split| path.len() - split - 1 <= 100).expect("path is too long for tar archive");
+        (&path[..split], &path[split + 1..])
+    };
+    let mut header = [0u8; TAR_BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    tar_octal(&mut header[100..108], 0o644);
+    tar_octal(&mut header[108..116], 0);
+    tar_octal(&mut header[116..124], 0);
+    tar_octal(&mut header[124..136], size);
+    // Modification times are left as zero so that archives of the same build are byte-identical.
+    tar_octal(&mut header[136..148], 0);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    // The checksum is calculated with the checksum field set to spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum = header.iter().map(|b| *b as usize).sum::<usize>();
+    tar_octal(&mut header[148..155], checksum);
+    header
+}
+
+fn tar_padding(size: usize) -> &'static [u8] {
+    &[0u8; TAR_BLOCK_SIZE][..(TAR_BLOCK_SIZE - size % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE]
+}
+
+impl OutputSink for TarOutput {
+    fn write(&self, path: &str, data: &[u8]) {
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&tar_header(path, data.len())).expect("write output archive");
+        writer.write_all(data).expect("write output archive");
+        writer.write_all(tar_padding(data.len())).expect("write output archive");
+    }
+
+    fn write_file(&self, path: &str, source: &Path) {
+        let size = metadata(source).expect("read file for output").len() as usize;
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&tar_header(path, size)).expect("write output archive");
+        std::io::copy(&mut File::open(source).expect("open file for output"), &mut *writer).expect("write output archive");
+        writer.write_all(tar_padding(size)).expect("write output archive");
+    }
+
+    // Entries cannot be removed from an archive, but an archive only ever contains artifacts of one build.
+    fn clear(&self, _dir: &str) {}
+}
+
+impl Drop for TarOutput {
+    fn drop(&mut self) {
+        let writer = self.writer.get_mut().unwrap();
+        writer.write_all(&[0u8; TAR_BLOCK_SIZE * 2]).expect("write output archive");
+        writer.flush().expect("write output archive");
+    }
+}
+
+// Holds artifacts in memory, keyed by path.
+pub struct MemoryOutput {
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryOutput {
+    pub fn new() -> MemoryOutput {
+        MemoryOutput { files: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.files.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn into_files(self) -> BTreeMap<String, Vec<u8>> {
+        self.files.into_inner().unwrap()
+    }
+}
+
+impl Default for MemoryOutput {
+    fn default() -> MemoryOutput {
+        MemoryOutput::new()
+    }
+}
+
+impl OutputSink for MemoryOutput {
+    fn write(&self, path: &str, data: &[u8]) {
+        self.files.lock().unwrap().insert(path.to_string(), data.to_vec());
+    }
+
+    fn clear(&self, dir: &str) {
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        self.files.lock().unwrap().retain(|path, _| !path.starts_with(&prefix));
+    }
+}
+
+// Writes to another sink with all paths under `prefix`, e.g. `shards/0/`.
+pub(crate) struct PrefixedOutput {
+    inner: Arc<dyn OutputSink>,
+    prefix: String,
+}
+
+impl PrefixedOutput {
+    pub fn new(inner: Arc<dyn OutputSink>, prefix: String) -> PrefixedOutput {
+        PrefixedOutput { inner, prefix }
+    }
+}
+
+impl OutputSink for PrefixedOutput {
+    fn write(&self, path: &str, data: &[u8]) {
+        self.inner.write(&format!("{}{}", self.prefix, path), data);
+    }
+
+    fn clear(&self, dir: &str) {
+        self.inner.clear(&format!("{}{}", self.prefix, dir));
+    }
+
+    fn write_all(&self, files: &[(String, &[u8])]) {
+        self.inner.write_all(&files.iter().map(|(path, data)| (format!("{}{}", self.prefix, path), *data)).collect::<Vec<_>>());
+    }
+
+    fn write_file(&self, path: &str, source: &Path) {
+        self.inner.write_file(&format!("{}{}", self.prefix, path), source);
+    }
+}
+
+static SCRATCH_DIR_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// A uniquely named folder for intermediate files that are not part of the output, such as spilled runs. Removed when dropped.
+pub(crate) struct ScratchDir {
+    dir: PathBuf,
+}
+
+impl ScratchDir {
+    // Create within `parent`, or the system temporary folder if None.
+    pub fn new(parent: Option<&Path>, name: &str) -> ScratchDir {
+        let dir = parent.map_or_else(std::env::temp_dir, Path::to_path_buf).join(format!(
+            "edgesearch-{}-{}-{}",
+            name,
+            process::id(),
+            SCRATCH_DIR_COUNTER.fetch_add(1, Ordering::Relaxed),
+        ));
+        create_dir_all(&dir).expect("create scratch folder");
+        ScratchDir { dir }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.dir);
+    }
+}
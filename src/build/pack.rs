@@ -0,0 +1,54 @@
+use std::convert::TryInto;
+use std::fs::{File, read};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::build::output::{OutputSink, ScratchDir};
+
+// File name of the packed chunks of a layer, stored at `{layer_prefix}index.bin`.
+// Keep in sync with PACKED_FILE_NAME in script/src/search.ts.
+pub const PACKED_FILE_NAME: &str = "index.bin";
+
+// Concatenates all chunks of a layer into one file, so that they can be served as a single static file and fetched using Range requests.
+// The file is written to a scratch folder as chunks are appended, so that it does not need to fit in memory.
+pub struct PackWriter {
+    scratch: ScratchDir,
+    writer: BufWriter<File>,
+    len: usize,
+}
+
+impl PackWriter {
+    pub fn new(scratch_dir: Option<&Path>) -> PackWriter {
+        let scratch = ScratchDir::new(scratch_dir, "pack");
+        let writer = BufWriter::new(File::create(PackWriter::path(&scratch)).expect("create packed file"));
+        PackWriter { scratch, writer, len: 0 }
+    }
+
+    fn path(scratch: &ScratchDir) -> PathBuf {
+        scratch.path().join(PACKED_FILE_NAME)
+    }
+
+    // Append `data` and return the byte offset it starts at.
+    pub fn append(&mut self, data: &[u8]) -> usize {
+        let offset = self.len;
+        self.writer.write_all(data).expect("write packed file");
+        self.len += data.len();
+        // Offsets are stored in the runner as 32-bit integers.
+        let _: u32 = self.len.try_into().expect("packed file must be smaller than 4 GiB");
+        offset
+    }
+
+    // Write the packed file to `{layer_prefix}index.bin` of the output.
+    pub fn finish(mut self, output: &dyn OutputSink, layer_prefix: &str) {
+        self.writer.flush().expect("write packed file");
+        output.write_file(&format!("{}{}", layer_prefix, PACKED_FILE_NAME), &PackWriter::path(&self.scratch));
+    }
+
+    // Like `finish`, but also return the contents of the packed file, e.g. to embed it in the runner.
+    pub fn finish_and_read(mut self, output: &dyn OutputSink, layer_prefix: &str) -> Vec<u8> {
+        self.writer.flush().expect("write packed file");
+        let data = read(PackWriter::path(&self.scratch)).expect("read packed file");
+        output.write(&format!("{}{}", layer_prefix, PACKED_FILE_NAME), &data);
+        data
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Ordered as they are listed in schemas.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonType {
+    Null,
+    Boolean,
+    Integer,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+// A JSON Schema of documents, inferred from every document that has been added to it.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct DocumentSchema {
+    // Types of every value seen. Empty if no values have been seen, such as items of arrays that are always empty.
+    #[serde(rename = "type", default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub types: BTreeSet<JsonType>,
+    // Schema of items of every array seen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<DocumentSchema>>,
+    // Schemas of properties of every object seen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub properties: Option<BTreeMap<String, DocumentSchema>>,
+    // Properties present in every object seen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required: Option<BTreeSet<String>>,
+}
+
+impl DocumentSchema {
+    fn add_type(&mut self, json_type: JsonType) {
+        self.types.insert(json_type);
+        // Integers are also numbers.
+        if self.types.contains(&JsonType::Number) {
+            self.types.remove(&JsonType::Integer);
+        };
+    }
+
+    pub fn add(&mut self, value: &Value) {
+        match value {
+            Value::Null => self.add_type(JsonType::Null),
+            Value::Bool(_) => self.add_type(JsonType::Boolean),
+            // Integers too large for an i64 are treated as numbers, as most clients can't represent them.
+            Value::Number(n) => self.add_type(if n.is_i64() { JsonType::Integer } else { JsonType::Number }),
+            Value::String(_) => self.add_type(JsonType::String),
+            Value::Array(items) => {
+                self.add_type(JsonType::Array);
+                let items_schema = self.items.get_or_insert_with(Default::default);
+                for item in items {
+                    items_schema.add(item);
+                };
+            }
+            Value::Object(properties) => {
+                self.add_type(JsonType::Object);
+                match &mut self.required {
+                    Some(required) => required.retain(|name| properties.contains_key(name)),
+                    None => self.required = Some(properties.keys().cloned().collect()),
+                };
+                let properties_schema = self.properties.get_or_insert_with(BTreeMap::new);
+                for (name, value) in properties {
+                    properties_schema.entry(name.clone()).or_default().add(value);
+                };
+            }
+        };
+    }
+
+    // Whether the property is present in every object seen.
+    pub fn is_required(&self, name: &str) -> bool {
+        self.required.as_ref().is_some_and(|required| required.contains(name))
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs::{create_dir_all, File, remove_dir_all};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::build::{build_index, BuildConfig, DataStore, ShardBy, Target};
+use crate::build::client::{ClientIndex, generate_clients};
+use crate::build::js::generate_coordinator_js;
+use crate::build::manifest::{FORMAT_VERSION, Manifest, ManifestTiming};
+use crate::build::output::{PrefixedOutput, ScratchDir};
+use crate::build::schema::DocumentSchema;
+use crate::data::document_keys::DocumentKeysReader;
+use crate::data::document_terms::DocumentTermsReader;
+use crate::data::documents::DocumentsReader;
+use crate::util::format::number;
+
+const SHARD_DOCUMENTS_FILE_NAME: &str = "documents.input";
+const SHARD_DOCUMENT_TERMS_FILE_NAME: &str = "document_terms.input";
+const SHARD_DOCUMENT_KEYS_FILE_NAME: &str = "document_keys.input";
+const SHARD_DOCUMENT_SUMMARIES_FILE_NAME: &str = "document_summaries.input";
+
+// Shards are written to `shards/{shard}/` of the output.
+fn shard_prefix(shard: usize) -> String {
+    format!("shards/{}/", shard)
+}
+
+fn shard_dir(dir: &Path, shard: usize) -> PathBuf {
+    dir.join(shard_prefix(shard))
+}
+
+fn shard_for_document(shard_by: ShardBy, shards: usize, document_count: usize, document_id: usize) -> usize {
+    match shard_by {
+        ShardBy::Range => document_id * shards / document_count,
+        // Fibonacci hashing, so that consecutive IDs are spread evenly across shards.
+        ShardBy::Hash => ((document_id as u64).wrapping_mul(0x9E3779B97F4A7C15) >> 32) as usize % shards,
+    }
+}
+
+// Split the inputs into one set of inputs per shard, build each shard as its own index, and generate a coordinator worker that searches all of them.
+pub fn build_shards(BuildConfig {
+    chunk_naming,
+    chunk_size,
+    compression,
+    data_store,
+    data_store_url_prefix,
+    data_store_bucket_binding,
+    data_store_region,
+    target,
+    worker_format,
+    document_terms_source,
+    mut documents_source,
+    document_keys_source,
+    document_summaries_source,
+    stored_fields,
+    maximum_query_results,
+    maximum_query_terms,
+    generate_clients: infer_schema,
+    output,
+    scratch_dir,
+    previous_output_dir,
+    delta_base_dir,
+    deleted_documents_source,
+    deleted_document_keys_source,
+    memory_limit,
+    threads,
+    shards,
+    shard_by,
+    shard_url_template,
+}: BuildConfig) {
+    let mut timing = ManifestTiming::start();
+    assert!(matches!(target, Target::Worker), "sharded builds must target workers, as shards are searched by a coordinator worker");
+    assert!(delta_base_dir.is_none(), "sharded builds cannot be deltas");
+    assert!(deleted_documents_source.is_none() && deleted_document_keys_source.is_none(), "sharded builds cannot delete documents");
+    let shard_url_template = shard_url_template.expect("shard URL template is required for sharded builds");
+    assert!(shard_url_template.contains("{shard}"), "shard URL template must contain {}", "{shard}");
+
+    let document_count = DocumentsReader::new(documents_source.try_clone().expect("clone documents file")).count();
+    documents_source.seek(SeekFrom::Start(0)).expect("seek to start of documents file");
+    assert!(document_count > 0, "there are no documents to shard");
+
+    // The inputs of each shard are written to a scratch folder, as they are not part of the output.
+    let scratch = ScratchDir::new(scratch_dir.as_deref(), "shards");
+    let mut documents_writers = Vec::new();
+    let mut document_terms_writers = Vec::new();
+    let mut document_keys_writers = Vec::new();
+    let mut document_summaries_writers = Vec::new();
+    let has_keys = document_keys_source.is_some();
+    let has_summaries = document_summaries_source.is_some();
+    for shard in 0..shards {
+        let dir = shard_dir(scratch.path(), shard);
+        create_dir_all(&dir).expect("create shard inputs folder");
+        documents_writers.push(BufWriter::new(File::create(dir.join(SHARD_DOCUMENTS_FILE_NAME)).expect("create shard documents file")));
+        document_terms_writers.push(BufWriter::new(File::create(dir.join(SHARD_DOCUMENT_TERMS_FILE_NAME)).expect("create shard document terms file")));
+        if has_keys {
+            document_keys_writers.push(BufWriter::new(File::create(dir.join(SHARD_DOCUMENT_KEYS_FILE_NAME)).expect("create shard document keys file")));
+        };
+        if has_summaries {
+            document_summaries_writers.push(BufWriter::new(File::create(dir.join(SHARD_DOCUMENT_SUMMARIES_FILE_NAME)).expect("create shard document summaries file")));
+        };
+    };
+
+    // document_id => (shard, document ID within shard).
+    let mut shard_document_ids = Vec::<(usize, usize)>::with_capacity(document_count);
+    // shard => document_count.
+    let mut shard_document_counts = vec![0usize; shards];
+    // Clients are generated for the coordinator, so the schema is inferred from all documents here instead of by each shard.
+    let mut document_schema = if infer_schema { Some(DocumentSchema::default()) } else { None };
+    for (document_id, document) in DocumentsReader::new(documents_source) {
+        if let Some(schema) = &mut document_schema {
+            schema.add(&serde_json::from_str(&document).expect("parse document as JSON"));
+        };
+        let shard = shard_for_document(shard_by, shards, document_count, document_id);
+        shard_document_ids.push((shard, shard_document_counts[shard]));
+        shard_document_counts[shard] += 1;
+        let writer = &mut documents_writers[shard];
+        writer.write_all(document.as_bytes()).expect("write shard document");
+        writer.write_all(b"\0").expect("write shard document");
+    };
+
+    // shard => amount of documents whose terms have been completely written.
+    let mut shard_documents_written = vec![0usize; shards];
+    for (document_id, term) in DocumentTermsReader::new(document_terms_source) {
+        let (shard, shard_document_id) = *shard_document_ids.get(document_id)
+            .unwrap_or_else(|| panic!("document terms refer to document {} but there are only {} documents", document_id, document_count));
+        let writer = &mut document_terms_writers[shard];
+        // Close previous documents, including any without terms.
+        while shard_documents_written[shard] < shard_document_id {
+            writer.write_all(b"\0").expect("write shard document terms");
+            shard_documents_written[shard] += 1;
+        };
+        writer.write_all(term.as_bytes()).expect("write shard document terms");
+        writer.write_all(b"\0").expect("write shard document terms");
+    };
+    for (shard, writer) in document_terms_writers.iter_mut().enumerate() {
+        while shard_documents_written[shard] < shard_document_counts[shard] {
+            writer.write_all(b"\0").expect("write shard document terms");
+            shard_documents_written[shard] += 1;
+        };
+    };
+    // Keys are unique across all shards, as the coordinator looks up keys in every shard.
+    if let Some(source) = document_keys_source {
+        for (document_id, key) in DocumentKeysReader::new(source) {
+            let (shard, _) = *shard_document_ids.get(document_id).expect("there are more document keys than documents");
+            let writer = &mut document_keys_writers[shard];
+            writer.write_all(key.as_bytes()).expect("write shard document key");
+            writer.write_all(b"\0").expect("write shard document key");
+        };
+    };
+    let mut summary_schema = if infer_schema && has_summaries { Some(DocumentSchema::default()) } else { None };
+    if let Some(source) = document_summaries_source {
+        for (document_id, summary) in DocumentsReader::new(source) {
+            if let Some(schema) = &mut summary_schema {
+                schema.add(&serde_json::from_str(&summary).expect("parse summary as JSON"));
+            };
+            let (shard, _) = *shard_document_ids.get(document_id).expect("there are more document summaries than documents");
+            let writer = &mut document_summaries_writers[shard];
+            writer.write_all(summary.as_bytes()).expect("write shard document summary");
+            writer.write_all(b"\0").expect("write shard document summary");
+        };
+    };
+    drop(documents_writers);
+    drop(document_terms_writers);
+    drop(document_keys_writers);
+    drop(document_summaries_writers);
+
+    let mut shard_manifests = Vec::new();
+    for (shard, shard_document_count) in shard_document_counts.iter().enumerate() {
+        println!("Building shard {} of {} ({} documents)...", shard + 1, shards, number(*shard_document_count));
+        let dir = shard_dir(scratch.path(), shard);
+        shard_manifests.push(build_index(BuildConfig {
+            chunk_naming,
+            chunk_size,
+            compression,
+            data_store,
+            data_store_url_prefix: match data_store {
+                // Shards can share a KV namespace or bucket, so their keys are always prefixed.
+                DataStore::KV | DataStore::R2 => Some(format!("{}{}", data_store_url_prefix.as_deref().unwrap_or(""), shard_prefix(shard))),
+                _ => data_store_url_prefix.as_ref().map(|prefix| format!("{}{}", prefix, shard_prefix(shard))),
+            },
+            data_store_bucket_binding: data_store_bucket_binding.clone(),
+            data_store_region: data_store_region.clone(),
+            target,
+            worker_format,
+            document_terms_source: File::open(dir.join(SHARD_DOCUMENT_TERMS_FILE_NAME)).expect("open shard document terms file"),
+            documents_source: File::open(dir.join(SHARD_DOCUMENTS_FILE_NAME)).expect("open shard documents file"),
+            stored_fields: stored_fields.clone(),
+            document_keys_source: if has_keys { Some(File::open(dir.join(SHARD_DOCUMENT_KEYS_FILE_NAME)).expect("open shard document keys file")) } else { None },
+            document_summaries_source: if has_summaries { Some(File::open(dir.join(SHARD_DOCUMENT_SUMMARIES_FILE_NAME)).expect("open shard document summaries file")) } else { None },
+            maximum_query_results,
+            maximum_query_terms,
+            generate_clients: false,
+            output: Arc::new(PrefixedOutput::new(output.clone(), shard_prefix(shard))),
+            scratch_dir: scratch_dir.clone(),
+            previous_output_dir: previous_output_dir.as_ref().map(|previous| shard_dir(previous, shard)),
+            delta_base_dir: None,
+            deleted_documents_source: None,
+            deleted_document_keys_source: None,
+            memory_limit,
+            threads,
+            shards: 1,
+            shard_by,
+            shard_url_template: None,
+        }));
+        remove_dir_all(&dir).expect("remove shard inputs folder");
+    };
+
+    generate_coordinator_js(output.as_ref(), worker_format, shards, &shard_url_template, maximum_query_results);
+    if let Some(schema) = &document_schema {
+        generate_clients(output.as_ref(), target, maximum_query_results, maximum_query_terms, &[ClientIndex { name: None, schema, summary_schema: summary_schema.as_ref(), has_keys }]);
+    };
+    timing.finish();
+
+    // Describes the coordinator and where the shards are, so that the whole build can be deployed from the output folder.
+    let mut settings = shard_manifests[0].settings.clone();
+    settings.data_store_url_prefix = data_store_url_prefix;
+    Manifest {
+        edgesearch_version: env!("CARGO_PKG_VERSION").to_string(),
+        format_version: FORMAT_VERSION,
+        settings,
+        layer_prefix: "".to_string(),
+        delta_base: None,
+        document_count: shard_manifests.iter().map(|m| m.document_count).sum(),
+        term_count: shard_manifests.iter().map(|m| m.term_count).sum(),
+        terms_chunks: Vec::new(),
+        documents_chunks: Vec::new(),
+        summaries_chunks: None,
+        keys_chunks: Vec::new(),
+        fields: Vec::new(),
+        deleted: None,
+        documents_dictionary: None,
+        summaries_dictionary: None,
+        indexes: Vec::new(),
+        shards,
+        document_schema,
+        summary_schema,
+        timing,
+        embedded_data: None,
+    }.write(output.as_ref(), "");
+    println!("Sharded build complete")
+}
@@ -0,0 +1,105 @@
+use std::fs::{File, write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::build::{build_layer, BuildConfig, ChunkNaming, Compression, DataStore, DirectoryOutput, ShardBy, Target, WorkerFormat};
+use crate::build::chunks::{ChunkEntryKey, ChunkKey, ChunkU32Key};
+use crate::build::manifest::{Manifest, ManifestChunk};
+use crate::build::merge::read_chunks;
+use crate::build::output::ScratchDir;
+
+pub const DOCUMENTS: &[&str] = &[r#"{"a":1}"#, r#"{"a":2}"#, r#"{"a":3}"#, r#"{"a":4}"#];
+// The last document has no terms.
+pub const TERMS: &[&[&str]] = &[&["x", "y"], &["x"], &["y"], &[]];
+
+// Documents `{"i":0}` to `{"i":count - 1}`, and the terms of each, for tests that need more documents than DOCUMENTS.
+pub fn numbered_documents(count: usize, terms: impl Fn(usize) -> Vec<&'static str>) -> (Vec<String>, Vec<Vec<&'static str>>) {
+    ((0..count).map(|i| format!(r#"{{"i":{}}}"#, i)).collect(), (0..count).map(terms).collect())
+}
+
+// Folder of input files and build outputs for a test, removed when dropped.
+pub struct TestDir {
+    dir: ScratchDir,
+}
+
+impl TestDir {
+    pub fn new() -> TestDir {
+        TestDir { dir: ScratchDir::new(None, "test") }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    // Write `entries` to the input file `name`, each terminated by '\0', and open it.
+    pub fn input(&self, name: &str, entries: &[impl AsRef<str>]) -> File {
+        let path = self.path(name);
+        write(&path, entries.iter().map(|e| format!("{}\0", e.as_ref())).collect::<String>()).expect("write test input");
+        File::open(path).expect("open test input")
+    }
+
+    // Write the terms of each document in the format read by DocumentTermsReader, and open it.
+    pub fn document_terms<'t>(&self, name: &str, terms: &[impl AsRef<[&'t str]>]) -> File {
+        let path = self.path(name);
+        write(&path, terms.iter().map(|t| t.as_ref().iter().map(|t| format!("{}\0", t)).collect::<String>() + "\0").collect::<String>()).expect("write test input");
+        File::open(path).expect("open test input")
+    }
+
+    // Configuration of a build of `documents` and their `terms` into the output folder `output`, with small chunks so that tests span multiple chunks.
+    pub fn config<'t>(&self, output: &str, documents: &[impl AsRef<str>], terms: &[impl AsRef<[&'t str]>]) -> BuildConfig {
+        BuildConfig {
+            chunk_naming: ChunkNaming::Index,
+            chunk_size: 64,
+            compression: Compression::None,
+            data_store: DataStore::KV,
+            data_store_url_prefix: None,
+            data_store_bucket_binding: None,
+            data_store_region: None,
+            target: Target::Worker,
+            worker_format: WorkerFormat::Service,
+            document_terms_source: self.document_terms(&format!("{}.terms", output), terms),
+            documents_source: self.input(&format!("{}.documents", output), documents),
+            document_keys_source: None,
+            document_summaries_source: None,
+            stored_fields: Vec::new(),
+            maximum_query_results: 50,
+            maximum_query_terms: 50,
+            generate_clients: false,
+            output: Arc::new(DirectoryOutput::new(&self.path(output))),
+            scratch_dir: Some(self.dir.path().to_path_buf()),
+            previous_output_dir: None,
+            delta_base_dir: None,
+            deleted_documents_source: None,
+            deleted_document_keys_source: None,
+            memory_limit: None,
+            threads: 1,
+            shards: 1,
+            shard_by: ShardBy::Range,
+            shard_url_template: None,
+        }
+    }
+}
+
+// Build the chunks and manifest of a layer, without the worker and runner, which require clang.
+pub fn build_test_layer(config: BuildConfig) -> Manifest {
+    let output = config.output.clone();
+    let (mut manifest, _) = build_layer(config, "");
+    manifest.timing.finish();
+    manifest.write(output.as_ref(), "");
+    manifest
+}
+
+// Change the manifest of the build output in `dir`, such as to make it look like the output of a different kind of build.
+pub fn edit_manifest(dir: &Path, edit: impl FnOnce(&mut Manifest)) {
+    let mut manifest = Manifest::read(dir);
+    edit(&mut manifest);
+    manifest.write(&DirectoryOutput::new(dir), "");
+}
+
+// Read the entries of chunks keyed by document ID, such as documents, as (document ID, value) in order.
+pub fn read_u32_entries(dir: &Path, kind: &str, chunks: &[ManifestChunk]) -> Vec<(u32, Vec<u8>)> {
+    read_chunks::<ChunkU32Key>(dir, kind, chunks).map(|(key, value)| match key.key() {
+        ChunkKey::U32(document_id) => (*document_id, value),
+        ChunkKey::Str(_) => unreachable!(),
+    }).collect()
+}
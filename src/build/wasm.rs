@@ -1,12 +1,14 @@
-use std::fs::File;
-use std::io::Write;
+use std::fs::{read, write};
 use std::path::PathBuf;
 use std::process::Command;
 
-const RUNNER_C_CHUNKS: &'static str = include_str!("../../wasm/chunks.c");
-const RUNNER_C_INDEX: &'static str = include_str!("../../wasm/index.c");
-const RUNNER_C_ROARING: &'static str = include_str!("../../wasm/roaring.c");
-const RUNNER_C_SYS: &'static str = include_str!("../../wasm/sys.c");
+use crate::build::manifest::{FORMAT_VERSION, ManifestChunk, WorkerIndex};
+use crate::build::output::{OutputSink, ScratchDir};
+
+const RUNNER_C_CHUNKS: &str = include_str!("../../wasm/chunks.c");
+const RUNNER_C_INDEX: &str = include_str!("../../wasm/index.c");
+const RUNNER_C_ROARING: &str = include_str!("../../wasm/roaring.c");
+const RUNNER_C_SYS: &str = include_str!("../../wasm/sys.c");
 
 #[allow(dead_code)]
 pub enum WasmStandard {
@@ -53,7 +55,7 @@ pub fn compile_to_wasm(WasmCompileArgs {
     macros,
     input,
     output,
-}: WasmCompileArgs) -> () {
+}: WasmCompileArgs) {
     let mut cmd = Command::new("clang");
     cmd.arg(format!("-std={}", match standard {
         WasmStandard::C89 => "c89",
@@ -104,37 +106,121 @@ pub fn compile_to_wasm(WasmCompileArgs {
     };
 }
 
-pub fn generate_and_compile_runner_wasm(
-    output_dir: &PathBuf,
-    max_results: usize,
-    max_query_terms: usize,
-    terms_chunks_raw: &str,
-    terms_chunks_len: usize,
-    documents_chunks_raw: &str,
-    documents_chunks_len: usize,
-) -> () {
-    let source_path = output_dir.join("runner.c");
-    let output_path = output_dir.join("runner.wasm");
-
-    let mut source_file = File::create(&source_path).expect("open runner.c for writing");
-    source_file.write_all(RUNNER_C_SYS.as_bytes()).expect("write runner.c");
-    source_file.write_all(RUNNER_C_ROARING.as_bytes()).expect("write runner.c");
-    let runner_c_index = if cfg!(feature = "nonportable") {
+// Keep in sync with `bst_chunk_ref_t` in wasm/chunks.c.
+fn generate_chunk_refs(name: &str, chunks: &[ManifestChunk]) -> String {
+    let mut refs = String::new();
+    for chunk in chunks {
+        refs.push_str(&format!(r#"{{
+                .id = {id},
+                .mid_pos = {mid_pos},
+                .name = "{name}",
+                .name_len = {name_len},
+                .offset = {offset},
+                .size = {size},
+                .secondary_index_size = {secondary_index_size},
+                .first_key = {key},
+            }},"#,
+            id = chunk.id,
+            mid_pos = chunk.mid_pos,
+            name = chunk.name,
+            name_len = chunk.name.len(),
+            offset = chunk.offset.unwrap_or(0),
+            size = chunk.size,
+            secondary_index_size = chunk.secondary_index_size.unwrap_or(0),
+            key = chunk.first_key.c(),
+        ));
+    };
+    format!("bst_chunk_ref_t {}[] = {{\n{}\n}};\n", name, refs)
+}
+
+// Write `data` as a C string literal, split over lines. Every byte is escaped so that no escape can be followed by a hex digit.
+pub fn generate_bytes_literal(data: &[u8]) -> String {
+    let mut literal = String::with_capacity(data.len() * 4 + data.len() / 64 * 3 + 2);
+    literal.push('"');
+    for (i, byte) in data.iter().enumerate() {
+        if i > 0 && i % 64 == 0 {
+            literal.push_str("\"\n\"");
+        };
+        literal.push_str(&format!("\\x{:02x}", byte));
+    };
+    literal.push('"');
+    literal
+}
+
+// Layers of all indexes are numbered in order, matching LAYERS in the worker (see build::js::generate_worker_js).
+fn generate_chunk_tables(indexes: &[WorkerIndex]) -> String {
+    let mut code = String::new();
+    let mut terms_tables = String::new();
+    let mut documents_tables = String::new();
+    let mut summaries_tables = String::new();
+    let mut keys_tables = String::new();
+    let mut fields_tables = String::new();
+    let mut embedded_table = String::new();
+    for (i, layer) in indexes.iter().flat_map(|index| index.layers.iter()).enumerate() {
+        match layer.embedded_data {
+            Some(data) => {
+                code.push_str(&format!("static byte const EMBEDDED_DATA_{}[] = {};\n", i, generate_bytes_literal(data)));
+                embedded_table.push_str(&format!("EMBEDDED_DATA_{},\n", i));
+            }
+            None => embedded_table.push_str("NULL,\n"),
+        };
+        code.push_str(&generate_chunk_refs(&format!("TERMS_CHUNKS_{}", i), layer.terms_chunks));
+        code.push_str(&generate_chunk_refs(&format!("DOCUMENTS_CHUNKS_{}", i), layer.documents_chunks));
+        code.push_str(&generate_chunk_refs(&format!("SUMMARIES_CHUNKS_{}", i), layer.summaries_chunks.unwrap_or(&[])));
+        code.push_str(&generate_chunk_refs(&format!("KEYS_CHUNKS_{}", i), layer.keys_chunks));
+        terms_tables.push_str(&format!("{{ .refs = TERMS_CHUNKS_{}, .len = {} }},\n", i, layer.terms_chunks.len()));
+        documents_tables.push_str(&format!("{{ .refs = DOCUMENTS_CHUNKS_{}, .len = {} }},\n", i, layer.documents_chunks.len()));
+        summaries_tables.push_str(&format!("{{ .refs = SUMMARIES_CHUNKS_{}, .len = {} }},\n", i, layer.summaries_chunks.map_or(0, |chunks| chunks.len())));
+        keys_tables.push_str(&format!("{{ .refs = KEYS_CHUNKS_{}, .len = {} }},\n", i, layer.keys_chunks.len()));
+        // One bst_chunks_t per stored field of the layer, in the order of LAYERS[layer].fields in the worker.
+        let mut layer_fields_table = String::new();
+        for (j, field) in layer.fields.iter().enumerate() {
+            code.push_str(&generate_chunk_refs(&format!("FIELDS_CHUNKS_{}_{}", i, j), &field.chunks));
+            layer_fields_table.push_str(&format!("{{ .refs = FIELDS_CHUNKS_{}_{}, .len = {} }},\n", i, j, field.chunks.len()));
+        };
+        code.push_str(&format!("bst_chunks_t FIELDS_CHUNKS_{}[] = {{\n{}}};\n", i, layer_fields_table));
+        fields_tables.push_str(&format!("FIELDS_CHUNKS_{},\n", i));
+    };
+    code.push_str(&format!("bst_chunks_t TERMS_CHUNKS[] = {{\n{}}};\n", terms_tables));
+    code.push_str(&format!("bst_chunks_t DOCUMENTS_CHUNKS[] = {{\n{}}};\n", documents_tables));
+    code.push_str(&format!("bst_chunks_t SUMMARIES_CHUNKS[] = {{\n{}}};\n", summaries_tables));
+    code.push_str(&format!("bst_chunks_t KEYS_CHUNKS[] = {{\n{}}};\n", keys_tables));
+    code.push_str(&format!("bst_chunks_t* FIELDS_CHUNKS[] = {{\n{}}};\n", fields_tables));
+    code.push_str(&format!("byte const* EMBEDDED_DATA[] = {{\n{}}};\n", embedded_table));
+    code
+}
+
+// Bitmaps are deserialised in the format they're serialised in by build::bitmap.
+fn runner_c_index() -> String {
+    if cfg!(feature = "nonportable") {
         RUNNER_C_INDEX.replace(
             "roaring_bitmap_portable_deserialize",
             "roaring_bitmap_deserialize",
         )
     } else {
         RUNNER_C_INDEX.to_string()
-    };
-    source_file.write_all(runner_c_index.as_bytes()).expect("write runner.c");
-    source_file.write_all(RUNNER_C_CHUNKS
-        .replace("___NORMAL_TERMS_CHUNKS___", terms_chunks_raw)
-        .replace("___NORMAL_TERMS_CHUNKS_LEN___", format!("{}", terms_chunks_len).as_str())
-        .replace("___DOCUMENTS_CHUNKS___", documents_chunks_raw)
-        .replace("___DOCUMENTS_CHUNKS_LEN___", format!("{}", documents_chunks_len).as_str())
-        .as_bytes()
-    ).expect("write runner.c");
+    }
+}
+
+// Returns the size of the compiled runner.
+pub fn generate_and_compile_runner_wasm(
+    output: &dyn OutputSink,
+    max_results: usize,
+    max_query_terms: usize,
+    indexes: &[WorkerIndex],
+) -> usize {
+    let mut source = String::new();
+    source.push_str(RUNNER_C_SYS);
+    source.push_str(RUNNER_C_ROARING);
+    source.push_str(&runner_c_index());
+    source.push_str(&RUNNER_C_CHUNKS.replace("___CHUNK_TABLES___", &generate_chunk_tables(indexes)));
+    output.write("runner.c", source.as_bytes());
+
+    // clang needs real files, so compile in a scratch folder and then copy the result to the output.
+    let scratch = ScratchDir::new(None, "runner");
+    let source_path = scratch.path().join("runner.c");
+    let output_path = scratch.path().join("runner.wasm");
+    write(&source_path, &source).expect("write runner.c");
 
     compile_to_wasm(WasmCompileArgs {
         standard: WasmStandard::C11,
@@ -142,12 +228,98 @@ pub fn generate_and_compile_runner_wasm(
         all_warnings: true,
         extra_warnings: true,
         warnings_as_errors: false,
-        ignore_warnings: &vec![WasmCompileWarning::UnusedFunction],
+        ignore_warnings: &[WasmCompileWarning::UnusedFunction],
         macros: &[
+            ("FORMAT_VERSION", format!("{}", FORMAT_VERSION).as_str()),
             ("MAX_RESULTS", format!("{}", max_results).as_str()),
             ("MAX_QUERY_TERMS", format!("{}", max_query_terms).as_str()),
         ],
         input: &source_path,
         output: &output_path,
     });
+    let wasm = read(&output_path).expect("read runner.wasm");
+    output.write("runner.wasm", &wasm);
+    wasm.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+    use std::process::Command;
+
+    use crate::build::bitmap::bitmap::Bitmap;
+    use crate::build::output::ScratchDir;
+    use crate::build::wasm::{generate_bytes_literal, runner_c_index, RUNNER_C_ROARING, RUNNER_C_SYS};
+
+    fn bitmap_literal(document_ids: &[u32]) -> String {
+        let mut bitmap = Bitmap::create();
+        for document_id in document_ids {
+            bitmap.add(*document_id);
+        };
+        generate_bytes_literal(&bitmap.serialize())
+    }
+
+    // Run a query of one term per mode (or none, if empty) against `document_count` documents in the runner, compiled natively with
+    // the system C compiler as clang targeting WASM may not be available, and return the IDs of the results.
+    fn run_query(document_count: u32, modes: [&[u32]; 3], deleted: &[u32]) -> Vec<u32> {
+        let mut terms = String::new();
+        let mut serialised = String::new();
+        for (mode, document_ids) in modes.iter().enumerate() {
+            if !document_ids.is_empty() {
+                terms.push_str(&format!("char const* term_{}[] = {{ {}, NULL }};\n", mode, bitmap_literal(document_ids)));
+                serialised.push_str(&format!("query->serialised[i++] = term_{};\n", mode));
+            };
+            serialised.push_str("query->serialised[i++] = NULL;\n");
+        };
+        let driver = format!(r#"
+            int main(void) {{
+              reset();
+              {terms}
+              char const* deleted[] = {{ {deleted}, NULL }};
+              index_query_t* query = index_query_malloc();
+              query->first_rank = 0;
+              query->document_count = {document_count};
+              query->deleted = {deleted_ptr};
+              size_t i = 0;
+              {serialised}
+              results_t* results = index_query(query);
+              for (uint8_t r = 0; r < results->count; r++) printf("result %u\n", results->documents[r]);
+              return 0;
+            }}
+        "#,
+            terms = terms,
+            deleted = bitmap_literal(deleted),
+            document_count = document_count,
+            deleted_ptr = if deleted.is_empty() { "NULL" } else { "deleted" },
+            serialised = serialised,
+        );
+        let scratch = ScratchDir::new(None, "runner-test");
+        let source_path = scratch.path().join("runner.c");
+        let heap_path = scratch.path().join("heap.c");
+        let output_path = scratch.path().join("runner");
+        write(&source_path, [RUNNER_C_SYS, RUNNER_C_ROARING, &runner_c_index(), &driver].concat()).unwrap();
+        // Provided by the WASM host.
+        write(&heap_path, "unsigned char __heap_base[1 << 24];\n").unwrap();
+        let status = Command::new("cc")
+            .args(["-std=c11", "-w", "-nostdinc", "-ffreestanding", "-fno-builtin", "-DDISABLE_X64", "-DFORMAT_VERSION=0", "-DMAX_RESULTS=50", "-DMAX_QUERY_TERMS=50"])
+            .arg(&source_path)
+            .arg(&heap_path)
+            .arg("-o")
+            .arg(&output_path)
+            .status()
+            .expect("compile runner");
+        assert!(status.success());
+        let output = Command::new(&output_path).output().expect("run runner");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().lines()
+            .filter_map(|line| line.strip_prefix("result "))
+            .map(|document_id| document_id.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn queries_with_only_exclude_terms_return_all_other_documents() {
+        assert_eq!(run_query(6, [&[], &[], &[1, 4]], &[]), vec![0, 2, 3, 5]);
+        assert_eq!(run_query(6, [&[], &[0, 1, 2], &[1]], &[]), vec![0, 2]);
+    }
 }
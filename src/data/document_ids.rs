@@ -0,0 +1,30 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::DocumentId;
+use crate::data::read_null_terminated;
+
+pub struct DocumentIdsReader {
+    reader: BufReader<File>,
+}
+
+impl DocumentIdsReader {
+    pub fn new(input: File) -> DocumentIdsReader {
+        DocumentIdsReader {
+            reader: BufReader::new(input),
+        }
+    }
+}
+
+impl Iterator for DocumentIdsReader {
+    type Item = DocumentId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_null_terminated(&mut self.reader).map(|data| {
+            String::from_utf8(data).expect("parsing document ID as UTF-8")
+                .trim()
+                .parse::<DocumentId>()
+                .expect("parsing document ID as integer")
+        })
+    }
+}
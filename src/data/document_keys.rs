@@ -0,0 +1,34 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::data::read_null_terminated;
+
+// Reads external keys of documents, such as primary keys, in the same order as the documents. Integer keys are written in decimal.
+pub struct DocumentKeysReader {
+    reader: BufReader<File>,
+    next_document_id: usize,
+}
+
+impl DocumentKeysReader {
+    pub fn new(input: File) -> DocumentKeysReader {
+        DocumentKeysReader {
+            reader: BufReader::new(input),
+            next_document_id: 0,
+        }
+    }
+}
+
+impl Iterator for DocumentKeysReader {
+    type Item = (usize, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_null_terminated(&mut self.reader).map(|data| {
+            let doc_id = self.next_document_id;
+            self.next_document_id += 1;
+            let key = String::from_utf8(data).expect("parsing document key as UTF-8");
+            // Keys are stored as ChunkStrKey, whose length is a single byte.
+            assert!(!key.is_empty() && key.len() <= 255, "document key {:?} must be between 1 and 255 bytes", key);
+            (doc_id, key)
+        })
+    }
+}
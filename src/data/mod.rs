@@ -1,6 +1,8 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
+pub mod document_ids;
+pub mod document_keys;
 pub mod document_terms;
 pub mod documents;
 
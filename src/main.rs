@@ -1,42 +1,192 @@
 use std::fs::File;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use structopt::StructOpt;
 
-use edgesearch::build::{build, BuildConfig, DataStore};
+use edgesearch::build::{build, build_named, BuildConfig, ChunkNaming, Compression, DataStore, DirectoryOutput, merge, MergeConfig, OutputSink, ShardBy, TarOutput, Target, WorkerFormat};
 
+// Arguments shared by all commands that produce an index.
 #[derive(StructOpt)]
-struct Cli {
+struct IndexArgs {
+    #[structopt(long, default_value = "index", possible_values = &ChunkNaming::variants(), case_insensitive = true)] chunk_naming: ChunkNaming,
     #[structopt(long, default_value = "26214400")] chunk_size: usize,
+    /// Compress each document and summary in chunks against a dictionary trained on the documents; the worker decompresses them using `DecompressionStream`.
+    #[structopt(long, default_value = "none", possible_values = &Compression::variants(), case_insensitive = true)] compression: Compression,
     #[structopt(long, possible_values = &DataStore::variants(), case_insensitive = true)] data_store: DataStore,
     #[structopt(long)] data_store_url_prefix: Option<String>,
-    #[structopt(long, parse(from_os_str))] document_terms: PathBuf,
-    #[structopt(long, parse(from_os_str))] documents: PathBuf,
+    #[structopt(long)] data_store_bucket_binding: Option<String>,
+    #[structopt(long)] data_store_region: Option<String>,
+    #[structopt(long, default_value = "worker", possible_values = &Target::variants(), case_insensitive = true)] target: Target,
+    #[structopt(long, default_value = "service", possible_values = &WorkerFormat::variants(), case_insensitive = true)] worker_format: WorkerFormat,
     #[structopt(long, default_value = "50")] maximum_query_results: usize,
     #[structopt(long, default_value = "50")] maximum_query_terms: usize,
-    #[structopt(long, parse(from_os_str))] output_dir: PathBuf,
+    /// Infer a schema from the documents and write typed TypeScript and Rust clients to `client/` of the output.
+    #[structopt(long)] generate_clients: bool,
+    #[structopt(long, parse(from_os_str), required_unless = "output-tar", conflicts_with = "output-tar")] output_dir: Option<PathBuf>,
+    /// Write the output as a single tar archive instead of a folder.
+    #[structopt(long, parse(from_os_str))] output_tar: Option<PathBuf>,
+    #[structopt(long, default_value = "1")] threads: usize,
 }
 
-fn main() {
-    let Cli {
-        chunk_size,
-        data_store,
-        data_store_url_prefix,
-        document_terms,
-        documents,
-        maximum_query_results,
-        maximum_query_terms,
-        output_dir,
-    } = Cli::from_args();
+fn output_sink(output_dir: &Option<PathBuf>, output_tar: &Option<PathBuf>) -> Arc<dyn OutputSink> {
+    match (output_dir, output_tar) {
+        (Some(dir), None) => Arc::new(DirectoryOutput::new(dir)),
+        (None, Some(path)) => Arc::new(TarOutput::create(path)),
+        _ => unreachable!(),
+    }
+}
+
+// Only parsed once, so the size of its variants doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(StructOpt)]
+enum Cli {
+    /// Build an index from documents and their terms.
+    Build {
+        #[structopt(flatten)] index: IndexArgs,
+        #[structopt(long, parse(from_os_str))] deleted_documents: Option<PathBuf>,
+        #[structopt(long, parse(from_os_str))] deleted_document_keys: Option<PathBuf>,
+        #[structopt(long, parse(from_os_str))] delta_base_dir: Option<PathBuf>,
+        /// Multiple documents and document terms files can be provided when building named indexes, one per name.
+        #[structopt(long, parse(from_os_str), required = true, number_of_values = 1)] document_terms: Vec<PathBuf>,
+        #[structopt(long, parse(from_os_str), required = true, number_of_values = 1)] documents: Vec<PathBuf>,
+        /// If provided, there must be one document keys file for every documents file.
+        #[structopt(long, parse(from_os_str), number_of_values = 1)] document_keys: Vec<PathBuf>,
+        /// If provided, there must be one document summaries file for every documents file.
+        #[structopt(long, parse(from_os_str), number_of_values = 1)] document_summaries: Vec<PathBuf>,
+        #[structopt(long = "index-name", number_of_values = 1)] index_names: Vec<String>,
+        #[structopt(long)] memory_limit: Option<usize>,
+        #[structopt(long, parse(from_os_str))] previous_output_dir: Option<PathBuf>,
+        #[structopt(long, parse(from_os_str))] scratch_dir: Option<PathBuf>,
+        #[structopt(long, default_value = "range", possible_values = &ShardBy::variants(), case_insensitive = true)] shard_by: ShardBy,
+        #[structopt(long)] shard_url_template: Option<String>,
+        #[structopt(long, default_value = "1")] shards: usize,
+        /// Top-level fields of documents to also store separately, so that search results can return only them.
+        #[structopt(long = "stored-field", number_of_values = 1)] stored_fields: Vec<String>,
+    },
+    /// Merge the output directories of multiple builds into one index.
+    Merge {
+        #[structopt(flatten)] index: IndexArgs,
+        #[structopt(long = "input-dir", parse(from_os_str), required = true)] input_dirs: Vec<PathBuf>,
+    },
+}
 
-    build(BuildConfig {
-        chunk_size,
-        data_store,
-        data_store_url_prefix,
-        document_terms_source: File::open(document_terms).expect("open document terms file"),
-        documents_source: File::open(documents).expect("open documents file"),
-        maximum_query_results,
-        maximum_query_terms,
-        output_dir,
-    });
+fn main() {
+    match Cli::from_args() {
+        Cli::Build {
+            index: IndexArgs {
+                chunk_naming,
+                chunk_size,
+                compression,
+                data_store,
+                data_store_url_prefix,
+                data_store_bucket_binding,
+                data_store_region,
+                target,
+                worker_format,
+                maximum_query_results,
+                maximum_query_terms,
+                generate_clients,
+                output_dir,
+                output_tar,
+                threads,
+            },
+            deleted_documents,
+            deleted_document_keys,
+            delta_base_dir,
+            document_terms,
+            documents,
+            document_keys,
+            document_summaries,
+            index_names,
+            memory_limit,
+            previous_output_dir,
+            scratch_dir,
+            shard_by,
+            shard_url_template,
+            shards,
+            stored_fields,
+        } => {
+            assert_eq!(documents.len(), document_terms.len(), "every documents file must have a document terms file");
+            assert!(document_keys.is_empty() || document_keys.len() == documents.len(), "every documents file must have a document keys file");
+            assert!(document_summaries.is_empty() || document_summaries.len() == documents.len(), "every documents file must have a document summaries file");
+            let output = output_sink(&output_dir, &output_tar);
+            let config = |i: usize| BuildConfig {
+                chunk_naming,
+                chunk_size,
+                compression,
+                data_store,
+                data_store_url_prefix: data_store_url_prefix.clone(),
+                data_store_bucket_binding: data_store_bucket_binding.clone(),
+                data_store_region: data_store_region.clone(),
+                target,
+                worker_format,
+                document_terms_source: File::open(&document_terms[i]).expect("open document terms file"),
+                documents_source: File::open(&documents[i]).expect("open documents file"),
+                document_keys_source: document_keys.get(i).map(|p| File::open(p).expect("open document keys file")),
+                document_summaries_source: document_summaries.get(i).map(|p| File::open(p).expect("open document summaries file")),
+                stored_fields: stored_fields.clone(),
+                maximum_query_results,
+                maximum_query_terms,
+                generate_clients,
+                output: output.clone(),
+                scratch_dir: scratch_dir.clone(),
+                previous_output_dir: previous_output_dir.clone(),
+                delta_base_dir: delta_base_dir.clone(),
+                deleted_documents_source: deleted_documents.as_ref().map(|p| File::open(p).expect("open deleted documents file")),
+                deleted_document_keys_source: deleted_document_keys.as_ref().map(|p| File::open(p).expect("open deleted document keys file")),
+                memory_limit,
+                threads,
+                shards,
+                shard_by,
+                shard_url_template: shard_url_template.clone(),
+            };
+            if index_names.is_empty() {
+                assert_eq!(documents.len(), 1, "multiple documents files can only be provided when building named indexes");
+                build(config(0));
+            } else {
+                assert_eq!(index_names.len(), documents.len(), "every index name must have a documents file");
+                build_named(index_names.iter().enumerate().map(|(i, name)| (name.clone(), config(i))).collect());
+            };
+        }
+        Cli::Merge {
+            index: IndexArgs {
+                chunk_naming,
+                chunk_size,
+                compression,
+                data_store,
+                data_store_url_prefix,
+                data_store_bucket_binding,
+                data_store_region,
+                target,
+                worker_format,
+                maximum_query_results,
+                maximum_query_terms,
+                generate_clients,
+                output_dir,
+                output_tar,
+                threads,
+            },
+            input_dirs,
+        } => {
+            assert!(output_dir.as_ref().filter(|dir| input_dirs.contains(dir)).is_none(), "the output directory cannot be an input");
+            merge(MergeConfig {
+                chunk_naming,
+                chunk_size,
+                compression,
+                data_store,
+                data_store_url_prefix,
+                data_store_bucket_binding,
+                data_store_region,
+                target,
+                worker_format,
+                input_dirs,
+                maximum_query_results,
+                maximum_query_terms,
+                generate_clients,
+                output: output_sink(&output_dir, &output_tar),
+                threads,
+            });
+        }
+    };
 }
@@ -8,7 +8,11 @@ const path = require('path');
 const args = minimist(process.argv.slice(2));
 
 const OUTPUT_DIR = args['output-dir'];
+// Output directory of the base build, if OUTPUT_DIR contains a delta build.
+const BASE_OUTPUT_DIR = args['base-output-dir'] || OUTPUT_DIR;
 const PORT = args['port'];
+// Name of the R2 bucket binding, if the build uses the R2 data store.
+const BUCKET_BINDING = args['bucket-binding'];
 
 const workerScript = fs.readFileSync(path.join(OUTPUT_DIR, 'worker.js'), 'utf8');
 const runnerWasm = fs.readFileSync(path.join(OUTPUT_DIR, 'runner.wasm'));
@@ -37,7 +41,7 @@ global.TransformStream = class TransformStream {
     this.bufferedWrites = [];
     this.writer = {
       write: data => this.bufferedWrites.push(data),
-      releaseLock: () => void 0,
+      close: () => void 0,
     };
   }
 
@@ -52,20 +56,61 @@ global.TransformStream = class TransformStream {
   }
 }
 
-global.KV = {
+// Keys of shards are prefixed by the shard (e.g. `shards/0/`), and each shard is tested from its own output folder.
+const withoutShardPrefix = key => key.replace(/^shards\/\d+\//, '');
+
+const kv = {
   async get (key) {
-    const [prefix, id] = key.split('/');
-    if (!['documents', 'terms'].includes(prefix) || !/^[0-9]+$/.test(id)) {
+    key = withoutShardPrefix(key);
+    // Keys of named indexes are prefixed by the index name.
+    const matches = /^([\w-]+\/)?(delta\/)?(documents|summaries|terms|keys|fields\/\d+|deleted|documents-dictionary|summaries-dictionary)\/([0-9a-f]+)$/.exec(key);
+    if (!matches) {
       throw new Error(`Unknown KV key: ${key}`);
     }
-    return readBuffer(path.join(OUTPUT_DIR, prefix, id));
+    return readBuffer(path.join(matches[2] ? OUTPUT_DIR : BASE_OUTPUT_DIR, key));
+  },
+};
+
+// Serves objects from the output directory in place of an R2 bucket, including range reads.
+const bucket = {
+  async get (key, {range} = {}) {
+    key = withoutShardPrefix(key);
+    const file = path.join(/^([\w-]+\/)?delta\//.test(key) ? OUTPUT_DIR : BASE_OUTPUT_DIR, key);
+    let data;
+    try {
+      data = await fs.promises.readFile(file);
+    } catch {
+      return null;
+    }
+    if (range) {
+      data = data.slice(range.offset, range.offset + range.length);
+    }
+    return {
+      arrayBuffer: async () => data.buffer.slice(data.byteOffset, data.byteOffset + data.byteLength),
+    };
   },
 };
 
 global.QUERY_RUNNER_WASM = new WebAssembly.Module(runnerWasm);
 
-global.self = {
-  addEventListener (eventName, handler) {
+const bindings = {
+  KV: kv,
+  ...(BUCKET_BINDING && {[BUCKET_BINDING]: bucket}),
+};
+
+// Module workers (see `--worker-format module`) export their handler and receive bindings with each request, and service workers listen for `fetch` events with bindings as globals.
+const isModuleWorker = /^\s*export default /m.test(workerScript);
+let handleRequest;
+if (isModuleWorker) {
+  // The runner is already a global, so its import is removed, and the default export is returned instead.
+  const worker = Function(workerScript
+    .replace(/^\s*import QUERY_RUNNER_WASM from "\.\/runner\.wasm";$/m, '')
+    .replace(/^\s*export default /m, 'return '))();
+  handleRequest = request => worker.fetch(request, bindings);
+} else {
+  Object.assign(global, bindings);
+  global.self = global;
+  global.addEventListener = (eventName, handler) => {
     switch (eventName) {
     case 'fetch':
       onFetch = handler;
@@ -73,27 +118,27 @@ global.self = {
     default:
       throw new TypeError(`Unknown event name: ${eventName}`);
     }
-  },
-};
-
-Function(workerScript)();
+  };
+  Function(workerScript)();
+  handleRequest = request => new Promise(resolve => onFetch({request, respondWith: resolve}));
+}
 
 const server = express();
 
 server.use(async (req, res) => {
-  onFetch({
-    request: {
-      url: `http://localhost:${PORT}${req.url}`,
-    },
-    async respondWith (responsePromise) {
-      const response = await responsePromise;
-      res.status(response.status());
-      for (const [name, value] of Object.entries(response.headers())) {
-        res.setHeader(name, value);
-      }
-      res.send(response.body);
-    },
+  const response = await handleRequest({
+    method: req.method,
+    url: `http://localhost:${PORT}${req.url}`,
   });
+  res.status(response.status());
+  for (const [name, value] of Object.entries(response.headers())) {
+    res.setHeader(name, value);
+  }
+  // Bodies of single documents are byte arrays, which Express would otherwise send as JSON.
+  const body = response.body instanceof Uint8Array && !Buffer.isBuffer(response.body)
+    ? Buffer.from(response.body.buffer, response.body.byteOffset, response.body.byteLength)
+    : response.body;
+  res.send(body);
 });
 
 server.listen(PORT, () => console.log(`Test server started on ${PORT}`));
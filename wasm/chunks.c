@@ -11,6 +11,14 @@ typedef union {
 typedef struct {
   uint32_t id;
   uint32_t mid_pos;
+  // Key suffix of the chunk in the data store; either its index or a hash of its contents.
+  char const* name;
+  uint32_t name_len;
+  // Byte offset of the chunk in its layer's packed file if the data store is packed, otherwise 0.
+  uint32_t offset;
+  uint32_t size;
+  // Size of the chunk's secondary index if the data store supports Range requests, otherwise 0.
+  uint32_t secondary_index_size;
   str_or_uint32_t first_key;
 } bst_chunk_ref_t;
 
@@ -19,14 +27,15 @@ typedef enum {
   KEY_STR = 1,
 } bst_key_t;
 
-bst_chunk_ref_t NORMAL_TERMS_CHUNKS[] = {
-  ___NORMAL_TERMS_CHUNKS___
-};
-uint32_t NORMAL_TERMS_CHUNKS_LEN = ___NORMAL_TERMS_CHUNKS_LEN___;
-bst_chunk_ref_t DOCUMENTS_CHUNKS[] = {
-  ___DOCUMENTS_CHUNKS___
-};
-uint32_t DOCUMENTS_CHUNKS_LEN = ___DOCUMENTS_CHUNKS_LEN___;
+typedef struct {
+  bst_chunk_ref_t* refs;
+  uint32_t len;
+} bst_chunks_t;
+
+// Declares TERMS_CHUNKS, DOCUMENTS_CHUNKS, SUMMARIES_CHUNKS, and KEYS_CHUNKS, which have one bst_chunks_t per layer of every index,
+// and FIELDS_CHUNKS, which has one array per layer of every index with one bst_chunks_t per stored field.
+// Generated by build::wasm::generate_and_compile_runner_wasm.
+___CHUNK_TABLES___
 
 static inline int compare_int(int a, int b) {
   return (a > b) - (a < b);
@@ -53,8 +62,11 @@ typedef struct {
   byte* ptr;
 } chunk_entry_t;
 
-static inline bst_chunk_ref_t* find_chunk(bst_chunk_ref_t chunks[], uint32_t chunks_len, bst_key_t key_type, str_or_uint32_t key) {
-  int32_t lo = 0, hi = chunks_len - 1;
+static inline bst_chunk_ref_t* find_chunk(bst_chunks_t* table, bst_key_t key_type, str_or_uint32_t key) {
+  bst_chunk_ref_t* chunks = table->refs;
+  // A layer may have no chunks of a kind, e.g. a delta that only deletes documents.
+  if (table->len == 0) return NULL;
+  int32_t lo = 0, hi = table->len - 1;
   while (true) {
     int32_t dist = hi + 1 - lo;
     if (dist <= 0) {
@@ -71,17 +83,43 @@ static inline bst_chunk_ref_t* find_chunk(bst_chunk_ref_t chunks[], uint32_t chu
   }
 }
 
-WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_term(char* term, uint8_t term_len) {
+WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_term(uint32_t layer, char* term, uint8_t term_len) {
   str_t term_str;
   term_str.len = term_len;
   term_str.val = term;
   str_or_uint32_t key;
   key.strval = term_str;
-  return find_chunk(NORMAL_TERMS_CHUNKS, NORMAL_TERMS_CHUNKS_LEN, KEY_STR, key);
+  return find_chunk(&TERMS_CHUNKS[layer], KEY_STR, key);
+}
+
+WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_doc(uint32_t layer, doc_id_t doc) {
+  str_or_uint32_t key;
+  key.intval = doc;
+  return find_chunk(&DOCUMENTS_CHUNKS[layer], KEY_NUM, key);
+}
+
+WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_summary(uint32_t layer, doc_id_t doc) {
+  str_or_uint32_t key;
+  key.intval = doc;
+  return find_chunk(&SUMMARIES_CHUNKS[layer], KEY_NUM, key);
+}
+
+WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_key(uint32_t layer, char* doc_key, uint8_t doc_key_len) {
+  str_t key_str;
+  key_str.len = doc_key_len;
+  key_str.val = doc_key;
+  str_or_uint32_t key;
+  key.strval = key_str;
+  return find_chunk(&KEYS_CHUNKS[layer], KEY_STR, key);
 }
 
-WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_doc(doc_id_t doc) {
+WASM_EXPORT bst_chunk_ref_t* find_chunk_containing_field(uint32_t layer, uint32_t field, doc_id_t doc) {
   str_or_uint32_t key;
   key.intval = doc;
-  return find_chunk(DOCUMENTS_CHUNKS, DOCUMENTS_CHUNKS_LEN, KEY_NUM, key);
+  return find_chunk(&FIELDS_CHUNKS[layer][field], KEY_NUM, key);
+}
+
+// Start of the packed chunks of a layer if they're embedded in the runner (see build::DataStore::Embedded), otherwise NULL.
+WASM_EXPORT byte const* embedded_data(uint32_t layer) {
+  return EMBEDDED_DATA[layer];
 }
@@ -7,6 +7,11 @@ typedef enum {
   EXCLUDE = 2,
 } mode_t;
 
+// Used by the worker to detect a runner built for a different data format.
+WASM_EXPORT uint32_t format_version(void) {
+  return FORMAT_VERSION;
+}
+
 // This should be called before every query.
 WASM_EXPORT void reset(void) {
   heap = &__heap_base;
@@ -14,16 +19,22 @@ WASM_EXPORT void reset(void) {
 
 typedef struct {
   uint32_t first_rank;
-  // This is a flattened form of char const*[][].
+  // Total documents in the index being queried, as a runner can serve multiple indexes.
+  uint32_t document_count;
+  // NULL-terminated array of serialised Roaring Bitmaps of IDs of deleted documents (one per index layer with deletions), or NULL if there are none.
+  // They are combined using OR and removed from the results.
+  char const** deleted;
+  // This is a flattened form of char const**[][].
   // There's a subarray for each mode, and they are ordered according to their numeric value (see mode_t).
-  // Each mode contains pointers to byte arrays containing serialised Roaring Bitmaps representing a term.
+  // Each mode contains pointers to terms, where a term is a NULL-terminated array of pointers to byte arrays containing serialised Roaring Bitmaps
+  // (one per index layer containing the term), which are combined using OR.
   // Each mode is terminated by NULL.
   // For example: `{
-  //   &bitmapForHello, &bitmapForWorld, NULL,
-  //   &bitmapForThe, &bitmapForQuick, &bitmapForFox, NULL,
-  //   &bitmapForAstronaut, NULL,
+  //   &partsForHello, &partsForWorld, NULL,
+  //   &partsForThe, &partsForQuick, &partsForFox, NULL,
+  //   &partsForAstronaut, NULL,
   // }`.
-  char const* serialised[MAX_QUERY_TERMS + 3];
+  void const* serialised[MAX_QUERY_TERMS + 3];
 } index_query_t;
 
 // Result of a query executed within WASM.
@@ -47,18 +58,28 @@ WASM_EXPORT index_query_t* index_query_malloc(void) {
   return malloc(sizeof(index_query_t));
 }
 
-// Internal function used to deserialise multiple bitmaps from an `index_query_t->serialised` value, starting at and incrementing `*i`.
-// Pointers to serialised bytes will be replaced with pointers to deserialised bitmaps
+// Internal function used to deserialise a NULL-terminated array of serialised bitmaps and combine them using OR.
+roaring_bitmap_t* index_deserialise_parts(char const** parts) {
+  roaring_bitmap_t* bitmap = roaring_bitmap_portable_deserialize(parts[0]);
+  for (size_t p = 1; parts[p]; p++) {
+    roaring_bitmap_t* part = roaring_bitmap_portable_deserialize(parts[p]);
+    roaring_bitmap_or_inplace(bitmap, part);
+  }
+  return bitmap;
+}
+
+// Internal function used to deserialise multiple terms from an `index_query_t->serialised` value, starting at and incrementing `*i`.
+// Pointers to serialised parts will be replaced with pointers to deserialised bitmaps
 // (which are allocated on the heap).
 // The deserialised bitmaps are then combined using OR on the heap and the pointer to it will be returned. If there are no bitmaps to combine, NULL is returned instead.
 roaring_bitmap_t* index_deserialise_and_combine(
-  char const** ptrs,
+  void const** ptrs,
   size_t* i
 ) {
   size_t start = *i;
-  for (char const* serialised; (serialised = ptrs[*i]); (*i)++) {
-    printf("Deserialising bitmap %zu pointing to char const* at %zX...\n", *i, serialised);
-    ptrs[*i] = (void*) roaring_bitmap_portable_deserialize(serialised);
+  for (void const* parts; (parts = ptrs[*i]); (*i)++) {
+    printf("Deserialising term %zu pointing to char const** at %zX...\n", *i, parts);
+    ptrs[*i] = (void*) index_deserialise_parts((char const**) parts);
   }
   // Move past NULL.
   if (start == (*i)++) {
@@ -80,7 +101,7 @@ WASM_EXPORT results_t* index_query(index_query_t* query) {
   // REQUIRE.
   printf("Processing REQUIRE terms...\n");
   while (query->serialised[i]) {
-    roaring_bitmap_t* bitmap = roaring_bitmap_portable_deserialize(query->serialised[i]);
+    roaring_bitmap_t* bitmap = index_deserialise_parts((char const**) query->serialised[i]);
     if (result_bitmap == NULL) result_bitmap = bitmap;
     else roaring_bitmap_and_inplace(result_bitmap, bitmap);
     i++;
@@ -95,17 +116,20 @@ WASM_EXPORT results_t* index_query(index_query_t* query) {
     else roaring_bitmap_and_inplace(result_bitmap, contain_bitmaps_combined);
   }
 
+  // If there are no REQUIRE or CONTAIN terms, start from all documents so that EXCLUDE terms and deleted documents are removed from them.
+  if (result_bitmap == NULL) {
+    result_bitmap = roaring_bitmap_from_range(0, query->document_count, 1);
+  }
+
   // EXCLUDE.
   printf("Processing EXCLUDE terms at %zu...\n", i);
   roaring_bitmap_t* exclude_bitmaps_combined = index_deserialise_and_combine( query->serialised, &i);
   if (exclude_bitmaps_combined != NULL) {
-    if (result_bitmap == NULL) result_bitmap = exclude_bitmaps_combined;
-    else roaring_bitmap_andnot_inplace(result_bitmap, exclude_bitmaps_combined);
+    roaring_bitmap_andnot_inplace(result_bitmap, exclude_bitmaps_combined);
   }
 
-  if (result_bitmap == NULL) {
-    printf("NULL result bitmap\n");
-    return NULL;
+  if (query->deleted != NULL) {
+    roaring_bitmap_andnot_inplace(result_bitmap, index_deserialise_parts(query->deleted));
   }
 
   printf("Result bitmap built\n");
@@ -129,3 +153,14 @@ WASM_EXPORT results_t* index_query(index_query_t* query) {
 
   return results;
 }
+
+// Function to be called from JS that combines a NULL-terminated array of serialised bitmaps of deleted documents (see `index_query_t->deleted`),
+// so that documents can be looked up by ID without returning deleted ones.
+WASM_EXPORT roaring_bitmap_t* index_deleted(char const** deleted) {
+  return index_deserialise_parts(deleted);
+}
+
+// Function to be called from JS that checks whether a document is in a bitmap returned by `index_deleted`.
+WASM_EXPORT bool index_is_deleted(roaring_bitmap_t const* deleted, doc_id_t doc) {
+  return roaring_bitmap_contains(deleted, doc);
+}